@@ -0,0 +1,4 @@
+-- The tags of the products are loaded by enrich-setup, see postgres::db_setup
+
+-- The primary key covers tag -> products, this covers product -> tags
+CREATE INDEX ON product_tag USING BTREE (product_id, tag_id);
@@ -0,0 +1,4 @@
+-- Which generated dataset the tables hold, see postgres::db_setup
+CREATE TABLE dataset_marker (
+  marker TEXT NOT NULL
+);
@@ -0,0 +1,581 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use eyre::Context;
+use serde::Deserialize;
+
+use crate::report::RunRecord;
+
+use super::runner::{self, read_json_lines};
+
+/// Timings of one benchmark configuration, in seconds
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub binary: String,
+    /// Binary parameters, like `v=-s`
+    pub variant: String,
+    /// noir arguments selecting the deployment, like `-l8` or `-r noir-1.yml`
+    pub cluster: String,
+    /// Cores of the deployment, if known
+    pub cores: Option<u64>,
+    pub times: Vec<f64>,
+}
+
+/// Identifies the samples that are merged and compared
+pub type GroupKey = (String, String, String);
+
+impl Sample {
+    pub fn key(&self) -> GroupKey {
+        (
+            self.binary.clone(),
+            self.variant.clone(),
+            self.cluster.clone(),
+        )
+    }
+}
+
+#[derive(Deserialize)]
+struct HyperfineExport {
+    results: Vec<HyperfineResult>,
+}
+
+#[derive(Deserialize)]
+struct HyperfineResult {
+    command: String,
+    times: Vec<f64>,
+    #[serde(default)]
+    exit_codes: Vec<Option<i32>>,
+    #[serde(default)]
+    parameters: BTreeMap<String, String>,
+}
+
+/// Load the samples of a path:
+/// - a results directory of `bench run`, using the wall time of the successful runs
+/// - a hyperfine `--export-json` file
+/// - a JSON lines file of [RunRecord], using their execution time
+/// - any other directory, loading every JSON and JSON lines file inside
+pub fn load(path: &Path) -> eyre::Result<Vec<Sample>> {
+    if path.is_dir() {
+        if path.join("matrix.yml").exists() {
+            return load_bench_dir(path);
+        }
+        let mut entries = std::fs::read_dir(path)
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .map(|e| Ok(e?.path()))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        entries.sort();
+        let mut samples = Vec::new();
+        for p in entries {
+            let ext = p.extension().and_then(|e| e.to_str());
+            if p.is_dir() || matches!(ext, Some("json" | "jsonl")) {
+                samples.extend(load(&p)?);
+            }
+        }
+        return Ok(samples);
+    }
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => load_hyperfine(path),
+        Some("jsonl") => load_records(path),
+        _ => eyre::bail!("unknown results format {}", path.display()),
+    }
+}
+
+fn load_hyperfine(path: &Path) -> eyre::Result<Vec<Sample>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let export: HyperfineExport = serde_json::from_str(&content)
+        .with_context(|| format!("invalid hyperfine export {}", path.display()))?;
+
+    let mut samples = Vec::new();
+    for r in export.results {
+        let mut words = r.command.split_whitespace();
+        let Some(binary) = words.next() else {
+            continue;
+        };
+        let binary = binary.rsplit('/').next().unwrap_or(binary).to_string();
+        let cluster = match r.parameters.get("conf") {
+            Some(conf) => conf.clone(),
+            None => words
+                .take_while(|w| *w != "--")
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        let variant = variant_name(r.parameters.iter().filter(|(k, _)| *k != "conf"));
+        // runs with a failing exit code are not timings of the pipeline
+        let times = r
+            .times
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| r.exit_codes.get(*i).is_none_or(|c| *c == Some(0)))
+            .map(|(_, t)| *t)
+            .collect();
+        samples.push(Sample {
+            cores: cluster_cores(&cluster),
+            binary,
+            variant,
+            cluster,
+            times,
+        });
+    }
+    Ok(samples)
+}
+
+fn load_bench_dir(dir: &Path) -> eyre::Result<Vec<Sample>> {
+    let mut groups: BTreeMap<GroupKey, Vec<f64>> = BTreeMap::new();
+    for e in runner::read_runs(dir)? {
+        if !e.success {
+            continue;
+        }
+        let key = (e.benchmark, variant_name(e.params.iter()), e.cluster);
+        groups.entry(key).or_default().push(e.wall_s);
+    }
+    Ok(groups
+        .into_iter()
+        .map(|((binary, variant, cluster), times)| Sample {
+            cores: cluster_cores(&cluster),
+            binary,
+            variant,
+            cluster,
+            times,
+        })
+        .collect())
+}
+
+fn load_records(path: &Path) -> eyre::Result<Vec<Sample>> {
+    let mut groups: BTreeMap<GroupKey, (u64, Vec<f64>)> = BTreeMap::new();
+    for r in read_json_lines::<RunRecord>(path)? {
+        // every host records the run, the first one times it
+        if !r.cluster.is_leader() {
+            continue;
+        }
+        let cluster = match &r.cluster.config {
+            Some(config) => format!("-r {config}"),
+            None => format!("-l{}", r.cluster.cores),
+        };
+        // the records of `bench run` carry the parameters naming the other results
+        let variant = match r.params.is_empty() {
+            true => r.variant.to_string(),
+            false => variant_name(r.params.iter()),
+        };
+        let key = (r.binary, variant, cluster);
+        let group = groups.entry(key).or_insert((r.cluster.cores, vec![]));
+        group.1.push(r.exec_time_s);
+    }
+    Ok(groups
+        .into_iter()
+        .map(|((binary, variant, cluster), (cores, times))| Sample {
+            binary,
+            variant,
+            cluster,
+            cores: Some(cores),
+            times,
+        })
+        .collect())
+}
+
+fn variant_name<'a>(params: impl Iterator<Item = (&'a String, &'a String)>) -> String {
+    let name = params
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    match name.is_empty() {
+        true => "-".to_string(),
+        false => name,
+    }
+}
+
+#[derive(Deserialize)]
+struct ClusterFile {
+    hosts: Vec<ClusterHost>,
+}
+
+#[derive(Deserialize)]
+struct ClusterHost {
+    num_cores: u64,
+}
+
+/// Cores of `-lN`, or summed over the hosts of the `-r` config file when it can be read
+fn cluster_cores(cluster: &str) -> Option<u64> {
+    let mut words = cluster.split_whitespace();
+    match words.next()? {
+        "-r" | "--remote" => {
+            let content = std::fs::read_to_string(words.next()?).ok()?;
+            let file: ClusterFile = serde_yaml::from_str(&content).ok()?;
+            Some(file.hosts.iter().map(|h| h.num_cores).sum())
+        }
+        w => w.strip_prefix("-l").and_then(|n| n.parse().ok()),
+    }
+}
+
+/// Merge the samples with the same binary, variant and cluster
+pub fn group(samples: Vec<Sample>) -> BTreeMap<GroupKey, Sample> {
+    let mut groups: BTreeMap<GroupKey, Sample> = BTreeMap::new();
+    for s in samples {
+        match groups.get_mut(&s.key()) {
+            Some(g) => {
+                g.times.extend(s.times);
+                g.cores = g.cores.or(s.cores);
+            }
+            None => {
+                groups.insert(s.key(), s);
+            }
+        }
+    }
+    groups
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub n: usize,
+    pub mean: f64,
+    /// Sample standard deviation
+    pub stddev: f64,
+    pub median: f64,
+    pub min: f64,
+}
+
+impl Stats {
+    pub fn new(times: &[f64]) -> Option<Self> {
+        if times.is_empty() {
+            return None;
+        }
+        let n = times.len();
+        let mean = times.iter().sum::<f64>() / n as f64;
+        let var = match n {
+            1 => 0.0,
+            _ => times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / (n - 1) as f64,
+        };
+        let mut sorted = times.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let median = match n % 2 {
+            0 => (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0,
+            _ => sorted[n / 2],
+        };
+        Some(Self {
+            n,
+            mean,
+            stddev: var.sqrt(),
+            median,
+            min: sorted[0],
+        })
+    }
+}
+
+/// Rows of a report, printed as Markdown or CSV
+pub struct Table {
+    pub headers: Vec<&'static str>,
+    pub rows: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Markdown,
+    Csv,
+}
+
+impl Table {
+    pub fn write(&self, format: Format, mut out: impl Write) -> eyre::Result<()> {
+        match format {
+            Format::Markdown => {
+                writeln!(out, "| {} |", self.headers.join(" | "))?;
+                writeln!(out, "|{}", "---|".repeat(self.headers.len()))?;
+                for row in &self.rows {
+                    let row = row
+                        .iter()
+                        .map(|c| c.replace('|', "\\|"))
+                        .collect::<Vec<_>>();
+                    writeln!(out, "| {} |", row.join(" | "))?;
+                }
+            }
+            Format::Csv => {
+                let mut w = csv::Writer::from_writer(out);
+                w.write_record(&self.headers)?;
+                for row in &self.rows {
+                    w.write_record(row)?;
+                }
+                w.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn opt(v: Option<f64>, precision: usize) -> String {
+    v.map(|v| format!("{v:.precision$}")).unwrap_or_default()
+}
+
+/// Statistics of every group, with the speedup over the `baseline` cluster of the same
+/// binary and variant and the scaling efficiency against the remote cluster with the
+/// fewest cores, `(T_ref * cores_ref) / (T * cores)`
+pub fn summary(groups: &BTreeMap<GroupKey, Sample>, baseline: &str) -> Table {
+    let mut rows = Vec::new();
+    for ((binary, variant, cluster), s) in groups {
+        let Some(stats) = Stats::new(&s.times) else {
+            continue;
+        };
+        let same_variant = || {
+            groups
+                .values()
+                .filter(|o| &o.binary == binary && &o.variant == variant)
+        };
+        let mean_of = |o: &Sample| Stats::new(&o.times).map(|st| st.mean);
+
+        let speedup = same_variant()
+            .find(|o| o.cluster == baseline)
+            .and_then(mean_of)
+            .map(|base| base / stats.mean);
+        let reference = same_variant()
+            .filter(|o| o.cluster.starts_with("-r") || o.cluster.starts_with("--remote"))
+            .filter_map(|o| Some((o.cores?, mean_of(o)?)))
+            .min_by_key(|(cores, _)| *cores);
+        let efficiency = match (reference, s.cores) {
+            (Some((ref_cores, ref_mean)), Some(cores)) if !s.cluster.starts_with("-l") => {
+                Some((ref_mean * ref_cores as f64) / (stats.mean * cores as f64))
+            }
+            _ => None,
+        };
+
+        rows.push(vec![
+            binary.clone(),
+            variant.clone(),
+            cluster.clone(),
+            s.cores.map(|c| c.to_string()).unwrap_or_default(),
+            stats.n.to_string(),
+            format!("{:.3}", stats.mean),
+            format!("{:.3}", stats.stddev),
+            format!("{:.3}", stats.median),
+            format!("{:.3}", stats.min),
+            opt(speedup, 2),
+            opt(efficiency, 2),
+        ]);
+    }
+    Table {
+        headers: vec![
+            "binary",
+            "variant",
+            "cluster",
+            "cores",
+            "n",
+            "mean_s",
+            "stddev_s",
+            "median_s",
+            "min_s",
+            "speedup",
+            "efficiency",
+        ],
+        rows,
+    }
+}
+
+/// Welch's t-test between the groups present in both runs, a change is significant when
+/// the two-sided p-value is below `alpha`
+pub fn compare(
+    old: &BTreeMap<GroupKey, Sample>,
+    new: &BTreeMap<GroupKey, Sample>,
+    alpha: f64,
+) -> (Table, usize) {
+    let mut rows = Vec::new();
+    let mut regressions = 0;
+    for (key, o) in old {
+        let Some(n) = new.get(key) else {
+            continue;
+        };
+        let (Some(so), Some(sn)) = (Stats::new(&o.times), Stats::new(&n.times)) else {
+            continue;
+        };
+        let p = welch_p_value(&so, &sn);
+        let verdict = match p {
+            Some(p) if p < alpha && sn.mean > so.mean => {
+                regressions += 1;
+                "regression"
+            }
+            Some(p) if p < alpha => "improvement",
+            _ => "",
+        };
+        rows.push(vec![
+            key.0.clone(),
+            key.1.clone(),
+            key.2.clone(),
+            format!("{:.3}", so.mean),
+            format!("{:.3}", sn.mean),
+            format!("{:+.1}", (sn.mean / so.mean - 1.0) * 100.0),
+            opt(p, 4),
+            verdict.to_string(),
+        ]);
+    }
+    let table = Table {
+        headers: vec![
+            "binary",
+            "variant",
+            "cluster",
+            "old_mean_s",
+            "new_mean_s",
+            "change_%",
+            "p_value",
+            "verdict",
+        ],
+        rows,
+    };
+    (table, regressions)
+}
+
+/// Two-sided p-value of Welch's t-test, none with fewer than two timings per side
+fn welch_p_value(a: &Stats, b: &Stats) -> Option<f64> {
+    if a.n < 2 || b.n < 2 {
+        return None;
+    }
+    let va = a.stddev.powi(2) / a.n as f64;
+    let vb = b.stddev.powi(2) / b.n as f64;
+    if va + vb == 0.0 {
+        return Some(if a.mean == b.mean { 1.0 } else { 0.0 });
+    }
+    let t = (a.mean - b.mean) / (va + vb).sqrt();
+    let df = (va + vb).powi(2) / (va.powi(2) / (a.n - 1) as f64 + vb.powi(2) / (b.n - 1) as f64);
+    // P(|T| > t) for a Student t with df degrees of freedom
+    Some(incomplete_beta(df / 2.0, 0.5, df / (df + t * t)))
+}
+
+/// Regularized incomplete beta function I_x(a, b)
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let front =
+        (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    // the continued fraction converges quickly below this point, use the symmetry otherwise
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_fraction(a, b, x) / a
+    } else {
+        1.0 - front * beta_fraction(b, a, 1.0 - x) / b
+    }
+}
+
+/// Continued fraction of the incomplete beta, modified Lentz's method
+fn beta_fraction(a: f64, b: f64, x: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let mut c = 1.0;
+    let mut d = 1.0 - (a + b) * x / (a + 1.0);
+    d = 1.0 / if d.abs() < TINY { TINY } else { d };
+    let mut h = d;
+    for m in 1..300 {
+        let m = m as f64;
+        for num in [
+            m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m)),
+            -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0)),
+        ] {
+            d = 1.0 + num * d;
+            d = 1.0 / if d.abs() < TINY { TINY } else { d };
+            c = 1.0 + num / c;
+            c = if c.abs() < TINY { TINY } else { c };
+            h *= d * c;
+        }
+        if (d * c - 1.0).abs() < 1e-12 {
+            break;
+        }
+    }
+    h
+}
+
+/// Lanczos approximation of ln Γ(x), for x > 0
+fn ln_gamma(x: f64) -> f64 {
+    const G: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut ser = 1.000000000190015;
+    for (i, g) in G.iter().enumerate() {
+        ser += g / (x + 1.0 + i as f64);
+    }
+    -tmp + (2.5066282746310005 * ser / x).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64, tolerance: f64) -> bool {
+        (a - b).abs() < tolerance
+    }
+
+    fn stats(n: usize, mean: f64, stddev: f64) -> Stats {
+        Stats {
+            n,
+            mean,
+            stddev,
+            median: mean,
+            min: mean,
+        }
+    }
+
+    #[test]
+    fn ln_gamma_matches_the_factorials() {
+        for (x, expected) in [
+            (1.0, 0.0),
+            (2.0, 0.0),
+            (5.0, 24f64.ln()),
+            (10.0, 362880f64.ln()),
+            (0.5, std::f64::consts::PI.sqrt().ln()),
+        ] {
+            assert!(close(ln_gamma(x), expected, 1e-9), "ln_gamma({x})");
+        }
+    }
+
+    #[test]
+    fn incomplete_beta_matches_the_closed_forms() {
+        for x in [0.1, 0.25, 0.5, 0.9] {
+            assert!(close(incomplete_beta(1.0, 1.0, x), x, 1e-9));
+            assert!(close(incomplete_beta(2.0, 1.0, x), x * x, 1e-9));
+            assert!(close(incomplete_beta(1.0, 2.0, x), x * (2.0 - x), 1e-9));
+            let arcsine = 2.0 / std::f64::consts::PI * x.sqrt().asin();
+            assert!(close(incomplete_beta(0.5, 0.5, x), arcsine, 1e-9));
+        }
+        assert!(close(incomplete_beta(3.0, 3.0, 0.5), 0.5, 1e-9));
+        assert_eq!(incomplete_beta(2.0, 3.0, 0.0), 0.0);
+        assert_eq!(incomplete_beta(2.0, 3.0, 1.0), 1.0);
+    }
+
+    #[test]
+    fn beta_fraction_matches_the_closed_forms() {
+        // I_x(1, 1) = x and I_x(1, 2) = x (2 - x), divided by their front factor
+        for x in [0.1, 0.25, 0.4] {
+            assert!(close(beta_fraction(1.0, 1.0, x), 1.0 / (1.0 - x), 1e-9));
+            let expected = (2.0 - x) / (2.0 * (1.0 - x).powi(2));
+            assert!(close(beta_fraction(1.0, 2.0, x), expected, 1e-9));
+        }
+    }
+
+    #[test]
+    fn welch_p_value_matches_the_t_distribution() {
+        // equal sizes and deviations give 2 (n - 1) degrees of freedom, and the critical
+        // values of the two-sided 5% and 1% levels
+        for (n, t, p) in [
+            (2, 4.302652729911275, 0.05),
+            (6, 2.2281388519649385, 0.05),
+            (6, 3.169272672616951, 0.01),
+            (16, 2.0422724563012373, 0.05),
+        ] {
+            let diff = t * (2.0 / n as f64).sqrt();
+            let (a, b) = (stats(n, 10.0 + diff, 1.0), stats(n, 10.0, 1.0));
+            let value = welch_p_value(&a, &b).unwrap();
+            assert!(close(value, p, 1e-6), "n = {n}, t = {t}: {value}");
+            assert!(close(welch_p_value(&b, &a).unwrap(), p, 1e-6));
+        }
+
+        let a = stats(5, 10.0, 1.0);
+        assert!(close(welch_p_value(&a, &a).unwrap(), 1.0, 1e-9));
+        assert_eq!(welch_p_value(&stats(1, 10.0, 0.0), &a), None);
+        let (c, d) = (stats(3, 10.0, 0.0), stats(3, 11.0, 0.0));
+        assert_eq!(welch_p_value(&c, &c), Some(1.0));
+        assert_eq!(welch_p_value(&c, &d), Some(0.0));
+    }
+}
@@ -0,0 +1,413 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use eyre::Context;
+use serde::{Deserialize, Serialize};
+
+/// Name of the matrix copy kept in every results directory
+const MATRIX_FILE: &str = "matrix.yml";
+/// One [RunEntry] per line, appended as soon as a run ends
+const RUNS_FILE: &str = "runs.jsonl";
+/// Records written by the binaries themselves, see [crate::report::RunRecord]
+const RECORDS_FILE: &str = "records.jsonl";
+
+/// Declarative benchmark matrix.
+///
+/// Every benchmark runs once per cluster and per combination of its parameters.
+/// `clusters`, `features`, `warmup` and `repetitions` are defaults for the benchmarks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Matrix {
+    /// Root of the timestamped results directories
+    #[serde(default = "default_results")]
+    pub results: PathBuf,
+    /// noir arguments selecting the deployment, like `-l8` or `-r noir-1.yml`
+    #[serde(default)]
+    pub clusters: Vec<String>,
+    #[serde(default)]
+    pub features: Vec<String>,
+    #[serde(default)]
+    pub warmup: usize,
+    #[serde(default = "default_repetitions")]
+    pub repetitions: usize,
+    pub benchmarks: Vec<Benchmark>,
+}
+
+fn default_results() -> PathBuf {
+    "results".into()
+}
+
+fn default_repetitions() -> usize {
+    10
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Benchmark {
+    /// Defaults to the binary name
+    pub name: Option<String>,
+    pub binary: String,
+    /// Binary arguments, split on whitespace, `{param}` is replaced by each value of the
+    /// parameter and a leading `~/` by the home directory
+    #[serde(default)]
+    pub args: String,
+    #[serde(default)]
+    pub params: BTreeMap<String, Vec<String>>,
+    pub clusters: Option<Vec<String>>,
+    pub features: Option<Vec<String>>,
+    pub warmup: Option<usize>,
+    pub repetitions: Option<usize>,
+}
+
+/// One point of the matrix
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cell {
+    /// Stable identifier, used to resume a matrix
+    pub id: String,
+    pub benchmark: String,
+    pub binary: String,
+    pub features: Vec<String>,
+    pub cluster: String,
+    pub params: BTreeMap<String, String>,
+    pub args: Vec<String>,
+    pub warmup: usize,
+    pub repetitions: usize,
+}
+
+impl Matrix {
+    pub fn from_file(path: &Path) -> eyre::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read matrix {}", path.display()))?;
+        let matrix: Self = serde_yaml::from_str(&content)
+            .with_context(|| format!("invalid matrix {}", path.display()))?;
+        for b in &matrix.benchmarks {
+            eyre::ensure!(
+                !b.clusters.as_ref().unwrap_or(&matrix.clusters).is_empty(),
+                "benchmark {} has no cluster",
+                b.binary
+            );
+        }
+        Ok(matrix)
+    }
+
+    pub fn cells(&self) -> Vec<Cell> {
+        let mut cells = Vec::new();
+        for b in &self.benchmarks {
+            let name = b.name.clone().unwrap_or_else(|| b.binary.clone());
+            for cluster in b.clusters.as_ref().unwrap_or(&self.clusters) {
+                for params in combinations(&b.params) {
+                    let mut id = format!("{name} [{cluster}]");
+                    for (k, v) in &params {
+                        id.push_str(&format!(" {k}={v}"));
+                    }
+                    cells.push(Cell {
+                        id,
+                        benchmark: name.clone(),
+                        binary: b.binary.clone(),
+                        features: b.features.clone().unwrap_or_else(|| self.features.clone()),
+                        cluster: cluster.clone(),
+                        args: expand_args(&b.args, &params),
+                        params,
+                        warmup: b.warmup.unwrap_or(self.warmup),
+                        repetitions: b.repetitions.unwrap_or(self.repetitions),
+                    });
+                }
+            }
+        }
+        cells
+    }
+}
+
+/// Cartesian product of the parameter values, a single empty assignment without parameters
+fn combinations(params: &BTreeMap<String, Vec<String>>) -> Vec<BTreeMap<String, String>> {
+    params
+        .iter()
+        .fold(vec![BTreeMap::new()], |acc, (k, values)| {
+            acc.iter()
+                .flat_map(|a| {
+                    values.iter().map(move |v| {
+                        let mut a = a.clone();
+                        a.insert(k.clone(), v.clone());
+                        a
+                    })
+                })
+                .collect()
+        })
+}
+
+fn expand_args(args: &str, params: &BTreeMap<String, String>) -> Vec<String> {
+    let mut args = args.to_string();
+    for (k, v) in params {
+        args = args.replace(&format!("{{{k}}}"), v);
+    }
+    let home = std::env::var("HOME").unwrap_or_default();
+    args.split_whitespace()
+        .map(|a| match a.strip_prefix("~/") {
+            Some(rest) => format!("{home}/{rest}"),
+            None => a.to_string(),
+        })
+        .collect()
+}
+
+/// Outcome of one execution of a cell, warmups are not recorded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunEntry {
+    pub cell: String,
+    pub benchmark: String,
+    pub cluster: String,
+    pub params: BTreeMap<String, String>,
+    pub repetition: usize,
+    /// Wall time of the process, like hyperfine
+    pub wall_s: f64,
+    pub success: bool,
+    /// Seconds since the epoch at the end of the run
+    pub timestamp: u64,
+}
+
+/// Runs the cells of a matrix into a results directory
+pub struct Runner {
+    dir: PathBuf,
+    matrix: Matrix,
+}
+
+impl Runner {
+    /// Start a new timestamped results directory under the matrix `results`
+    pub fn create(matrix_path: &Path) -> eyre::Result<Self> {
+        let matrix = Matrix::from_file(matrix_path)?;
+        let dir = matrix.results.join(utc_timestamp(SystemTime::now()));
+        std::fs::create_dir_all(dir.join("logs"))
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        std::fs::copy(matrix_path, dir.join(MATRIX_FILE))?;
+        Ok(Self { dir, matrix })
+    }
+
+    /// Continue the matrix of an existing results directory, skipping the completed runs
+    pub fn resume(dir: &Path) -> eyre::Result<Self> {
+        let matrix = Matrix::from_file(&dir.join(MATRIX_FILE))?;
+        std::fs::create_dir_all(dir.join("logs"))?;
+        Ok(Self {
+            dir: dir.to_owned(),
+            matrix,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn cells(&self) -> Vec<Cell> {
+        self.matrix.cells()
+    }
+
+    /// Successful runs of each cell recorded so far
+    pub fn completed(&self) -> eyre::Result<HashMap<String, usize>> {
+        let mut done = HashMap::new();
+        for e in read_runs(&self.dir)? {
+            if e.success {
+                *done.entry(e.cell).or_default() += 1;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Build every binary of the matrix in release mode
+    pub fn build(&self) -> eyre::Result<()> {
+        let cells = self.cells();
+        let mut built = Vec::new();
+        for c in &cells {
+            if built.contains(&(&c.binary, &c.features)) {
+                continue;
+            }
+            let mut cmd = Command::new("cargo");
+            cmd.args(["build", "--release", "--bin", &c.binary]);
+            if !c.features.is_empty() {
+                cmd.args(["--features", &c.features.join(",")]);
+            }
+            log::info!("building {}", c.binary);
+            let status = cmd.status().context("failed to run cargo")?;
+            eyre::ensure!(status.success(), "failed to build {}", c.binary);
+            built.push((&c.binary, &c.features));
+        }
+        Ok(())
+    }
+
+    pub fn run(&self) -> eyre::Result<()> {
+        let done = self.completed()?;
+        let cells = self.cells();
+        for (i, cell) in cells.iter().enumerate() {
+            let skip = done.get(&cell.id).copied().unwrap_or(0);
+            if skip >= cell.repetitions {
+                log::info!("[{}/{}] {} done, skipping", i + 1, cells.len(), cell.id);
+                continue;
+            }
+            log::info!("[{}/{}] {}", i + 1, cells.len(), cell.id);
+            self.run_cell(cell, skip)?;
+        }
+        Ok(())
+    }
+
+    fn run_cell(&self, cell: &Cell, skip: usize) -> eyre::Result<()> {
+        let log = self
+            .dir
+            .join("logs")
+            .join(format!("{}.log", file_name(&cell.id)));
+        // a failing warmup would fail the repetitions too, the cell is left for a later run
+        for _ in 0..cell.warmup {
+            if !self.execute(cell, &log, false)? {
+                log::error!("{} warmup failed, skipping, see {}", cell.id, log.display());
+                return Ok(());
+            }
+        }
+        for repetition in skip..cell.repetitions {
+            let start = Instant::now();
+            let success = self.execute(cell, &log, true)?;
+            let entry = RunEntry {
+                cell: cell.id.clone(),
+                benchmark: cell.benchmark.clone(),
+                cluster: cell.cluster.clone(),
+                params: cell.params.clone(),
+                repetition,
+                wall_s: start.elapsed().as_secs_f64(),
+                success,
+                timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            };
+            if !success {
+                log::error!("{} failed, see {}", cell.id, log.display());
+            }
+            append_json(&self.dir.join(RUNS_FILE), &entry)?;
+        }
+        Ok(())
+    }
+
+    fn execute(&self, cell: &Cell, log: &Path, record: bool) -> eyre::Result<bool> {
+        let out = OpenOptions::new().create(true).append(true).open(log)?;
+        let mut cmd = Command::new(Path::new("target/release").join(&cell.binary));
+        cmd.args(cell.cluster.split_whitespace())
+            .arg("--")
+            .args(&cell.args)
+            .stdout(Stdio::from(out.try_clone()?))
+            .stderr(Stdio::from(out));
+        match record {
+            true => {
+                cmd.arg("--record").arg(self.dir.join(RECORDS_FILE));
+                // the record names its variant like the other results
+                for (k, v) in &cell.params {
+                    cmd.arg(format!("--record-param={k}={v}"));
+                }
+            }
+            false => {
+                cmd.arg("--no-record");
+            }
+        }
+        let status = cmd
+            .status()
+            .with_context(|| format!("failed to run {}", cell.binary))?;
+        Ok(status.success())
+    }
+}
+
+pub fn read_runs(dir: &Path) -> eyre::Result<Vec<RunEntry>> {
+    read_json_lines(&dir.join(RUNS_FILE))
+}
+
+pub fn read_records(dir: &Path) -> eyre::Result<Vec<crate::report::RunRecord>> {
+    read_json_lines(&dir.join(RECORDS_FILE))
+}
+
+pub(crate) fn read_json_lines<T: serde::de::DeserializeOwned>(path: &Path) -> eyre::Result<Vec<T>> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).with_context(|| format!("failed to open {}", path.display())),
+    };
+    BufReader::new(f)
+        .lines()
+        .filter(|l| !matches!(l, Ok(l) if l.trim().is_empty()))
+        .map(|l| Ok(serde_json::from_str(&l?)?))
+        .collect()
+}
+
+fn append_json(path: &Path, value: &impl Serialize) -> eyre::Result<()> {
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(f, "{}", serde_json::to_string(value)?)?;
+    Ok(())
+}
+
+fn file_name(id: &str) -> String {
+    id.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' | '=' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// `date -uIseconds` format, like the hyperfine exports in `results/`
+fn utc_timestamp(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!(
+        "{y:04}-{m:02}-{d:02}T{:02}:{:02}:{:02}+00:00",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(p: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        p.iter()
+            .map(|(k, v)| (k.to_string(), v.iter().map(|v| v.to_string()).collect()))
+            .collect()
+    }
+
+    fn assignment(a: &[(&str, &str)]) -> BTreeMap<String, String> {
+        a.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn combinations_are_the_cartesian_product() {
+        assert_eq!(combinations(&params(&[])), [assignment(&[])]);
+        assert_eq!(
+            combinations(&params(&[("b", &["x", "y"]), ("a", &["1", "2"])])),
+            [
+                assignment(&[("a", "1"), ("b", "x")]),
+                assignment(&[("a", "1"), ("b", "y")]),
+                assignment(&[("a", "2"), ("b", "x")]),
+                assignment(&[("a", "2"), ("b", "y")]),
+            ]
+        );
+        assert!(combinations(&params(&[("a", &["1"]), ("b", &[])])).is_empty());
+    }
+
+    #[test]
+    fn utc_timestamp_matches_known_dates() {
+        for (secs, date) in [
+            (0, "1970-01-01T00:00:00+00:00"),
+            (951_782_400, "2000-02-29T00:00:00+00:00"),
+            (1_234_567_890, "2009-02-13T23:31:30+00:00"),
+            (4_107_542_399, "2100-02-28T23:59:59+00:00"),
+            (4_107_542_400, "2100-03-01T00:00:00+00:00"),
+        ] {
+            let t = UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            assert_eq!(utc_timestamp(t), date);
+        }
+    }
+}
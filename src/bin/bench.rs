@@ -0,0 +1,128 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use noir_plus_extra::bench::report::{self, Format};
+use noir_plus_extra::bench::runner::{Matrix, Runner};
+
+#[derive(Debug, Parser)]
+struct Options {
+    #[clap(subcommand)]
+    command: Commands,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Run a benchmark matrix into a new timestamped results directory
+    Run {
+        /// Matrix file, see `bench.yml`
+        matrix: PathBuf,
+
+        /// Do not build the binaries before running
+        #[clap(long)]
+        no_build: bool,
+
+        /// Only list the cells
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Complete the matrix of an interrupted run
+    Resume {
+        /// Results directory created by `run`
+        dir: PathBuf,
+
+        /// Do not build the binaries before running
+        #[clap(long)]
+        no_build: bool,
+    },
+    /// Summarize results, with the speedup over a baseline cluster and the scaling efficiency
+    Report {
+        /// Results directories of `run`, hyperfine JSON exports or record files
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Cluster the speedup is computed against
+        #[clap(long, default_value = "-l8", allow_hyphen_values = true)]
+        baseline: String,
+
+        #[clap(long, value_enum, default_value = "markdown")]
+        format: Format,
+    },
+    /// Flag statistically significant changes between two results
+    Compare {
+        old: PathBuf,
+        new: PathBuf,
+
+        /// Significance level of the Welch t-test
+        #[clap(long, default_value_t = 0.05)]
+        alpha: f64,
+
+        #[clap(long, value_enum, default_value = "markdown")]
+        format: Format,
+
+        /// Exit with an error if any configuration regressed
+        #[clap(long)]
+        fail_on_regression: bool,
+    },
+}
+
+fn main() -> eyre::Result<()> {
+    color_eyre::install().ok();
+    tracing_subscriber::fmt::init();
+    let opt = Options::parse();
+
+    match opt.command {
+        Commands::Run {
+            matrix,
+            no_build,
+            dry_run,
+        } => {
+            if dry_run {
+                let matrix = Matrix::from_file(&matrix)?;
+                for cell in matrix.cells() {
+                    println!("{} x{}: {}", cell.id, cell.repetitions, cell.args.join(" "));
+                }
+                return Ok(());
+            }
+            let runner = Runner::create(&matrix)?;
+            eprintln!("results: {}", runner.dir().display());
+            run(&runner, no_build)
+        }
+        Commands::Resume { dir, no_build } => run(&Runner::resume(&dir)?, no_build),
+        Commands::Report {
+            paths,
+            baseline,
+            format,
+        } => {
+            let mut samples = Vec::new();
+            for p in &paths {
+                samples.extend(report::load(p)?);
+            }
+            let groups = report::group(samples);
+            report::summary(&groups, &baseline).write(format, std::io::stdout())
+        }
+        Commands::Compare {
+            old,
+            new,
+            alpha,
+            format,
+            fail_on_regression,
+        } => {
+            let old = report::group(report::load(&old)?);
+            let new = report::group(report::load(&new)?);
+            let (table, regressions) = report::compare(&old, &new, alpha);
+            table.write(format, std::io::stdout())?;
+            eyre::ensure!(
+                !fail_on_regression || regressions == 0,
+                "{regressions} configurations regressed"
+            );
+            Ok(())
+        }
+    }
+}
+
+fn run(runner: &Runner, no_build: bool) -> eyre::Result<()> {
+    if !no_build {
+        runner.build()?;
+    }
+    runner.run()
+}
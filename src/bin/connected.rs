@@ -1,27 +1,40 @@
 use ahash::AHashMap as HashMap;
 use clap::Parser;
+use std::mem::{size_of, take};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use noir_compute::prelude::*;
+use noir_plus_extra::graph::input::{Graph, GraphArgs};
+use noir_plus_extra::output::OutputArgs;
+use noir_plus_extra::report::{ClusterInfo, ReportArgs, RunRecord};
 use serde::{Deserialize, Serialize};
 
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-#[derive(clap::Parser)]
+#[derive(Clone, clap::Parser, Serialize)]
 struct Options {
     #[clap(long, short('i'))]
     iterations: usize,
-    #[clap(short, long)]
-    nodes_path: String,
-    #[clap(short, long)]
-    edges_path: String,
-    #[clap(short('N'), long)]
-    nodes: usize,
+    #[clap(flatten)]
+    graph: GraphArgs,
 
     #[clap(long, short)]
     shared: bool,
+
+    /// Keep the component of every node in the stream, partitioned by node, instead of a
+    /// dense vector broadcast as iteration state. Handles any node id
+    #[clap(long, conflicts_with = "shared")]
+    keyed: bool,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    report: ReportArgs,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    output: OutputArgs,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default)]
@@ -39,27 +52,162 @@ impl State {
             iteration_count: 0,
         }
     }
+
+    /// `(node, component)` of every node
+    fn rows(self) -> Vec<(u64, u64)> {
+        (0..).zip(self.component).collect()
+    }
+
+    /// Bytes broadcast every iteration for `num_nodes` nodes
+    fn size(num_nodes: usize) -> usize {
+        size_of::<Self>() + num_nodes * size_of::<u64>()
+    }
+}
+
+/// Component of a node in the keyed variant
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct Node {
+    id: u64,
+    component: u64,
+    /// Whether the component changed in the last iteration, only then it is sent to the
+    /// neighbours
+    changed: bool,
+    /// Messages gathered in the last iteration, its own included
+    received: u64,
+}
+
+impl Node {
+    fn new(id: u64) -> Self {
+        Self {
+            id,
+            component: id,
+            changed: true,
+            received: 0,
+        }
+    }
+
+    /// The node keeps its own component, and sends it to its neighbours if it changed
+    fn messages(self, adj: Option<&[u64]>) -> Vec<(u64, Gather)> {
+        let own = Gather {
+            own: Some(self.component),
+            min: self.component,
+            count: 1,
+        };
+        let sent = Gather {
+            own: None,
+            min: self.component,
+            count: 1,
+        };
+        let adj = adj.filter(|_| self.changed).unwrap_or_default();
+        std::iter::once((self.id, own))
+            .chain(adj.iter().map(|&y| (y, sent)))
+            .collect()
+    }
+}
+
+/// What a node gathers in an iteration of the keyed variant
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct Gather {
+    /// Component of the node before the iteration
+    own: Option<u64>,
+    /// Smallest component among its own and the received ones
+    min: u64,
+    /// Messages merged
+    count: u64,
+}
+
+impl Default for Gather {
+    fn default() -> Self {
+        Self {
+            own: None,
+            min: u64::MAX,
+            count: 0,
+        }
+    }
 }
 
-fn connected_components_join(config: EnvironmentConfig, opts: Options) -> eyre::Result<()> {
-    let mut env = StreamEnvironment::new(config);
+impl Gather {
+    fn merge(&mut self, other: Gather) {
+        self.own = self.own.or(other.own);
+        self.min = self.min.min(other.min);
+        self.count += other.count;
+    }
+
+    /// Component of node `x` after the iteration, a node found only in the edges starts in
+    /// its own component
+    fn resolve(self, x: u64) -> Node {
+        let own = self.own.unwrap_or(x);
+        let component = own.min(self.min);
+        Node {
+            id: x,
+            component,
+            changed: component < own,
+            received: self.count,
+        }
+    }
+}
 
-    let nodes_source = CsvSource::<u64>::new(opts.nodes_path).has_headers(false);
-    let edges_source = CsvSource::<(u64, u64)>::new(opts.edges_path)
-        .delimiter(b',')
-        .has_headers(false);
+/// Iteration state of the keyed variant, which holds no node
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct Progress {
+    /// Nodes whose component changed in the last iteration
+    changes: u64,
+    /// Nodes and messages shuffled by the iterations so far
+    nodes: u64,
+    messages: u64,
+    iteration_count: usize,
+}
+
+impl Progress {
+    fn add(&mut self, node: Node) {
+        self.changes += node.changed as u64;
+        self.nodes += 1;
+        self.messages += node.received;
+    }
+
+    fn merge(&mut self, delta: Progress) {
+        self.changes += delta.changes;
+        self.nodes += delta.nodes;
+        self.messages += delta.messages;
+    }
+
+    /// Bytes of the nodes joined with their neighbours and of the messages they sent
+    fn shuffled_bytes(&self) -> u64 {
+        self.nodes * size_of::<Node>() as u64 + self.messages * size_of::<(u64, Gather)>() as u64
+    }
+}
 
-    let edges = env
-        .stream(edges_source)
+/// Result of a job, the iterations are known on the host collecting the final state
+struct Outcome {
+    elapsed: Duration,
+    iterations: Option<usize>,
+    /// Bytes of the iteration state
+    state_bytes: usize,
+    /// Bytes of the per-node state and messages shuffled by all the iterations, where the
+    /// iteration state is collected. Only measured by the keyed variant
+    shuffled_bytes: Option<u64>,
+}
+
+fn connected_components_join(
+    config: RuntimeConfig,
+    opts: Options,
+    graph: Graph,
+) -> eyre::Result<Outcome> {
+    let host_id = config.host_id();
+    let num_nodes = graph.num_nodes()?;
+    let mut env = StreamContext::new(config);
+
+    let edges = graph
+        .edge_source(&mut env)
         .flat_map(|(x, y)| vec![(x, y), (y, x)]);
 
-    let (result, dropme) = env
-        .stream(nodes_source)
+    let (result, dropme) = graph
+        .node_source(&mut env)
         // put each node in its own component
         .map(|x| (x, x))
         .iterate(
             opts.iterations,
-            State::new(opts.nodes),
+            State::new(num_nodes),
             move |s, state| {
                 s.join(edges, |&(x, _component)| x, |&(x, _y)| x)
                     .map(|(_, ((_x, component), (_, y)))| (y, component))
@@ -92,29 +240,48 @@ fn connected_components_join(config: EnvironmentConfig, opts: Options) -> eyre::
                 condition
             },
         );
-    let result = result.collect_vec();
+    let result = match opts.output.sink(host_id, &["node", "component"])? {
+        // the final state is emitted once, spread its rows between the replicas
+        Some(sink) => {
+            result.flat_map(State::rows).shuffle().for_each(sink);
+            None
+        }
+        None => Some(result.collect_vec()),
+    };
     dropme.for_each(std::mem::drop);
 
     let start = Instant::now();
     env.execute_blocking();
     let elapsed = start.elapsed();
 
-    std::hint::black_box(result);
+    // the final state is collected on a single host
+    let state = result.and_then(|r| r.get());
+    let iterations = state
+        .as_ref()
+        .and_then(|s| s.last())
+        .map(|s| s.iteration_count);
+    std::hint::black_box(state);
 
     eprintln!("{elapsed:?}");
-    Ok(())
+    Ok(Outcome {
+        elapsed,
+        iterations,
+        state_bytes: State::size(num_nodes),
+        shuffled_bytes: None,
+    })
 }
 
-fn connected_components_shared(config: EnvironmentConfig, opts: Options) -> eyre::Result<()> {
-    let mut env = StreamEnvironment::new(config.clone());
-    let nodes_source = CsvSource::<u64>::new(opts.nodes_path).has_headers(false);
+fn connected_components_shared(
+    config: RuntimeConfig,
+    opts: Options,
+    graph: Graph,
+) -> eyre::Result<Outcome> {
+    let host_id = config.host_id();
+    let num_nodes = graph.num_nodes()?;
+    let mut env = StreamContext::new(config.clone());
 
-    let edges_source = CsvSource::<(u64, u64)>::new(opts.edges_path)
-        .delimiter(b',')
-        .has_headers(false);
-
-    let edges = env
-        .stream(edges_source)
+    let edges = graph
+        .edge_source(&mut env)
         // edges are undirected
         .flat_map(|(x, y)| vec![(x, y), (y, x)])
         .group_by_fold(
@@ -129,19 +296,22 @@ fn connected_components_shared(config: EnvironmentConfig, opts: Options) -> eyre
     env.execute_blocking();
 
     let edges = Arc::new(edges.get().unwrap());
-    
-    let mut env = StreamEnvironment::new(config);
-    let (result, dropme) = env
-        .stream(nodes_source)
+
+    let mut env = StreamContext::new(config);
+    let (result, dropme) = graph
+        .node_source(&mut env)
         // put each node in its own component
         .map(|x| (x, x))
         .iterate(
             opts.iterations,
-            State::new(opts.nodes),
+            State::new(num_nodes),
             move |s, state| {
                 s.flat_map(move |(x, c)| {
-                    edges[&x]
-                        .iter()
+                    // isolated nodes have no entry
+                    edges
+                        .get(&x)
+                        .into_iter()
+                        .flatten()
                         .filter(|&&y| c < y)
                         .map(move |&y| (y, c))
                         .collect::<Vec<_>>()
@@ -176,29 +346,146 @@ fn connected_components_shared(config: EnvironmentConfig, opts: Options) -> eyre
                 condition
             },
         );
-    let result = result.collect_vec();
+    let result = match opts.output.sink(host_id, &["node", "component"])? {
+        // the final state is emitted once, spread its rows between the replicas
+        Some(sink) => {
+            result.flat_map(State::rows).shuffle().for_each(sink);
+            None
+        }
+        None => Some(result.collect_vec()),
+    };
     dropme.for_each(std::mem::drop);
 
     env.execute_blocking();
     let elapsed = start.elapsed();
 
-    std::hint::black_box(result);
+    // the final state is collected on a single host
+    let state = result.and_then(|r| r.get());
+    let iterations = state
+        .as_ref()
+        .and_then(|s| s.last())
+        .map(|s| s.iteration_count);
+    std::hint::black_box(state);
 
     eprintln!("{elapsed:?}");
-    Ok(())
+    Ok(Outcome {
+        elapsed,
+        iterations,
+        state_bytes: State::size(num_nodes),
+        shuffled_bytes: None,
+    })
+}
+
+fn connected_components_keyed(
+    config: RuntimeConfig,
+    opts: Options,
+    graph: Graph,
+) -> eyre::Result<Outcome> {
+    let host_id = config.host_id();
+    let mut env = StreamContext::new(config);
+
+    let adj_list = graph
+        .edge_source(&mut env)
+        // edges are undirected
+        .flat_map(|(x, y)| vec![(x, y), (y, x)])
+        // construct adjacency list
+        .group_by_fold(
+            |(x, _y)| *x,
+            Vec::new(),
+            |edges, (_x, y)| edges.push(y),
+            |edges1, mut edges2| edges1.append(&mut edges2),
+        )
+        .unkey();
+
+    let (progress, result) = graph
+        .node_source(&mut env)
+        // put each node in its own component
+        .map(Node::new)
+        .iterate(
+            opts.iterations,
+            Progress::default(),
+            move |s, _| {
+                // every node flows to its own replica, only the changed ones reach the
+                // neighbours. Isolated nodes are kept by the left join
+                s.left_join(adj_list, |n| n.id, |(x, _adj)| *x)
+                    .flat_map(|(_, (node, adj))| {
+                        node.messages(adj.as_ref().map(|(_, a)| a.as_slice()))
+                    })
+                    .drop_key()
+                    .group_by_fold(
+                        |&(x, _)| x,
+                        Gather::default(),
+                        |acc, (_x, g)| acc.merge(g),
+                        |acc, g| acc.merge(g),
+                    )
+                    .map(|(&x, g)| g.resolve(x))
+                    .drop_key()
+            },
+            Progress::add,
+            Progress::merge,
+            |progress| {
+                // stop if there were no changes
+                let condition = take(&mut progress.changes) > 0;
+                progress.iteration_count += 1;
+                condition
+            },
+        );
+    let rows = result.map(|n| (n.id, n.component));
+    let result = match opts.output.sink(host_id, &["node", "component"])? {
+        // the nodes are already spread between the replicas
+        Some(sink) => {
+            rows.for_each(sink);
+            None
+        }
+        None => Some(rows.collect_vec()),
+    };
+    let progress = progress.collect_vec();
+
+    let start = Instant::now();
+    env.execute_blocking();
+    let elapsed = start.elapsed();
+
+    std::hint::black_box(result.and_then(|r| r.get()));
+    let progress = progress.get().and_then(|mut p| p.pop());
+
+    eprintln!("{elapsed:?}");
+    Ok(Outcome {
+        elapsed,
+        iterations: progress.as_ref().map(|p| p.iteration_count),
+        state_bytes: size_of::<Progress>(),
+        shuffled_bytes: progress.as_ref().map(Progress::shuffled_bytes),
+    })
 }
 
 fn main() -> eyre::Result<()> {
     color_eyre::install().ok();
-    let (config, args) = EnvironmentConfig::from_args();
+    let (config, args) = RuntimeConfig::from_args();
     let opts = Options::parse_from(args);
 
     config.spawn_remote_workers();
 
-    match opts.shared {
-        true => connected_components_shared(config, opts),
-        false => connected_components_join(config, opts),
+    let graph = opts.graph.graph()?;
+    let cluster = ClusterInfo::new(&config);
+    let outcome = match (opts.keyed, opts.shared) {
+        (true, _) => connected_components_keyed(config, opts.clone(), graph),
+        (false, true) => connected_components_shared(config, opts.clone(), graph),
+        (false, false) => connected_components_join(config, opts.clone(), graph),
     }?;
+    eprintln!("iteration state: {} bytes", outcome.state_bytes);
+    if let Some(bytes) = outcome.shuffled_bytes {
+        eprintln!("shuffled: {bytes} bytes");
+    }
 
+    let mut record = RunRecord::new(
+        env!("CARGO_BIN_NAME"),
+        &opts,
+        cluster,
+        None,
+        outcome.elapsed,
+    )?;
+    record.iterations = outcome.iterations;
+    record.state_bytes = Some(outcome.state_bytes);
+    record.shuffled_bytes = outcome.shuffled_bytes;
+    record.write(&opts.report)?;
     Ok(())
 }
@@ -1,25 +1,50 @@
-use std::{ops::Rem, time::Instant};
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 use eyre::{Context, Result};
 use noir_compute::{operator::Operator, prelude::*, Stream};
-use noir_plus_extra::enrich::{postgres_blocking as db, postgres as pg_async, types::Product};
-use r2d2_postgres::postgres;
-use rand::prelude::*;
-use rand_distr::Exp;
+use noir_plus_extra::enrich::backend::{
+    AnyBackend, AnyBackendBlocking, BackendKind, ConnectConfig, EnrichBackend,
+    EnrichBackendBlocking, Recommender,
+};
+use noir_plus_extra::enrich::cache::{self, CacheConfig};
+use noir_plus_extra::enrich::pool;
+use noir_plus_extra::enrich::types::Product;
+use noir_plus_extra::enrich::verify::{self, VerifyConfig};
+use noir_plus_extra::latency::{self, Timed};
+use noir_plus_extra::report::{ClusterInfo, ReportArgs, RunRecord};
+use noir_plus_extra::retry::{self, DeadLetter, RetryConfig, RetryPolicy};
+use noir_plus_extra::trace::{self, TraceArgs};
+use noir_plus_extra::workload::{FlaggedKey, WorkloadConfig};
+use serde::Serialize;
 
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Parser, Serialize)]
 struct Options {
-    /// Number of generated events
-    #[clap(short('n'), long, default_value_t = 1_000_000)]
-    event_number: u64,
+    #[clap(flatten)]
+    workload: WorkloadConfig,
 
-    /// 1 / lambda parameter for exponential id distribution
-    #[clap(short('l'), long, default_value_t = 20_000)]
-    lambda_inv: usize,
+    #[clap(flatten)]
+    #[serde(skip)]
+    report: ReportArgs,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    trace: TraceArgs,
+
+    #[clap(flatten)]
+    retry: RetryConfig,
+
+    #[clap(flatten)]
+    cache: CacheConfig,
+
+    #[clap(flatten)]
+    connect: ConnectConfig,
+
+    #[clap(flatten)]
+    verify: VerifyConfig,
 
     /// Size of the memoization caches, disable memoization if None
     #[clap(short('m'), long)]
@@ -27,66 +52,139 @@ struct Options {
 
     #[clap(long, short)]
     shared: bool,
+
+    /// Store used for the lookups, defaults to `postgres-blocking` for the pooled
+    /// pipeline and `postgres` for the async one
+    #[clap(long, value_enum)]
+    backend: Option<BackendKind>,
+
+    /// Recommendation strategy
+    #[clap(long, value_enum, default_value_t = Recommender::Category)]
+    recommender: Recommender,
+
+    /// Fraction of the loaded products that also mark a hit, 0 disables the write path
+    #[clap(short('w'), long, default_value_t = 0.0)]
+    write_ratio: f64,
+
+    /// Load the products of this many events with a single multi-get in the async
+    /// pipeline, 0 loads each event on its own
+    #[clap(long, default_value_t = 0)]
+    lookup_batch: usize,
 }
 
 fn main() -> Result<()> {
     color_eyre::install().ok();
     dotenvy::dotenv().ok();
-    tracing_subscriber::fmt::init();
-    let (conf, args) = EnvironmentConfig::from_args();
+    let (conf, args) = RuntimeConfig::from_args();
     conf.spawn_remote_workers();
-    let opt = Options::try_parse_from(args)?;
+    let mut opt = Options::try_parse_from(args)?;
+    opt.connect.for_host(&conf);
+    let _trace = trace::init(&opt.trace, conf.host_id())?;
     tracing::info!("config: {opt:?}");
 
-    let lambda = 1. / opt.lambda_inv as f32;
+    eyre::ensure!(
+        (0.0..=1.0).contains(&opt.write_ratio),
+        "write ratio must be in 0..=1"
+    );
+    eyre::ensure!(
+        !opt.verify.verify || opt.write_ratio == 0.0,
+        "verification requires a read-only run (-w 0)"
+    );
+    eyre::ensure!(
+        opt.lookup_batch == 0 || opt.shared,
+        "batched lookups require the async pipeline (-s)"
+    );
 
     // db::db_setup()?;
 
+    let retry = opt.retry.policy()?;
+    opt.verify.init(&opt.connect.dataset, opt.recommender)?;
+    let cluster = ClusterInfo::new(&conf);
     let start = Instant::now();
-    match opt.shared {
-        false => pipeline_pool(conf, lambda, opt.event_number)?,
-        true => pipeline_async(conf, lambda, opt.event_number)?,
-    }
+    let (elapsed, write_retries) = match opt.shared {
+        false => {
+            let backend = opt.backend.unwrap_or(BackendKind::PostgresBlocking);
+            let pool = AnyBackendBlocking::connect(backend, &opt.connect)?
+                .with_cache(&opt.cache, &opt.connect.dataset)?;
+            pipeline_pool(
+                conf,
+                &opt.workload,
+                pool,
+                opt.recommender,
+                opt.write_ratio,
+                retry,
+            )?
+        }
+        true => {
+            let backend = opt.backend.unwrap_or(BackendKind::Postgres);
+            pipeline_async(conf, &opt, backend, retry)?
+        }
+    };
     eprintln!("time: {:?}", start.elapsed());
+    eprintln!("latency: {}", latency::summary_json());
+    eprintln!("errors: {}", retry::summary_json());
+    let write_retries = (opt.write_ratio > 0.0).then_some(write_retries);
+    if let Some(n) = write_retries {
+        eprintln!("write retries: {n}");
+    }
+    eprintln!("pools: {}", pool::summary_json());
+    if opt.cache.enabled() {
+        eprintln!("cache: {}", cache::summary_json());
+    }
+    if opt.verify.verify {
+        eprintln!("verify: {}", verify::summary_json());
+    }
     micrometer::summary_grouped();
-    // micrometer::append_csv_uniform(
-    //     "target/mm.csv",
-    //     &format!("{}-c{}l{:?}", name, opt.event_number, lambda),
-    // )?;
 
-    Ok(())
-}
+    let events = Some(opt.workload.event_number);
+    let mut record = RunRecord::new(env!("CARGO_BIN_NAME"), &opt, cluster, events, elapsed)?;
+    record.write_retries = write_retries;
+    record.write(&opt.report)?;
 
-fn make_source(
-    lambda: f32,
-    env: &mut StreamEnvironment,
-    events: u64,
-) -> eyre::Result<Stream<impl Operator<Out = i32>>> {
-    let distr = Exp::new(lambda)?;
-    let source = env
-        .stream_par_iter(move |i, n| {
-            let mut rng = SmallRng::seed_from_u64(i ^ 0xfeeddabeef);
-            (0..events / n).map(move |_| distr.sample(&mut rng).max(1.0).rem(1_000_000.) as i32)
-        })
-        .batch_mode(BatchMode::adaptive(8192, std::time::Duration::from_secs(1)));
-    Ok(source)
+    if let Some(v) = verify::summary() {
+        eyre::ensure!(
+            v.mismatched() == 0,
+            "{} of {} recommendations differ from the dataset",
+            v.mismatched(),
+            v.checked
+        );
+    }
+
+    Ok(())
 }
 
-fn map_get_product(db: &mut postgres::Client, id: i32) -> Option<Product> {
-    db::get_product(db, id).context("get_product").unwrap()
+fn map_get_product(
+    db: &impl EnrichBackendBlocking,
+    retry: &RetryPolicy,
+    id: i32,
+) -> Result<Option<Product>, DeadLetter<i32>> {
+    retry.run("get_product", &id, || {
+        db.get_product(id).context("get_product")
+    })
 }
 
-fn map_get_recommendation(db: &mut postgres::Client, p: Product) -> (Product, Vec<Product>) {
-    let rec = db::recommend_0(db, &p).context("recommend").unwrap();
-    (p, rec)
+fn map_get_recommendation(
+    db: &impl EnrichBackendBlocking,
+    retry: &RetryPolicy,
+    p: Product,
+    r: Recommender,
+) -> Result<(Product, Vec<Product>), DeadLetter<Product>> {
+    let _span = micrometer::span!("recommend");
+    let rec = retry.run("recommend", &p, || db.recommend(&p, r).context("recommend"))?;
+    Ok((p, rec))
 }
 
-#[allow(unused)]
-fn map_mark_hit(db: &mut postgres::Client, p: Product) {
-    db::mark_hit(db, &p).context("mark_hit").unwrap();
+fn map_mark_hit(
+    db: &impl EnrichBackendBlocking,
+    retry: &RetryPolicy,
+    p: Product,
+) -> Result<(), DeadLetter<Product>> {
+    let _span = micrometer::span!("mark_hit");
+    retry.run("mark_hit", &p, || db.mark_hit(&p).context("mark_hit"))
 }
 
 fn inspect((p, rec): (Product, Vec<Product>)) {
+    verify::check(&p, &rec);
     if p.id % 5000 == 0 {
         println!(
             "{}: {}",
@@ -96,79 +194,235 @@ fn inspect((p, rec): (Product, Vec<Product>)) {
     }
 }
 
-fn pipeline_pool(conf: EnvironmentConfig, lambda: f32, events: u64) -> Result<()> {
-    let mut env = StreamEnvironment::new(conf);
-    let source = make_source(lambda, &mut env, events)?;
-    let pool = db::db_init_pool()?;
+fn pipeline_pool(
+    conf: RuntimeConfig,
+    workload: &WorkloadConfig,
+    pool: AnyBackendBlocking,
+    recommender: Recommender,
+    write_ratio: f64,
+    retry: RetryPolicy,
+) -> Result<(Duration, u64)> {
+    let mut env = StreamContext::new(conf);
+    let source = workload.timed_write_source(&mut env, write_ratio)?;
 
-    // Load
+    // Load, keeping the write flag of every event
     let db = pool.clone();
     let s2 = source
-        .map(move |id| {
-            let mut db = db.get().unwrap();
-            map_get_product(&mut db, id)
+        .map(move |e| {
+            e.map(|(id, write)| map_get_product(&db, &retry, id).map(|p| p.map(|p| (p, write))))
+                .transpose()
         })
+        .filter_map(retry::sink())
+        .map(|e| e.transpose())
         .flatten()
-        .filter(|p| p.id % 101 < 57);
+        .rich_map(latency::recorder("get_product"))
+        .filter(|e| e.value.0.id % 101 < 57);
 
-    // Recommend
-    let db = pool.clone();
-    s2.map(move |p| {
-        let mut db = db.get().unwrap();
-        map_get_recommendation(&mut db, p)
-    })
-    .for_each(inspect);
+    if write_ratio > 0.0 {
+        let mut s2 = s2.split(2);
+
+        // Process
+        let db = pool.clone();
+        s2.pop()
+            .unwrap()
+            .filter(|e| e.value.1)
+            .map(move |e| map_mark_hit(&db, &retry, e.value.0))
+            .filter_map(retry::sink())
+            .for_each(std::mem::drop);
 
+        // Recommend
+        let db = pool.clone();
+        s2.pop()
+            .unwrap()
+            .map(move |e| {
+                e.map(|(p, _)| map_get_recommendation(&db, &retry, p, recommender))
+                    .transpose()
+            })
+            .filter_map(retry::sink())
+            .rich_map(latency::recorder("recommend"))
+            .for_each(|e| inspect(e.value));
+    } else {
+        // Recommend
+        let db = pool.clone();
+        s2.map(move |e| {
+            e.map(|(p, _)| map_get_recommendation(&db, &retry, p, recommender))
+                .transpose()
+        })
+        .filter_map(retry::sink())
+        .rich_map(latency::recorder("recommend"))
+        .for_each(|e| inspect(e.value));
+    }
+
+    let start = Instant::now();
     env.execute_blocking();
+    let elapsed = start.elapsed();
 
-    Ok(())
+    Ok((elapsed, pool.write_retries()))
 }
 
-async fn map_get_product_async(db: pg_async::Pool, id: i32) -> Option<Product> {
-    pg_async::get_product(&db, id)
+async fn map_get_product_async(
+    db: impl EnrichBackend,
+    retry: RetryPolicy,
+    id: i32,
+) -> Result<Option<Product>, DeadLetter<i32>> {
+    let db = &db;
+    retry
+        .run_async("get_product", &id, move || async move {
+            db.get_product(id).await.context("get_product")
+        })
         .await
-        .context("get_product")
-        .unwrap()
 }
 
-async fn map_get_recommendation_async(db: pg_async::Pool, p: Product) -> (Product, Vec<Product>) {
-    let rec = pg_async::recommend_0(&db, &p).await.context("recommend").unwrap();
-    (p, rec)
+async fn map_get_products_many_async(
+    db: impl EnrichBackend,
+    retry: RetryPolicy,
+    batch: Vec<Timed<FlaggedKey>>,
+) -> Result<Vec<Timed<Option<(Product, bool)>>>, DeadLetter<Vec<i32>>> {
+    let _span = micrometer::span!("get_products_many");
+    let ids = batch.iter().map(|e| e.value.0).collect::<Vec<_>>();
+    let (db, q) = (&db, &ids);
+    let products = retry
+        .run_async("get_products_many", &ids, move || async move {
+            db.get_products_many(q).await.context("get_products_many")
+        })
+        .await?;
+    Ok(batch
+        .into_iter()
+        .zip(products)
+        .map(|(e, p)| e.map(|(_, write)| p.map(|p| (p, write))))
+        .collect())
+}
+
+async fn map_get_recommendation_async(
+    db: impl EnrichBackend,
+    retry: RetryPolicy,
+    p: Product,
+    r: Recommender,
+) -> Result<(Product, Vec<Product>), DeadLetter<Product>> {
+    let _span = micrometer::span!("recommend");
+    let (db, q) = (&db, &p);
+    let rec = retry
+        .run_async("recommend", &p, move || async move {
+            db.recommend(q, r).await.context("recommend")
+        })
+        .await?;
+    Ok((p, rec))
 }
 
-#[allow(unused)]
-async fn map_mark_hit_async(db: pg_async::Pool, p: Product) {
-    pg_async::mark_hit(&db, &p).await.context("mark_hit").unwrap();
+async fn map_mark_hit_async(
+    db: impl EnrichBackend,
+    retry: RetryPolicy,
+    p: Product,
+) -> Result<(), DeadLetter<Product>> {
+    let _span = micrometer::span!("mark_hit");
+    let (db, q) = (&db, &p);
+    retry
+        .run_async("mark_hit", &p, move || async move {
+            db.mark_hit(q).await.context("mark_hit")
+        })
+        .await
 }
 
-fn pipeline_async(conf: EnvironmentConfig, lambda: f32, events: u64) -> Result<()> {
+fn pipeline_async(
+    conf: RuntimeConfig,
+    opt: &Options,
+    backend: BackendKind,
+    retry: RetryPolicy,
+) -> Result<(Duration, u64)> {
+    let (workload, recommender, write_ratio) = (&opt.workload, opt.recommender, opt.write_ratio);
+    let lookup_batch = opt.lookup_batch;
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(async move {
-            let mut env = StreamEnvironment::new(conf);
-            let source = make_source(lambda, &mut env, events)?;
-            let pool = pg_async::db_init_pool().await?;
-        
+            let mut env = StreamContext::new(conf);
+            let pool = AnyBackend::connect(backend, &opt.connect)
+                .await?
+                .with_cache(&opt.cache, &opt.connect.dataset)?;
+
             // Load
             let db = pool.clone();
-            let s2 = source
-                .map_async(move |id| map_get_product_async(db.clone(), id))
-                .flatten()
-                .filter(|p| p.id % 101 < 57);
-            
-            // Recommend
-            let db = pool.clone();
-            s2
-                // .pop()
-                // .unwrap()
-                .map_async(move |p| map_get_recommendation_async(db.clone(), p))
-                .for_each(inspect);
-        
+            if lookup_batch > 0 {
+                // one round trip for every batch of events
+                let s2 = workload
+                    .timed_batch_source(&mut env, lookup_batch, write_ratio)?
+                    .map_async(move |batch| map_get_products_many_async(db.clone(), retry, batch))
+                    .filter_map(retry::sink())
+                    .flatten()
+                    .map(|e| e.transpose())
+                    .flatten();
+                enrich_async(s2, &pool, recommender, write_ratio, retry);
+            } else {
+                let s2 = workload
+                    .timed_write_source(&mut env, write_ratio)?
+                    .map_async(move |e| {
+                        let db = db.clone();
+                        e.then(move |(id, write)| async move {
+                            let p = map_get_product_async(db, retry, id).await?;
+                            Ok(p.map(|p| (p, write)))
+                        })
+                    })
+                    .map(|e| e.transpose())
+                    .filter_map(retry::sink())
+                    .map(|e| e.transpose())
+                    .flatten();
+                enrich_async(s2, &pool, recommender, write_ratio, retry);
+            }
+
+            let start = Instant::now();
             env.execute().await;
-            Ok::<(), eyre::Error>(())
-        })?;
+            let elapsed = start.elapsed();
+            Ok::<_, eyre::Error>((elapsed, pool.write_retries()))
+        })
+}
 
-    Ok(())
+/// Process and recommend stages of the async pipeline, after the products are loaded
+fn enrich_async(
+    s2: Stream<impl Operator<Out = Timed<(Product, bool)>> + 'static>,
+    pool: &AnyBackend,
+    recommender: Recommender,
+    write_ratio: f64,
+    retry: RetryPolicy,
+) {
+    let s2 = s2
+        .rich_map(latency::recorder("get_product"))
+        .filter(|e| e.value.0.id % 101 < 57);
+
+    if write_ratio > 0.0 {
+        let mut s2 = s2.split(2);
+
+        // Process
+        let db = pool.clone();
+        s2.pop()
+            .unwrap()
+            .filter(|e| e.value.1)
+            .map_async(move |e| map_mark_hit_async(db.clone(), retry, e.value.0))
+            .filter_map(retry::sink())
+            .for_each(std::mem::drop);
+
+        // Recommend
+        let db = pool.clone();
+        s2.pop()
+            .unwrap()
+            .map_async(move |e| {
+                let db = db.clone();
+                e.then(move |(p, _)| map_get_recommendation_async(db, retry, p, recommender))
+            })
+            .map(|e| e.transpose())
+            .filter_map(retry::sink())
+            .rich_map(latency::recorder("recommend"))
+            .for_each(|e| inspect(e.value));
+    } else {
+        // Recommend
+        let db = pool.clone();
+        s2.map_async(move |e| {
+            let db = db.clone();
+            e.then(move |(p, _)| map_get_recommendation_async(db, retry, p, recommender))
+        })
+        .map(|e| e.transpose())
+        .filter_map(retry::sink())
+        .rich_map(latency::recorder("recommend"))
+        .for_each(|e| inspect(e.value));
+    }
 }
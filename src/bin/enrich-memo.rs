@@ -1,74 +1,144 @@
-use std::{ops::Rem, time::Instant};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 use eyre::{Context, Result};
-use noir_compute::{operator::Operator, prelude::*, Stream};
-use noir_plus_extra::enrich::{postgres as pg_async, types::Product};
-use rand::prelude::*;
-use rand_distr::Exp;
+use noir_compute::{group_by_hash, prelude::*, Replication};
+use noir_plus_extra::enrich::backend::{ConnectConfig, EnrichBackend, Recommender};
+use noir_plus_extra::enrich::cache::{self, CacheConfig, Cached};
+use noir_plus_extra::enrich::verify::{self, VerifyConfig};
+use noir_plus_extra::enrich::{pool, postgres as pg_async, types::Product};
+use noir_plus_extra::latency::{self, Timed};
+use noir_plus_extra::report::{ClusterInfo, ReportArgs, RunRecord};
+use noir_plus_extra::retry::{self, DeadLetter, RetryConfig, RetryPolicy};
+use noir_plus_extra::trace::{self, TraceArgs};
+use noir_plus_extra::workload::WorkloadConfig;
+use quick_cache::sync::Cache;
+use serde::Serialize;
 
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Parser, Serialize)]
 struct Options {
-    /// Number of generated events
-    #[clap(short('n'), long, default_value_t = 1_000_000)]
-    event_number: u64,
+    #[clap(flatten)]
+    workload: WorkloadConfig,
 
-    /// 1 / lambda parameter for exponential id distribution
-    #[clap(short('l'), long, default_value_t = 20_000)]
-    lambda_inv: usize,
+    #[clap(flatten)]
+    #[serde(skip)]
+    report: ReportArgs,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    trace: TraceArgs,
+
+    #[clap(flatten)]
+    retry: RetryConfig,
+
+    #[clap(flatten)]
+    connect: ConnectConfig,
+
+    #[clap(flatten)]
+    verify: VerifyConfig,
+
+    /// Read-through cache of the lookups, shared by the replicas of each host, instead of
+    /// the memoization
+    #[clap(flatten)]
+    cache: CacheConfig,
 
     /// Size of the memoization caches, disable memoization if None
     #[clap(short('m'), long)]
     memo: Option<usize>,
+
+    /// Route each key to a single replica before the memoized lookups, so that it is
+    /// cached once in the cluster instead of once per host
+    #[clap(long)]
+    partition: bool,
+
+    /// Recommendation strategy
+    #[clap(long, value_enum, default_value_t = Recommender::Category)]
+    recommender: Recommender,
 }
 
 fn main() -> Result<()> {
     color_eyre::install().ok();
     dotenvy::dotenv().ok();
-    tracing_subscriber::fmt::init();
-    let (conf, args) = EnvironmentConfig::from_args();
+    let (conf, args) = RuntimeConfig::from_args();
     conf.spawn_remote_workers();
-    let opt = Options::try_parse_from(args)?;
+    let mut opt = Options::try_parse_from(args)?;
+    opt.connect.for_host(&conf);
+    let _trace = trace::init(&opt.trace, conf.host_id())?;
     tracing::info!("config: {opt:?}");
 
-    let lambda = 1. / opt.lambda_inv as f32;
+    eyre::ensure!(
+        !opt.partition || opt.memo.is_some_and(|n| n > 0),
+        "partitioned caches require memoization (-m)"
+    );
+    eyre::ensure!(
+        !opt.cache.enabled() || opt.memo.unwrap_or(0) == 0,
+        "the cache replaces the memoization (-m)"
+    );
 
     // db::db_setup()?;
 
+    let retry = opt.retry.policy()?;
+    opt.verify.init(&opt.connect.dataset, opt.recommender)?;
+    let cluster = ClusterInfo::new(&conf);
     let start = Instant::now();
-    match opt.memo {
-        Some(0) | None => pipeline_async(conf, lambda, opt.event_number)?,
-        Some(n) => pipeline_async_memo(conf, lambda, opt.event_number, n)?,
-    }
+    let elapsed = match opt.memo {
+        _ if opt.cache.enabled() => pipeline_async_cached(
+            conf,
+            &opt.workload,
+            &opt.cache,
+            opt.recommender,
+            &opt.connect,
+            retry,
+        )?,
+        Some(0) | None => {
+            pipeline_async(conf, &opt.workload, opt.recommender, &opt.connect, retry)?
+        }
+        Some(n) if opt.partition => pipeline_async_partitioned(
+            conf,
+            &opt.workload,
+            n,
+            opt.recommender,
+            &opt.connect,
+            retry,
+        )?,
+        Some(n) => {
+            pipeline_async_memo(conf, &opt.workload, n, opt.recommender, &opt.connect, retry)?
+        }
+    };
     eprintln!("time: {:?}", start.elapsed());
+    eprintln!("latency: {}", latency::summary_json());
+    eprintln!("errors: {}", retry::summary_json());
+    eprintln!("pools: {}", pool::summary_json());
+    if opt.cache.enabled() {
+        eprintln!("cache: {}", cache::summary_json());
+    }
+    if opt.verify.verify {
+        eprintln!("verify: {}", verify::summary_json());
+    }
     micrometer::summary_grouped();
-    // micrometer::append_csv_uniform(
-    //     "target/mm.csv",
-    //     &format!("{}-c{}l{:?}", name, opt.event_number, lambda),
-    // )?;
 
-    Ok(())
-}
+    let events = Some(opt.workload.event_number);
+    RunRecord::new(env!("CARGO_BIN_NAME"), &opt, cluster, events, elapsed)?.write(&opt.report)?;
 
-fn make_source(
-    lambda: f32,
-    env: &mut StreamEnvironment,
-    events: u64,
-) -> eyre::Result<Stream<impl Operator<Out = i32>>> {
-    let distr = Exp::new(lambda)?;
-    let source = env
-        .stream_par_iter(move |i, n| {
-            let mut rng = SmallRng::seed_from_u64(i ^ 0xfeeddabeef);
-            (0..events / n).map(move |_| distr.sample(&mut rng).max(1.0).rem(1_000_000.) as i32)
-        })
-        .batch_mode(BatchMode::adaptive(8192, std::time::Duration::from_secs(1)));
-    Ok(source)
+    if let Some(v) = verify::summary() {
+        eyre::ensure!(
+            v.mismatched() == 0,
+            "{} of {} recommendations differ from the dataset",
+            v.mismatched(),
+            v.checked
+        );
+    }
+
+    Ok(())
 }
 
-fn inspect((p, rec): (Product, Vec<Product>)) {
+fn inspect(e: Timed<(Product, Vec<Product>)>) {
+    let (p, rec) = e.value;
+    verify::check(&p, &rec);
     if p.id % 5000 == 0 {
         println!(
             "{}: {}",
@@ -78,98 +148,339 @@ fn inspect((p, rec): (Product, Vec<Product>)) {
     }
 }
 
-async fn map_get_product_async(db: pg_async::Pool, id: i32) -> Option<Product> {
-    pg_async::get_product(&db, id)
-        .await
-        .context("get_product")
-        .unwrap()
+/// Key of the memoized recommendations: they only depend on the category for recommender 0
+fn recommend_key(p: &Product, r: Recommender) -> i32 {
+    match r {
+        Recommender::Category => p.category_id,
+        Recommender::Tags => p.id,
+    }
 }
 
-async fn map_get_recommendation_async(db: pg_async::Pool, p: Product) -> (Product, Vec<Product>) {
-    let rec = pg_async::recommend_0(&db, &p)
+async fn map_get_product_async(
+    db: pg_async::Pool,
+    retry: RetryPolicy,
+    id: i32,
+) -> Result<Option<Product>, DeadLetter<i32>> {
+    let db = &db;
+    retry
+        .run_async("get_product", &id, move || async move {
+            let mut conn = pg_async::acquire(db).await.context("acquire")?;
+            pg_async::get_product(&mut *conn, id)
+                .await
+                .context("get_product")
+        })
         .await
-        .context("recommend")
-        .unwrap();
-    (p, rec)
 }
 
-#[allow(unused)]
-async fn map_mark_hit_async(db: pg_async::Pool, p: Product) {
-    pg_async::mark_hit(&db, &p)
+async fn map_get_recommendation_async(
+    db: pg_async::Pool,
+    retry: RetryPolicy,
+    p: Product,
+    r: Recommender,
+) -> Result<(Product, Vec<Product>), DeadLetter<Product>> {
+    let (db, q) = (&db, &p);
+    let rec = retry
+        .run_async("recommend", &p, move || async move {
+            let mut conn = pg_async::acquire(db).await.context("acquire")?;
+            let rec = match r {
+                Recommender::Category => pg_async::recommend_0(&mut *conn, q).await,
+                Recommender::Tags => pg_async::recommend_1(&mut *conn, q).await,
+            };
+            rec.context("recommend")
+        })
+        .await?;
+    Ok((p, rec))
+}
+
+/// Products memoized by id, built with the stream and shared by the replicas of the process
+/// like the memo of `map_async_memo` it replaces, which would also cache the ingest time
+type ProductMemo = Arc<Cache<i32, Option<Product>>>;
+
+/// Product `id`, from the memo if it was already looked up. Only the successful lookups
+/// are cached, so the later events of a failed key are looked up again
+async fn memo_get_product_async(
+    db: pg_async::Pool,
+    retry: RetryPolicy,
+    memo: ProductMemo,
+    id: i32,
+) -> Result<Option<Product>, DeadLetter<i32>> {
+    if let Some(p) = memo.get(&id) {
+        return Ok(p);
+    }
+    let p = map_get_product_async(db, retry, id).await?;
+    memo.insert(id, p.clone());
+    Ok(p)
+}
+
+/// Recommendations memoized by [recommend_key]. Like the memo of `map_async_memo_by`, the
+/// cache is built with the stream and shared by the replicas of the process
+type RecommendMemo = Arc<Cache<i32, Vec<Product>>>;
+
+/// Recommendations of `p`, paired with the memoized ones of its key. Only the successful
+/// lookups are cached, so the products of a key failing once are looked up again
+async fn memo_get_recommendation_async(
+    db: pg_async::Pool,
+    retry: RetryPolicy,
+    memo: RecommendMemo,
+    p: Product,
+    r: Recommender,
+) -> Result<(Product, Vec<Product>), DeadLetter<Product>> {
+    let key = recommend_key(&p, r);
+    if let Some(rec) = memo.get(&key) {
+        return Ok((p, rec));
+    }
+    let (p, rec) = map_get_recommendation_async(db, retry, p, r).await?;
+    memo.insert(key, rec.clone());
+    Ok((p, rec))
+}
+
+async fn map_get_product_cached(
+    db: Cached<pg_async::Pool>,
+    retry: RetryPolicy,
+    id: i32,
+) -> Result<Option<Product>, DeadLetter<i32>> {
+    let db = &db;
+    retry
+        .run_async("get_product", &id, move || async move {
+            db.get_product(id).await
+        })
         .await
-        .context("mark_hit")
-        .unwrap();
 }
 
-fn pipeline_async(conf: EnvironmentConfig, lambda: f32, events: u64) -> Result<()> {
-    tokio::runtime::Builder::new_multi_thread()
+async fn map_get_recommendation_cached(
+    db: Cached<pg_async::Pool>,
+    retry: RetryPolicy,
+    p: Product,
+    r: Recommender,
+) -> Result<(Product, Vec<Product>), DeadLetter<Product>> {
+    let (db, q) = (&db, &p);
+    let rec = retry
+        .run_async(
+            "recommend",
+            &p,
+            move || async move { db.recommend(q, r).await },
+        )
+        .await?;
+    Ok((p, rec))
+}
+
+fn pipeline_async(
+    conf: RuntimeConfig,
+    workload: &WorkloadConfig,
+    recommender: Recommender,
+    connect: &ConnectConfig,
+    retry: RetryPolicy,
+) -> Result<Duration> {
+    let elapsed = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(async move {
-            let mut env = StreamEnvironment::new(conf);
-            let source = make_source(lambda, &mut env, events)?;
-            let pool = pg_async::db_init_pool().await?;
+            let mut env = StreamContext::new(conf);
+            let source = workload.timed_source(&mut env)?;
+            let pool = pg_async::db_init_pool(connect).await?;
 
             // Load
             let db = pool.clone();
             let s2 = source
-                .map_async(move |id| map_get_product_async(db.clone(), id))
+                .map_async(move |e| {
+                    let db = db.clone();
+                    e.then(move |id| map_get_product_async(db, retry, id))
+                })
+                .map(|e| e.transpose())
+                .filter_map(retry::sink())
+                .map(|e| e.transpose())
                 .flatten()
-                .filter(|p| p.id % 101 < 57);
+                .rich_map(latency::recorder("get_product"))
+                .filter(|e| e.value.id % 101 < 57);
 
             // Recommend
             let db = pool.clone();
             s2
                 // .pop()
                 // .unwrap()
-                .map_async(move |p| map_get_recommendation_async(db.clone(), p))
+                .map_async(move |e| {
+                    let db = db.clone();
+                    e.then(move |p| map_get_recommendation_async(db, retry, p, recommender))
+                })
+                .map(|e| e.transpose())
+                .filter_map(retry::sink())
+                .rich_map(latency::recorder("recommend"))
                 .for_each(inspect);
 
+            let start = Instant::now();
             env.execute().await;
-            Ok::<(), eyre::Error>(())
+            let elapsed = start.elapsed();
+            Ok::<_, eyre::Error>(elapsed)
         })?;
 
-    Ok(())
+    Ok(elapsed)
 }
 
 fn pipeline_async_memo(
-    conf: EnvironmentConfig,
-    lambda: f32,
-    events: u64,
+    conf: RuntimeConfig,
+    workload: &WorkloadConfig,
     memo: usize,
-) -> Result<()> {
-    tokio::runtime::Builder::new_multi_thread()
+    recommender: Recommender,
+    connect: &ConnectConfig,
+    retry: RetryPolicy,
+) -> Result<Duration> {
+    let elapsed = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(async move {
-            let mut env = StreamEnvironment::new(conf);
-            let source = make_source(lambda, &mut env, events)?;
-            let pool = pg_async::db_init_pool().await?;
+            let mut env = StreamContext::new(conf);
+            let source = workload.timed_source(&mut env)?;
+            let pool = pg_async::db_init_pool(connect).await?;
 
             // Load
             let db = pool.clone();
+            let cache = ProductMemo::new(Cache::new(memo));
             let s2 = source
-                .map_async_memo(move |id| map_get_product_async(db.clone(), id), memo)
+                .map_async(move |e| {
+                    let (db, cache) = (db.clone(), cache.clone());
+                    e.then(move |id| memo_get_product_async(db, retry, cache, id))
+                })
+                .map(|e| e.transpose())
+                .filter_map(retry::sink())
+                .map(|e| e.transpose())
                 .flatten()
-                .filter(|p| p.id % 101 < 57);
+                .rich_map(latency::recorder("get_product"))
+                .filter(|e| e.value.id % 101 < 57);
 
             // Recommend
             let db = pool.clone();
+            let cache = RecommendMemo::new(Cache::new(memo));
             s2
                 // .pop()
                 // .unwrap()
-                .map_async_memo_by(
-                    move |p| map_get_recommendation_async(db.clone(), p),
-                    |p| p.category_id,
-                    memo,
-                )
+                .map_async(move |e| {
+                    let (db, cache) = (db.clone(), cache.clone());
+                    e.then(move |p| memo_get_recommendation_async(db, retry, cache, p, recommender))
+                })
+                .map(|e| e.transpose())
+                .filter_map(retry::sink())
+                .rich_map(latency::recorder("recommend"))
                 .for_each(inspect);
 
+            let start = Instant::now();
             env.execute().await;
-            Ok::<(), eyre::Error>(())
+            let elapsed = start.elapsed();
+            Ok::<_, eyre::Error>(elapsed)
         })?;
 
-    Ok(())
+    Ok(elapsed)
+}
+
+/// Like [pipeline_async_memo], but each key is cached by the host of the only replica
+/// receiving it
+fn pipeline_async_partitioned(
+    conf: RuntimeConfig,
+    workload: &WorkloadConfig,
+    memo: usize,
+    recommender: Recommender,
+    connect: &ConnectConfig,
+    retry: RetryPolicy,
+) -> Result<Duration> {
+    let elapsed = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async move {
+            let mut env = StreamContext::new(conf);
+            let source = workload.timed_source(&mut env)?;
+            let pool = pg_async::db_init_pool(connect).await?;
+
+            // Load
+            let db = pool.clone();
+            let cache = ProductMemo::new(Cache::new(memo));
+            let s2 = source
+                .repartition_by(Replication::Unlimited, |e| group_by_hash(&e.value))
+                .map_async(move |e| {
+                    let (db, cache) = (db.clone(), cache.clone());
+                    e.then(move |id| memo_get_product_async(db, retry, cache, id))
+                })
+                .map(|e| e.transpose())
+                .filter_map(retry::sink())
+                .map(|e| e.transpose())
+                .flatten()
+                .rich_map(latency::recorder("get_product"))
+                .filter(|e| e.value.id % 101 < 57);
+
+            // Recommend
+            let db = pool.clone();
+            let cache = RecommendMemo::new(Cache::new(memo));
+            s2.repartition_by(Replication::Unlimited, move |e| {
+                group_by_hash(&recommend_key(&e.value, recommender))
+            })
+            .map_async(move |e| {
+                let (db, cache) = (db.clone(), cache.clone());
+                e.then(move |p| memo_get_recommendation_async(db, retry, cache, p, recommender))
+            })
+            .map(|e| e.transpose())
+            .filter_map(retry::sink())
+            .rich_map(latency::recorder("recommend"))
+            .for_each(inspect);
+
+            let start = Instant::now();
+            env.execute().await;
+            let elapsed = start.elapsed();
+            Ok::<_, eyre::Error>(elapsed)
+        })?;
+
+    Ok(elapsed)
+}
+
+/// Like [pipeline_async], but the lookups go through the read-through cache of the host
+fn pipeline_async_cached(
+    conf: RuntimeConfig,
+    workload: &WorkloadConfig,
+    cache: &CacheConfig,
+    recommender: Recommender,
+    connect: &ConnectConfig,
+    retry: RetryPolicy,
+) -> Result<Duration> {
+    let elapsed = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async move {
+            let mut env = StreamContext::new(conf);
+            let source = workload.timed_source(&mut env)?;
+            let pool = pg_async::db_init_pool(connect).await?;
+            let pool = Cached::new(pool, cache, &connect.dataset)?;
+
+            // Load
+            let db = pool.clone();
+            let s2 = source
+                .map_async(move |e| {
+                    let db = db.clone();
+                    e.then(move |id| map_get_product_cached(db, retry, id))
+                })
+                .map(|e| e.transpose())
+                .filter_map(retry::sink())
+                .map(|e| e.transpose())
+                .flatten()
+                .rich_map(latency::recorder("get_product"))
+                .filter(|e| e.value.id % 101 < 57);
+
+            // Recommend
+            let db = pool.clone();
+            s2.map_async(move |e| {
+                let db = db.clone();
+                e.then(move |p| map_get_recommendation_cached(db, retry, p, recommender))
+            })
+            .map(|e| e.transpose())
+            .filter_map(retry::sink())
+            .rich_map(latency::recorder("recommend"))
+            .for_each(inspect);
+
+            let start = Instant::now();
+            env.execute().await;
+            let elapsed = start.elapsed();
+            Ok::<_, eyre::Error>(elapsed)
+        })?;
+
+    Ok(elapsed)
 }
@@ -1,26 +1,50 @@
-use std::{ops::Rem, time::Instant};
+use std::time::{Duration, Instant};
 
-use backoff::{retry_notify, ExponentialBackoff};
 use clap::Parser;
 use eyre::{Context, Result};
-use noir_compute::{operator::Operator, prelude::*, Stream};
+use noir_compute::prelude::*;
+use noir_plus_extra::enrich::backend::{
+    AnyBackendBlocking, BackendKind, ConnectConfig, EnrichBackendBlocking, Recommender,
+};
+use noir_plus_extra::enrich::cache::{self, CacheConfig};
+use noir_plus_extra::enrich::pool;
+use noir_plus_extra::enrich::verify::{self, VerifyConfig};
 use noir_plus_extra::enrich::{postgres_blocking as db, types::Product};
+use noir_plus_extra::latency;
+use noir_plus_extra::report::{ClusterInfo, ReportArgs, RunRecord};
+use noir_plus_extra::retry::{self, DeadLetter, RetryConfig, RetryPolicy};
+use noir_plus_extra::trace::{self, TraceArgs};
+use noir_plus_extra::workload::WorkloadConfig;
 use r2d2_postgres::postgres::{self, NoTls};
-use rand::prelude::*;
-use rand_distr::Exp;
+use serde::Serialize;
 
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Parser, Serialize)]
 struct Options {
-    /// Number of generated events
-    #[clap(short('n'), long, default_value_t = 1_000_000)]
-    event_number: u64,
+    #[clap(flatten)]
+    workload: WorkloadConfig,
 
-    /// 1 / lambda parameter for exponential id distribution
-    #[clap(short('l'), long, default_value_t = 20_000)]
-    lambda_inv: usize,
+    #[clap(flatten)]
+    #[serde(skip)]
+    report: ReportArgs,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    trace: TraceArgs,
+
+    #[clap(flatten)]
+    retry: RetryConfig,
+
+    #[clap(flatten)]
+    cache: CacheConfig,
+
+    #[clap(flatten)]
+    connect: ConnectConfig,
+
+    #[clap(flatten)]
+    verify: VerifyConfig,
 
     /// Size of the memoization caches, disable memoization if None
     #[clap(short('m'), long)]
@@ -28,66 +52,171 @@ struct Options {
 
     #[clap(long, short)]
     shared: bool,
+
+    /// Store used for the lookups when running with a shared pool
+    #[clap(long, value_enum, default_value_t = BackendKind::PostgresBlocking)]
+    backend: BackendKind,
+
+    /// Recommendation strategy
+    #[clap(long, value_enum, default_value_t = Recommender::Category)]
+    recommender: Recommender,
+
+    /// Fraction of the loaded products that also mark a hit, 0 disables the write path
+    #[clap(short('w'), long, default_value_t = 0.0)]
+    write_ratio: f64,
 }
 
 fn main() -> Result<()> {
     color_eyre::install().ok();
     dotenvy::dotenv().ok();
-    tracing_subscriber::fmt::init();
-    let (conf, args) = EnvironmentConfig::from_args();
+    let (conf, args) = RuntimeConfig::from_args();
     conf.spawn_remote_workers();
-    let opt = Options::try_parse_from(args)?;
+    let mut opt = Options::try_parse_from(args)?;
+    opt.connect.for_host(&conf);
+    let _trace = trace::init(&opt.trace, conf.host_id())?;
     tracing::info!("config: {opt:?}");
 
-    let lambda = 1. / opt.lambda_inv as f32;
+    eyre::ensure!(
+        (0.0..=1.0).contains(&opt.write_ratio),
+        "write ratio must be in 0..=1"
+    );
+    eyre::ensure!(
+        !opt.verify.verify || opt.write_ratio == 0.0,
+        "verification requires a read-only run (-w 0)"
+    );
+    eyre::ensure!(
+        !opt.cache.enabled() || opt.shared,
+        "the cache requires a shared pool (-s)"
+    );
 
     // db::db_setup()?;
 
+    let retry = opt.retry.policy()?;
+    opt.verify.init(&opt.connect.dataset, opt.recommender)?;
+    let cluster = ClusterInfo::new(&conf);
     let start = Instant::now();
-    match opt.shared {
-        true => pipeline_pool(conf, lambda, opt.event_number)?,
-        false => pipeline_nopool(conf, lambda, opt.event_number)?,
-    }
+    let (elapsed, write_retries) = match opt.shared {
+        true => {
+            let pool = AnyBackendBlocking::connect(opt.backend, &opt.connect)?
+                .with_cache(&opt.cache, &opt.connect.dataset)?;
+            pipeline_pool(
+                conf,
+                &opt.workload,
+                pool,
+                opt.recommender,
+                opt.write_ratio,
+                retry,
+            )?
+        }
+        false => pipeline_nopool(conf, &opt.workload, opt.recommender, opt.write_ratio, retry)?,
+    };
     eprintln!("time: {:?}", start.elapsed());
+    eprintln!("latency: {}", latency::summary_json());
+    eprintln!("errors: {}", retry::summary_json());
+    let write_retries = (opt.write_ratio > 0.0).then_some(write_retries);
+    if let Some(n) = write_retries {
+        eprintln!("write retries: {n}");
+    }
+    eprintln!("pools: {}", pool::summary_json());
+    if opt.cache.enabled() {
+        eprintln!("cache: {}", cache::summary_json());
+    }
+    if opt.verify.verify {
+        eprintln!("verify: {}", verify::summary_json());
+    }
     micrometer::summary_grouped();
-    // micrometer::append_csv_uniform(
-    //     "target/mm.csv",
-    //     &format!("{}-c{}l{:?}", name, opt.event_number, lambda),
-    // )?;
+
+    let events = Some(opt.workload.event_number);
+    let mut record = RunRecord::new(env!("CARGO_BIN_NAME"), &opt, cluster, events, elapsed)?;
+    record.write_retries = write_retries;
+    record.write(&opt.report)?;
+
+    if let Some(v) = verify::summary() {
+        eyre::ensure!(
+            v.mismatched() == 0,
+            "{} of {} recommendations differ from the dataset",
+            v.mismatched(),
+            v.checked
+        );
+    }
 
     Ok(())
 }
 
-fn make_source(
-    lambda: f32,
-    env: &mut StreamEnvironment,
-    events: u64,
-) -> eyre::Result<Stream<impl Operator<Out = i32>>> {
-    let distr = Exp::new(lambda)?;
-    let source = env
-        .stream_par_iter(move |i, n| {
-            let mut rng = SmallRng::seed_from_u64(i ^ 0xfeeddabeef);
-            (0..events / n).map(move |_| distr.sample(&mut rng).max(1.0).rem(1_000_000.) as i32)
-        })
-        .batch_mode(BatchMode::adaptive(8192, std::time::Duration::from_secs(1)));
-    Ok(source)
+/// Opens a new connection for each attempt, retried along with the query, there is no
+/// prepared statement to reuse
+fn connect(db_url: &str) -> Result<db::Connection> {
+    let client = postgres::Client::connect(db_url, NoTls).context("connect")?;
+    Ok(db::Connection::new(client, false))
 }
 
-fn map_get_product(db: &mut postgres::Client, id: i32) -> Option<Product> {
-    db::get_product(db, id).context("get_product").unwrap()
+fn map_get_product(
+    db_url: &str,
+    retry: &RetryPolicy,
+    id: i32,
+) -> Result<Option<Product>, DeadLetter<i32>> {
+    retry.run("get_product", &id, || {
+        let mut conn = connect(db_url)?;
+        db::get_product(&mut conn, id).context("get_product")
+    })
 }
 
-fn map_get_recommendation(db: &mut postgres::Client, p: Product) -> (Product, Vec<Product>) {
-    let rec = db::recommend_0(db, &p).context("recommend").unwrap();
-    (p, rec)
+fn map_get_recommendation(
+    db_url: &str,
+    retry: &RetryPolicy,
+    p: Product,
+    r: Recommender,
+) -> Result<(Product, Vec<Product>), DeadLetter<Product>> {
+    let rec = retry.run("recommend", &p, || {
+        let mut conn = connect(db_url)?;
+        let rec = match r {
+            Recommender::Category => db::recommend_0(&mut conn, &p),
+            Recommender::Tags => db::recommend_1(&mut conn, &p),
+        };
+        rec.context("recommend")
+    })?;
+    Ok((p, rec))
 }
 
-#[allow(unused)]
-fn map_mark_hit(db: &mut postgres::Client, p: Product) {
-    db::mark_hit(db, &p).context("mark_hit").unwrap();
+fn map_mark_hit(db_url: &str, retry: &RetryPolicy, p: Product) -> Result<(), DeadLetter<Product>> {
+    retry.run("mark_hit", &p, || {
+        let mut conn = connect(db_url)?;
+        db::mark_hit(&mut conn, &p).context("mark_hit")
+    })
+}
+
+fn map_get_product_backend(
+    db: &impl EnrichBackendBlocking,
+    retry: &RetryPolicy,
+    id: i32,
+) -> Result<Option<Product>, DeadLetter<i32>> {
+    retry.run("get_product", &id, || {
+        db.get_product(id).context("get_product")
+    })
+}
+
+fn map_get_recommendation_backend(
+    db: &impl EnrichBackendBlocking,
+    retry: &RetryPolicy,
+    p: Product,
+    r: Recommender,
+) -> Result<(Product, Vec<Product>), DeadLetter<Product>> {
+    let _span = micrometer::span!("recommend");
+    let rec = retry.run("recommend", &p, || db.recommend(&p, r).context("recommend"))?;
+    Ok((p, rec))
+}
+
+fn map_mark_hit_backend(
+    db: &impl EnrichBackendBlocking,
+    retry: &RetryPolicy,
+    p: Product,
+) -> Result<(), DeadLetter<Product>> {
+    let _span = micrometer::span!("mark_hit");
+    retry.run("mark_hit", &p, || db.mark_hit(&p).context("mark_hit"))
 }
 
 fn inspect((p, rec): (Product, Vec<Product>)) {
+    verify::check(&p, &rec);
     if p.id % 5000 == 0 {
         println!(
             "{}: {}",
@@ -97,86 +226,140 @@ fn inspect((p, rec): (Product, Vec<Product>)) {
     }
 }
 
-fn pipeline_nopool(conf: EnvironmentConfig, lambda: f32, events: u64) -> Result<()> {
-    let mut env = StreamEnvironment::new(conf);
-    let source = make_source(lambda, &mut env, events)?;
+/// Pipeline opening a connection for each lookup. Returns the execution time and the
+/// `mark_hit` attempts retried because of a write conflict, which Postgres does not retry
+fn pipeline_nopool(
+    conf: RuntimeConfig,
+    workload: &WorkloadConfig,
+    recommender: Recommender,
+    write_ratio: f64,
+    retry: RetryPolicy,
+) -> Result<(Duration, u64)> {
+    let mut env = StreamContext::new(conf);
+    let source = workload.timed_write_source(&mut env, write_ratio)?;
     let url = std::env::var("DATABASE_URL").context("Missing DATABASE_URL")?;
     // let url = Arc::new(url);
 
-    fn connect(db_url: &str) -> postgres::Client {
-        let op = || {
-            postgres::Client::connect(&db_url, NoTls).map_err(|e| {
-                let inner = e.into_source().unwrap();
-                if inner.is::<std::io::Error>() {
-                    backoff::Error::transient(inner)
-                } else {
-                    backoff::Error::permanent(inner)
-                }
-            })
-        };
-        let backoff = ExponentialBackoff::default();
-        retry_notify(backoff, op, |e, d| tracing::error!("[{d:8?}] {e}")).unwrap()
-    }
-
-    // Load
+    // Load, keeping the write flag of every event
     let db_url = url.clone();
     let s2 = source
-        .map(move |id| {
-            let mut conn = connect(&db_url);
-            map_get_product(&mut conn, id)
+        .map(move |e| {
+            e.map(|(id, write)| map_get_product(&db_url, &retry, id).map(|p| p.map(|p| (p, write))))
+                .transpose()
         })
+        .filter_map(retry::sink())
+        .map(|e| e.transpose())
         .flatten()
-        .filter(|p| p.id % 101 < 57);
+        .rich_map(latency::recorder("get_product"))
+        .filter(|e| e.value.0.id % 101 < 57);
 
-    // Recommend
-    let db_url = url.clone();
-    s2.map(move |p| {
-        let mut conn = connect(&db_url);
-        map_get_recommendation(&mut conn, p)
-    })
-    .for_each(inspect);
+    if write_ratio > 0.0 {
+        let mut s2 = s2.split(2);
 
+        // Process
+        let db_url = url.clone();
+        s2.pop()
+            .unwrap()
+            .filter(|e| e.value.1)
+            .map(move |e| map_mark_hit(&db_url, &retry, e.value.0))
+            .filter_map(retry::sink())
+            .for_each(std::mem::drop);
+
+        // Recommend
+        let db_url = url.clone();
+        s2.pop()
+            .unwrap()
+            .map(move |e| {
+                e.map(|(p, _)| map_get_recommendation(&db_url, &retry, p, recommender))
+                    .transpose()
+            })
+            .filter_map(retry::sink())
+            .rich_map(latency::recorder("recommend"))
+            .for_each(|e| inspect(e.value));
+    } else {
+        // Recommend
+        let db_url = url.clone();
+        s2.map(move |e| {
+            e.map(|(p, _)| map_get_recommendation(&db_url, &retry, p, recommender))
+                .transpose()
+        })
+        .filter_map(retry::sink())
+        .rich_map(latency::recorder("recommend"))
+        .for_each(|e| inspect(e.value));
+    }
+
+    let start = Instant::now();
     env.execute_blocking();
+    let elapsed = start.elapsed();
 
-    Ok(())
+    Ok((elapsed, 0))
 }
 
-fn pipeline_pool(conf: EnvironmentConfig, lambda: f32, events: u64) -> Result<()> {
-    let mut env = StreamEnvironment::new(conf);
-    let source = make_source(lambda, &mut env, events)?;
-    let pool = db::db_init_pool()?;
+/// Pipeline sharing the pool of `pool`, returns like [pipeline_nopool]
+fn pipeline_pool(
+    conf: RuntimeConfig,
+    workload: &WorkloadConfig,
+    pool: AnyBackendBlocking,
+    recommender: Recommender,
+    write_ratio: f64,
+    retry: RetryPolicy,
+) -> Result<(Duration, u64)> {
+    let mut env = StreamContext::new(conf);
+    let source = workload.timed_write_source(&mut env, write_ratio)?;
 
-    // Load
+    // Load, keeping the write flag of every event
     let db = pool.clone();
     let s2 = source
-        .map(move |id| {
-            let mut db = db.get().unwrap();
-            map_get_product(&mut db, id)
+        .map(move |e| {
+            e.map(|(id, write)| {
+                map_get_product_backend(&db, &retry, id).map(|p| p.map(|p| (p, write)))
+            })
+            .transpose()
         })
+        .filter_map(retry::sink())
+        .map(|e| e.transpose())
         .flatten()
-        .filter(|p| p.id % 101 < 57);
+        .rich_map(latency::recorder("get_product"))
+        .filter(|e| e.value.0.id % 101 < 57);
 
-    // Recommend
-    let db = pool.clone();
-    s2.map(move |p| {
-        let mut db = db.get().unwrap();
-        map_get_recommendation(&mut db, p)
-    })
-    .for_each(inspect);
+    if write_ratio > 0.0 {
+        let mut s2 = s2.split(2);
+
+        // Process: hot products are hit concurrently from every replica
+        let db = pool.clone();
+        s2.pop()
+            .unwrap()
+            .filter(|e| e.value.1)
+            .map(move |e| map_mark_hit_backend(&db, &retry, e.value.0))
+            .filter_map(retry::sink())
+            .for_each(std::mem::drop);
+
+        // Recommend
+        let db = pool.clone();
+        s2.pop()
+            .unwrap()
+            .map(move |e| {
+                e.map(|(p, _)| map_get_recommendation_backend(&db, &retry, p, recommender))
+                    .transpose()
+            })
+            .filter_map(retry::sink())
+            .rich_map(latency::recorder("recommend"))
+            .for_each(|e| inspect(e.value));
+    } else {
+        // Recommend
+        let db = pool.clone();
+        s2.map(move |e| {
+            e.map(|(p, _)| map_get_recommendation_backend(&db, &retry, p, recommender))
+                .transpose()
+        })
+        .filter_map(retry::sink())
+        .rich_map(latency::recorder("recommend"))
+        .for_each(|e| inspect(e.value));
+    }
 
+    let start = Instant::now();
     env.execute_blocking();
+    let elapsed = start.elapsed();
 
-    Ok(())
+    Ok((elapsed, pool.write_retries()))
 }
-
-// Process
-// let db = pool.clone();
-// s2.pop()
-//     .unwrap()
-//     .filter(|p| p.id % 101 < 3)
-//     .group_by(|p| p.id ^ (p.id >> 3) % 256)
-//     .window(CountWindow::sliding(2, 1))
-//     .max_by_key(|k| k.id % 53)
-//     .drop_key()
-//     .map(move |p| map_mark_hit(db.clone(), p))
-//     .for_each(std::mem::drop);
@@ -0,0 +1,22 @@
+use clap::Parser;
+use noir_plus_extra::enrich::backend::ConnectConfig;
+use noir_plus_extra::enrich::dataset::Dataset;
+
+#[derive(Debug, Parser)]
+struct Options {
+    #[clap(flatten)]
+    connect: ConnectConfig,
+}
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    dotenvy::dotenv().ok();
+    tracing_subscriber::fmt::init();
+    color_eyre::install().ok();
+    let opt = Options::parse();
+    let dataset = Dataset::new(opt.connect.dataset.clone())?;
+
+    noir_plus_extra::enrich::redis::db_setup(&dataset, &opt.connect).await?;
+
+    Ok(())
+}
@@ -1,10 +1,41 @@
+use clap::Parser;
+use noir_plus_extra::enrich::backend::{BackendKind, ConnectConfig};
+use noir_plus_extra::enrich::dataset::Dataset;
+
+#[derive(Debug, Parser)]
+struct Options {
+    /// Store the dataset is loaded into
+    #[clap(long, value_enum, default_value_t = BackendKind::Postgres)]
+    backend: BackendKind,
+
+    #[clap(flatten)]
+    connect: ConnectConfig,
+}
+
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     dotenvy::dotenv().ok();
     tracing_subscriber::fmt::init();
     color_eyre::install().ok();
+    let opt = Options::parse();
+    tracing::info!("config: {opt:?}");
+
+    let dataset = Dataset::new(opt.connect.dataset.clone())?;
 
-    noir_plus_extra::enrich::postgres::db_setup().await?;
+    match opt.backend {
+        BackendKind::Postgres | BackendKind::PostgresBlocking => {
+            noir_plus_extra::enrich::postgres::db_setup(&dataset, &opt.connect).await?
+        }
+        #[cfg(feature = "redis")]
+        BackendKind::Redis => {
+            noir_plus_extra::enrich::redis::db_setup(&dataset, &opt.connect).await?
+        }
+        #[cfg(feature = "scylla")]
+        BackendKind::Scylla => {
+            noir_plus_extra::enrich::scylladb::db_setup(&dataset, &opt.connect).await?
+        }
+        BackendKind::Memory => eyre::bail!("the memory backend generates its dataset in-process"),
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,42 +1,195 @@
 use ahash::AHashMap as HashMap;
 use clap::Parser;
-use std::fs::File;
-use std::mem::replace;
-use std::{io::BufReader, sync::Arc};
-use std::time::Instant;
+use std::mem::take;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use noir_compute::prelude::*;
+use noir_plus_extra::graph;
+use noir_plus_extra::graph::input::{Graph, GraphArgs};
+use noir_plus_extra::graph::pagerank::{PageRank, RankArgs};
+use noir_plus_extra::output::OutputArgs;
+use noir_plus_extra::report::{ClusterInfo, ReportArgs, RunRecord};
+use serde::{Deserialize, Serialize};
 
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-const EPS: f64 = 1e-8;
-const DAMPENING: f64 = 0.85;
+/// `(page, rank)` of every ranked page
+type Ranks = Vec<(u64, f64)>;
 
-#[derive(clap::Parser)]
+#[derive(Clone, clap::Parser, Serialize)]
 struct Options {
+    /// Most rank updates, the ranks usually converge earlier
     #[clap(short, long)]
     iterations: usize,
-    #[clap(short, long)]
-    nodes_path: String,
-    #[clap(short, long)]
-    edges_path: String,
-    #[clap(short('N'), long)]
-    nodes: usize,
+    #[clap(flatten)]
+    graph: GraphArgs,
 
     #[clap(long, short)]
     shared: bool,
+
+    #[clap(flatten)]
+    rank: RankArgs,
+
+    /// Compare the ranks to a single-threaded reference computed from the same graph,
+    /// failing if any differs by more than `EPS`
+    #[clap(long, conflicts_with = "output")]
+    validate: bool,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    report: ReportArgs,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    output: OutputArgs,
+}
+
+/// Rank of a page, flowing through the iterations
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Page {
+    id: u64,
+    rank: f64,
+    /// Rank before the last update
+    old: f64,
+    /// Whether the page has no out-links, its rank is then spread like the teleports
+    dangling: bool,
+}
+
+impl Page {
+    fn new(id: u64, rank: f64) -> Self {
+        Self {
+            id,
+            rank,
+            old: rank,
+            dangling: false,
+        }
+    }
+
+    /// The page keeps its own rank for the update, and sends a share of it along each out-link
+    fn inflows(self, adj: Option<&[u64]>) -> Vec<(u64, Inflow)> {
+        let adj = adj.unwrap_or_default();
+        let own = Inflow {
+            rank: self.rank,
+            shares: 0.0,
+            dangling: adj.is_empty(),
+        };
+        let shares = adj.iter().map(|&y| {
+            let share = Inflow {
+                rank: 0.0,
+                shares: self.rank / adj.len() as f64,
+                dangling: false,
+            };
+            (y, share)
+        });
+        std::iter::once((self.id, own)).chain(shares).collect()
+    }
+}
+
+/// What a page gathers in an iteration
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct Inflow {
+    /// Rank of the page itself
+    rank: f64,
+    /// Rank sent by its in-links
+    shares: f64,
+    dangling: bool,
+}
+
+impl Inflow {
+    fn merge(&mut self, other: Inflow) {
+        self.rank += other.rank;
+        self.shares += other.shares;
+        self.dangling |= other.dangling;
+    }
+
+    /// Updated rank of page `x`, the first pass keeps the initial ranks while it measures
+    /// their dangling mass
+    fn update(&self, x: u64, model: &PageRank, state: &RankState) -> Page {
+        let rank = match state.dangling {
+            Some(dangling) => model.rank(x, self.shares, dangling),
+            None => self.rank,
+        };
+        Page {
+            id: x,
+            rank,
+            old: self.rank,
+            dangling: self.dangling,
+        }
+    }
+}
+
+/// Dangling mass and convergence of the ranks, shared by the replicas
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct RankState {
+    /// Rank of the dangling pages after the last update, none before the first pass
+    dangling: Option<f64>,
+    /// Rank updates done
+    iterations: usize,
+    /// L1 distance moved by the last update
+    delta: f64,
+    /// Sums of the running iteration
+    sums: RankDelta,
+}
+
+/// Sums of an iteration over the pages of a replica
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct RankDelta {
+    dangling: f64,
+    l1: f64,
+}
+
+impl RankDelta {
+    fn add(&mut self, p: Page) {
+        self.l1 += (p.rank - p.old).abs();
+        if p.dangling {
+            self.dangling += p.rank;
+        }
+    }
+}
+
+impl RankState {
+    fn merge(&mut self, delta: RankDelta) {
+        self.sums.dangling += delta.dangling;
+        self.sums.l1 += delta.l1;
+    }
+
+    /// Close an iteration, returning whether another is needed
+    fn next(&mut self, model: &PageRank) -> bool {
+        let sums = take(&mut self.sums);
+        let first_pass = self.dangling.is_none();
+        self.dangling = Some(sums.dangling);
+        if first_pass {
+            return true;
+        }
+        self.iterations += 1;
+        self.delta = sums.l1;
+        !model.converged(sums.l1)
+    }
+}
+
+/// Result of a job, the ranks and the final state are collected on a single host
+struct Outcome {
+    elapsed: Duration,
+    ranks: Option<Ranks>,
+    state: Option<RankState>,
 }
 
-fn pagerank(config: EnvironmentConfig, opts: Options) -> eyre::Result<()> {
-    let mut env = StreamEnvironment::new(config);
+fn pagerank(
+    config: RuntimeConfig,
+    opts: Options,
+    graph: Graph,
+    model: PageRank,
+) -> eyre::Result<Outcome> {
+    let host_id = config.host_id();
+    let mut env = StreamContext::new(config);
 
-    let num_pages = opts.nodes;
-    let pages_source = CsvSource::<u64>::new(opts.nodes_path).has_headers(false);
-    let links_source = CsvSource::<(u64, u64)>::new(opts.edges_path).has_headers(false);
+    let initial = model.initial();
+    let pages = graph.node_source(&mut env);
 
-    let adj_list = env
-        .stream(links_source)
+    let adj_list = graph
+        .edge_source(&mut env)
         // construct adjacency list
         .group_by_fold(
             |(x, _y)| *x,
@@ -46,141 +199,186 @@ fn pagerank(config: EnvironmentConfig, opts: Options) -> eyre::Result<()> {
         )
         .unkey();
 
-    let (dropme, result) = env
-        .stream(pages_source)
+    let loop_model = model.clone();
+    let (state, result) = pages
         // distribute the ranks evenly
-        .map(move |x| (x, 0.0, 1.0 / num_pages as f64))
+        .map(move |x| Page::new(x, initial))
         .iterate(
-            opts.iterations,
-            // state maintains whether a new iteration is needed
-            false,
-            move |s, _| {
-                s
-                    .map(|(x, _, rank)| (x, rank))
-                    .join(adj_list, |(x, _rank)| *x, |(x, _adj)| *x)
-                    .flat_map(|(_, ((_x, rank), (_, adj)))| {
-                        // distribute the rank of the page between the connected pages
-                        let rank_to_distribute = rank / adj.len() as f64;
-                        adj.into_iter().map(move |y| (y, rank_to_distribute))
-                    })
+            // the first pass only measures the dangling mass
+            opts.iterations + 1,
+            RankState::default(),
+            move |s, state| {
+                // pages without out-links are kept by the left join
+                s.left_join(adj_list, |p| p.id, |(x, _adj)| *x)
+                    .flat_map(|(_, (p, adj))| p.inflows(adj.as_ref().map(|(_, a)| a.as_slice())))
                     .drop_key()
-                    .group_by_sum(|(y, _)| *y, |(_y, rank_to_distribute)| rank_to_distribute)
-                    // apply dampening factor
-                    .rich_map({
-                        let mut prev = 0.0;
-                        move |(x, rank)| {
-                            let rank = rank * DAMPENING + (1.0 - DAMPENING) / num_pages as f64;
-                            (*x, replace(&mut prev, rank), rank)
-                        }
-                    })
+                    .group_by_fold(
+                        |(x, _)| *x,
+                        Inflow::default(),
+                        |acc, (_, inflow)| acc.merge(inflow),
+                        |acc, inflow| acc.merge(inflow),
+                    )
+                    .map(move |(&x, inflow)| inflow.update(x, &model, state.get()))
                     .drop_key()
             },
-            // a new iteration is needed if at least one page's rank has changed
-            |changed: &mut bool, (_x, old, new)| {
-                *changed = *changed || (new - old).abs() / new > EPS
-            },
-            |state, changed| *state = *state || changed,
-            |state| {
-                let condition = *state;
-                *state = false;
-                condition
-            },
+            RankDelta::add,
+            RankState::merge,
+            move |state| state.next(&loop_model),
         );
-    let result = result.collect_vec();
-    dropme.for_each(|_| {});
+    let ranks = result.map(|p| (p.id, p.rank));
+    let result = match opts.output.sink(host_id, &["node", "rank"])? {
+        Some(sink) => {
+            ranks.for_each(sink);
+            None
+        }
+        None => Some(ranks.collect_vec()),
+    };
+    let state = state.collect_vec();
 
     let start = Instant::now();
     env.execute_blocking();
     let elapsed = start.elapsed();
 
-    std::hint::black_box(result);
-
     eprintln!("{elapsed:?}");
-    Ok(())
+    Ok(Outcome {
+        elapsed,
+        ranks: result.and_then(|r| r.get()),
+        state: state.get().and_then(|mut s| s.pop()),
+    })
 }
 
-fn pagerank_shared(config: EnvironmentConfig, opts: Options) -> eyre::Result<()> {
-    let mut env = StreamEnvironment::new(config);
-
-    let num_pages = opts.nodes;
-    let pages_source = CsvSource::<u64>::new(opts.nodes_path).has_headers(false);
-    // let links_source = CsvSource::<(u64, u64)>::new(opts.edges_path).has_headers(false);
-
-    let links = BufReader::new(File::open(opts.edges_path).unwrap());
-    let adjacency_list: HashMap<u64, Vec<u64>> =
-        csv::ReaderBuilder::default()
-            .from_reader(links)
-            .into_deserialize()
-            .map(|r| r.unwrap())
-            .fold(
-                HashMap::with_capacity_and_hasher(num_pages, Default::default()),
-                |mut acc, (x, y)| {
-                    acc.entry(x).or_default().push(y);
-                    acc
-                },
-            );
+fn pagerank_shared(
+    config: RuntimeConfig,
+    opts: Options,
+    graph: Graph,
+    model: PageRank,
+) -> eyre::Result<Outcome> {
+    let host_id = config.host_id();
+    let mut env = StreamContext::new(config);
+
+    let num_pages = graph.num_nodes()?;
+    let initial = model.initial();
+    let pages = graph.node_source(&mut env);
+
+    // every host reads all the links
+    let adjacency_list: HashMap<u64, Vec<u64>> = graph.edges(0, 1).fold(
+        HashMap::with_capacity_and_hasher(num_pages, Default::default()),
+        |mut acc, (x, y)| {
+            acc.entry(x).or_default().push(y);
+            acc
+        },
+    );
 
     let adjacency_list = Arc::new(adjacency_list);
 
-    let (dropme, result) = env
-        .stream(pages_source)
+    let loop_model = model.clone();
+    let (state, result) = pages
         // distribute the ranks evenly
-        .map(move |x| (x, 0.0, 1.0 / num_pages as f64))
+        .map(move |x| Page::new(x, initial))
         .iterate(
-            opts.iterations,
-            // state maintains whether a new iteration is needed
-            false,
-            move |s, _| {
-                s.flat_map(move |(x, _, rank)| {
-                    if let Some(adj) = adjacency_list.get(&x) {
-                        // distribute the rank of the page between the connected pages
-                        let distribute = rank / adj.len() as f64;
-                        adj.iter().map(move |y| (*y, distribute)).collect()
-                    } else {
-                        vec![]
-                    }
-                })
-                .group_by_sum(|(x, _)| *x, |(_, rank)| rank)
-                .rich_map({
-                    let mut prev = 0.0;
-                    move |(x, rank)| {
-                        let rank = rank * DAMPENING + (1.0 - DAMPENING) / num_pages as f64;
-                        (*x, replace(&mut prev, rank), rank)
-                    }
-                })
-                .drop_key()
-            },
-            // a new iteration is needed if at least one page's rank has changed
-            |changed: &mut bool, (_x, old, new)| {
-                *changed = *changed || (new - old).abs() / new > EPS
+            // the first pass only measures the dangling mass
+            opts.iterations + 1,
+            RankState::default(),
+            move |s, state| {
+                s.flat_map(move |p| p.inflows(adjacency_list.get(&p.id).map(Vec::as_slice)))
+                    .group_by_fold(
+                        |(x, _)| *x,
+                        Inflow::default(),
+                        |acc, (_, inflow)| acc.merge(inflow),
+                        |acc, inflow| acc.merge(inflow),
+                    )
+                    .map(move |(&x, inflow)| inflow.update(x, &model, state.get()))
+                    .drop_key()
             },
-            |state, changed| *state = *state || changed,
-            |state| replace(state, false),
+            RankDelta::add,
+            RankState::merge,
+            move |state| state.next(&loop_model),
         );
-    let result = result.collect_vec();
-    dropme.for_each(|_| {});
+    let ranks = result.map(|p| (p.id, p.rank));
+    let result = match opts.output.sink(host_id, &["node", "rank"])? {
+        Some(sink) => {
+            ranks.for_each(sink);
+            None
+        }
+        None => Some(ranks.collect_vec()),
+    };
+    let state = state.collect_vec();
 
     let start = Instant::now();
     env.execute_blocking();
     let elapsed = start.elapsed();
 
-    std::hint::black_box(result);
-
     eprintln!("{elapsed:?}");
-    Ok(())
+    Ok(Outcome {
+        elapsed,
+        ranks: result.and_then(|r| r.get()),
+        state: state.get().and_then(|mut s| s.pop()),
+    })
 }
 
 fn main() -> eyre::Result<()> {
     color_eyre::install().ok();
-    let (config, args) = EnvironmentConfig::from_args();
+    let (config, args) = RuntimeConfig::from_args();
     let opts = Options::parse_from(args);
 
     config.spawn_remote_workers();
 
-    match opts.shared {
-        true => pagerank_shared(config, opts),
-        false => pagerank(config, opts),
+    let graph = opts.graph.graph()?;
+    let model = opts.rank.model(graph.num_nodes()?)?;
+    let cluster = ClusterInfo::new(&config);
+    let outcome = match opts.shared {
+        true => pagerank_shared(config, opts.clone(), graph.clone(), model.clone()),
+        false => pagerank(config, opts.clone(), graph.clone(), model.clone()),
     }?;
+    if let Some(state) = &outcome.state {
+        eprintln!(
+            "iterations: {} (L1 delta {:e})",
+            state.iterations, state.delta
+        );
+    }
 
+    let validation = match (opts.validate, outcome.ranks) {
+        (true, Some(ranks)) => Some(validate(&opts, &graph, &model, &ranks)?),
+        (_, ranks) => {
+            std::hint::black_box(ranks);
+            None
+        }
+    };
+
+    let mut record = RunRecord::new(
+        env!("CARGO_BIN_NAME"),
+        &opts,
+        cluster,
+        None,
+        outcome.elapsed,
+    )?;
+    record.iterations = outcome.state.map(|s| s.iterations);
+    record.write(&opts.report)?;
+
+    if let Some(v) = validation {
+        eyre::ensure!(
+            v.passed(),
+            "{} missing, {} extra and {} mismatched ranks out of {}",
+            v.missing,
+            v.extra,
+            v.mismatched,
+            v.pages
+        );
+    }
     Ok(())
 }
+
+/// Compare the ranks to the reference computed from the input graph
+fn validate(
+    opts: &Options,
+    graph: &Graph,
+    model: &PageRank,
+    ranks: &Ranks,
+) -> eyre::Result<graph::pagerank::Validation> {
+    let nodes = graph.read_nodes()?;
+    let edges = graph.read_edges()?;
+    let reference = graph::pagerank::reference(model, &nodes, &edges, opts.iterations);
+    let v = graph::pagerank::validate(ranks, &reference);
+    eprintln!("validate: {}", serde_json::to_string(&v)?);
+    Ok(v)
+}
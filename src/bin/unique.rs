@@ -1,5 +1,8 @@
 use clap::Parser;
-use std::{collections::HashSet, ops::Rem, time::Instant};
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
 
 use noir_compute::{group_by_hash, prelude::*, GroupHasherBuilder, Replication};
 
@@ -7,60 +10,48 @@ use noir_compute::{group_by_hash, prelude::*, GroupHasherBuilder, Replication};
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 use eyre::Result;
-use noir_compute::{operator::Operator, Stream};
-use rand::prelude::*;
-use rand_distr::Exp;
+use noir_plus_extra::report::{ClusterInfo, ReportArgs, RunRecord};
+use noir_plus_extra::workload::WorkloadConfig;
+use serde::Serialize;
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Parser, Serialize)]
 struct Options {
-    /// Number of generated events
-    #[clap(short('n'), long, default_value_t = 1_000_000)]
-    event_number: u64,
+    #[clap(flatten)]
+    workload: WorkloadConfig,
 
-    /// 1 / lambda parameter for exponential id distribution
-    #[clap(short('l'), long, default_value_t = 20_000)]
-    lambda_inv: usize,
+    #[clap(flatten)]
+    #[serde(skip)]
+    report: ReportArgs,
 
     #[clap(long, short)]
     version: String,
 }
 
-fn make_source(
-    lambda: f32,
-    env: &mut StreamEnvironment,
-    events: u64,
-) -> eyre::Result<Stream<impl Operator<Out = i32>>> {
-    let distr = Exp::new(lambda)?;
-    let source = env
-        .stream_par_iter(move |i, n| {
-            let mut rng = SmallRng::seed_from_u64(i ^ 0xfeeddabeef);
-            (0..events / n).map(move |_| distr.sample(&mut rng).max(1.0).rem(1_000_000.) as i32)
-        })
-        .batch_mode(BatchMode::adaptive(8192, std::time::Duration::from_secs(1)));
-    Ok(source)
-}
-
 fn main() -> Result<()> {
     color_eyre::install().ok();
     dotenvy::dotenv().ok();
     tracing_subscriber::fmt::init();
-    let (conf, args) = EnvironmentConfig::from_args();
+    let (conf, args) = RuntimeConfig::from_args();
     conf.spawn_remote_workers();
     let opt = Options::try_parse_from(args)?;
     tracing::info!("config: {opt:?}");
 
     // db::db_setup()?;
 
+    let cluster = ClusterInfo::new(&conf);
     let start = Instant::now();
-    match opt.version.as_str() {
-        "new" => unique_assoc(conf, opt)?,
-        "split" => unique_split(conf, opt)?,
-        "base" => unique(conf, opt)?,
+    let elapsed = match opt.version.as_str() {
+        "new" => unique_assoc(conf, &opt)?,
+        "split" => unique_split(conf, &opt)?,
+        "base" => unique(conf, &opt)?,
         _ => unimplemented!(),
         // false => unique_new(conf, lambda, opt.event_number, n)?,
-    }
+    };
     eprintln!("time: {:?}", start.elapsed());
     micrometer::summary_grouped();
+
+    let events = Some(opt.workload.event_number);
+    RunRecord::new(env!("CARGO_BIN_NAME"), &opt, cluster, events, elapsed)?.write(&opt.report)?;
     Ok(())
 }
 
@@ -71,10 +62,9 @@ fn inspect(id: &i32) {
     }
 }
 
-fn unique_assoc(config: EnvironmentConfig, opts: Options) -> eyre::Result<()> {
-    let mut env = StreamEnvironment::new(config);
-    let lambda = 1. / opts.lambda_inv as f32;
-    let source = make_source(lambda, &mut env, opts.event_number)?;
+fn unique_assoc(config: RuntimeConfig, opts: &Options) -> eyre::Result<Duration> {
+    let mut env = StreamContext::new(config);
+    let source = opts.workload.source(&mut env)?;
 
     let k = source.unique_assoc().inspect(inspect).collect_count();
 
@@ -84,20 +74,19 @@ fn unique_assoc(config: EnvironmentConfig, opts: Options) -> eyre::Result<()> {
 
     println!("{:?}", k.get());
     eprintln!("{elapsed:?}");
-    Ok(())
+    Ok(elapsed)
 }
 
-fn unique(config: EnvironmentConfig, opts: Options) -> eyre::Result<()> {
-    let mut env = StreamEnvironment::new(config);
-    let lambda = 1. / opts.lambda_inv as f32;
-    let source = make_source(lambda, &mut env, opts.event_number)?;
+fn unique(config: RuntimeConfig, opts: &Options) -> eyre::Result<Duration> {
+    let mut env = StreamContext::new(config);
+    let source = opts.workload.source(&mut env)?;
 
     let mut set = HashSet::<_, GroupHasherBuilder>::default();
     let k = source
-        .repartition_by(Replication::Unlimited, |el| group_by_hash(el))
+        .repartition_by(Replication::Unlimited, group_by_hash)
         .rich_flat_map(move |el| {
             if !set.contains(&el) {
-                set.insert(el.clone());
+                set.insert(el);
                 Some(el)
             } else {
                 None
@@ -112,29 +101,28 @@ fn unique(config: EnvironmentConfig, opts: Options) -> eyre::Result<()> {
 
     println!("{:?}", k.get());
     eprintln!("{elapsed:?}");
-    Ok(())
+    Ok(elapsed)
 }
 
-fn unique_split(config: EnvironmentConfig, opts: Options) -> eyre::Result<()> {
-    let mut env = StreamEnvironment::new(config);
-    let lambda = 1. / opts.lambda_inv as f32;
-    let source = make_source(lambda, &mut env, opts.event_number)?;
+fn unique_split(config: RuntimeConfig, opts: &Options) -> eyre::Result<Duration> {
+    let mut env = StreamContext::new(config);
+    let source = opts.workload.source(&mut env)?;
 
     let mut local_set = HashSet::<_, GroupHasherBuilder>::default();
     let mut global_set = HashSet::<_, GroupHasherBuilder>::default();
     let k = source
         .rich_flat_map(move |el| {
             if !local_set.contains(&el) {
-                local_set.insert(el.clone());
+                local_set.insert(el);
                 Some(el)
             } else {
                 None
             }
         })
-        .repartition_by(Replication::Unlimited, |el| group_by_hash(el))
+        .repartition_by(Replication::Unlimited, group_by_hash)
         .rich_flat_map(move |el| {
             if !global_set.contains(&el) {
-                global_set.insert(el.clone());
+                global_set.insert(el);
                 Some(el)
             } else {
                 None
@@ -149,5 +137,5 @@ fn unique_split(config: EnvironmentConfig, opts: Options) -> eyre::Result<()> {
 
     println!("{:?}", k.get());
     eprintln!("{elapsed:?}");
-    Ok(())
+    Ok(elapsed)
 }
@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use eyre::Context;
+
+use super::cache::{CacheConfig, Cached};
+use super::dataset::DatasetConfig;
+use super::memory::{MemoryBackend, MemoryConfig};
+use super::pool::PoolConfig;
+#[cfg(feature = "redis")]
+use super::redis;
+#[cfg(feature = "scylla")]
+use super::scylladb;
+use super::types::Product;
+use super::{postgres, postgres_blocking};
+
+/// Enrichment operations shared by every store, for pipelines running on tokio
+#[async_trait]
+pub trait EnrichBackend: Clone + Send + Sync + 'static {
+    async fn get_product(&self, id: i32) -> eyre::Result<Option<Product>>;
+    async fn mark_hit(&self, p: &Product) -> eyre::Result<()>;
+    async fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>>;
+    async fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>>;
+
+    /// Products of `ids` in the same order, none for the missing ones.
+    ///
+    /// Stores with a multi-get answer in a single round trip, the default looks them up
+    /// one by one
+    async fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        let mut products = Vec::with_capacity(ids.len());
+        for &id in ids {
+            products.push(self.get_product(id).await?);
+        }
+        Ok(products)
+    }
+
+    /// Number of `mark_hit` attempts retried because of a write conflict
+    fn write_retries(&self) -> u64 {
+        0
+    }
+
+    async fn recommend(&self, p: &Product, r: Recommender) -> eyre::Result<Vec<Product>> {
+        match r {
+            Recommender::Category => self.recommend_0(p).await,
+            Recommender::Tags => self.recommend_1(p).await,
+        }
+    }
+}
+
+/// Blocking twin of [EnrichBackend], for pipelines calling the store from noir threads
+pub trait EnrichBackendBlocking: Clone + Send + Sync + 'static {
+    fn get_product(&self, id: i32) -> eyre::Result<Option<Product>>;
+    fn mark_hit(&self, p: &Product) -> eyre::Result<()>;
+    fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>>;
+    fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>>;
+
+    /// See [EnrichBackend::get_products_many]
+    fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        ids.iter().map(|&id| self.get_product(id)).collect()
+    }
+
+    /// Number of `mark_hit` attempts retried because of a write conflict
+    fn write_retries(&self) -> u64 {
+        0
+    }
+
+    fn recommend(&self, p: &Product, r: Recommender) -> eyre::Result<Vec<Product>> {
+        match r {
+            Recommender::Category => self.recommend_0(p),
+            Recommender::Tags => self.recommend_1(p),
+        }
+    }
+}
+
+/// Record the rows returned by an operation in its tracing span
+pub(crate) fn record_rows(rows: usize) {
+    tracing::Span::current().record("rows", rows);
+}
+
+/// Orders the products found by a multi-get like the requested `ids`
+pub fn align_products(ids: &[i32], found: Vec<Product>) -> Vec<Option<Product>> {
+    let found: HashMap<i32, Product> = found.into_iter().map(|p| (p.id, p)).collect();
+    // the same id may be requested more than once in a batch
+    ids.iter().map(|id| found.get(id).cloned()).collect()
+}
+
+/// Recommendation strategy used by the pipelines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+pub enum Recommender {
+    /// `recommend_0`: most hit products in the same category
+    #[value(name = "0")]
+    #[serde(rename = "0")]
+    Category,
+    /// `recommend_1`: most hit products sharing a tag
+    #[value(name = "1")]
+    #[serde(rename = "1")]
+    Tags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// sqlx async pool
+    Postgres,
+    /// r2d2 blocking pool
+    PostgresBlocking,
+    /// deadpool-redis pool, at `REDIS_URI`
+    #[cfg(feature = "redis")]
+    Redis,
+    /// deadpool scylla session pool, at `SCYLLA_URI`
+    #[cfg(feature = "scylla")]
+    Scylla,
+    /// generated in-process dataset, with the latency of [MemoryConfig]
+    Memory,
+}
+
+/// Options of the backend connections, flatten it in the binary options
+#[derive(Debug, Clone, Default, clap::Args, serde::Serialize)]
+pub struct ConnectConfig {
+    /// Parse every Postgres query again instead of reusing the statements prepared by each
+    /// pooled connection
+    #[clap(long)]
+    pub no_prepare: bool,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub pool: PoolConfig,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub memory: MemoryConfig,
+
+    /// Dataset of the memory backend and of `--verify`, with the same flags as the setup
+    /// binary
+    #[clap(flatten)]
+    #[serde(skip)]
+    pub dataset: DatasetConfig,
+}
+
+impl ConnectConfig {
+    /// Replicas of this host, which size the pools with `--pool-per-replica`, see
+    /// [PoolConfig::for_host]
+    pub fn for_host(&mut self, conf: &noir_compute::prelude::RuntimeConfig) {
+        self.pool.for_host(conf);
+    }
+}
+
+/// Runs a blocking backend on the tokio blocking thread pool
+#[derive(Clone)]
+pub struct SpawnBlocking<B>(pub B);
+
+#[async_trait]
+impl<B: EnrichBackendBlocking> EnrichBackend for SpawnBlocking<B> {
+    async fn get_product(&self, id: i32) -> eyre::Result<Option<Product>> {
+        let db = self.0.clone();
+        tokio::task::spawn_blocking(move || db.get_product(id)).await?
+    }
+
+    async fn mark_hit(&self, p: &Product) -> eyre::Result<()> {
+        let (db, p) = (self.0.clone(), p.clone());
+        tokio::task::spawn_blocking(move || db.mark_hit(&p)).await?
+    }
+
+    async fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        let (db, p) = (self.0.clone(), p.clone());
+        tokio::task::spawn_blocking(move || db.recommend_0(&p)).await?
+    }
+
+    async fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        let (db, p) = (self.0.clone(), p.clone());
+        tokio::task::spawn_blocking(move || db.recommend_1(&p)).await?
+    }
+
+    async fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        let (db, ids) = (self.0.clone(), ids.to_vec());
+        tokio::task::spawn_blocking(move || db.get_products_many(&ids)).await?
+    }
+}
+
+/// Drives an async backend from outside tokio using a dedicated runtime
+#[derive(Clone)]
+pub struct BlockOn<B> {
+    inner: B,
+    rt: Arc<tokio::runtime::Runtime>,
+}
+
+impl<B> BlockOn<B> {
+    /// `inner` must have been created inside `rt`
+    pub fn new(inner: B, rt: Arc<tokio::runtime::Runtime>) -> Self {
+        Self { inner, rt }
+    }
+}
+
+impl<B: EnrichBackend> EnrichBackendBlocking for BlockOn<B> {
+    fn get_product(&self, id: i32) -> eyre::Result<Option<Product>> {
+        self.rt.block_on(self.inner.get_product(id))
+    }
+
+    fn mark_hit(&self, p: &Product) -> eyre::Result<()> {
+        self.rt.block_on(self.inner.mark_hit(p))
+    }
+
+    fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        self.rt.block_on(self.inner.recommend_0(p))
+    }
+
+    fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        self.rt.block_on(self.inner.recommend_1(p))
+    }
+
+    fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        self.rt.block_on(self.inner.get_products_many(ids))
+    }
+
+    fn write_retries(&self) -> u64 {
+        self.inner.write_retries()
+    }
+}
+
+/// Backend selected at runtime, for async pipelines
+#[derive(Clone)]
+pub enum AnyBackend {
+    Postgres(postgres::Pool),
+    PostgresBlocking(SpawnBlocking<postgres_blocking::PgPool>),
+    #[cfg(feature = "redis")]
+    Redis(redis::Pool),
+    #[cfg(feature = "scylla")]
+    Scylla(scylladb::Pool),
+    Memory(MemoryBackend),
+    Cached(Cached<AnyBackend>),
+}
+
+impl AnyBackend {
+    pub async fn connect(kind: BackendKind, cfg: &ConnectConfig) -> eyre::Result<Self> {
+        let db = match kind {
+            BackendKind::Postgres => Self::Postgres(postgres::db_init_pool(cfg).await?),
+            BackendKind::PostgresBlocking => {
+                Self::PostgresBlocking(SpawnBlocking(postgres_blocking::db_init_pool(cfg)?))
+            }
+            #[cfg(feature = "redis")]
+            BackendKind::Redis => Self::Redis(redis::db_init(cfg).await?),
+            #[cfg(feature = "scylla")]
+            BackendKind::Scylla => Self::Scylla(scylladb::db_init(cfg).await?),
+            BackendKind::Memory => Self::Memory(MemoryBackend::new(&cfg.dataset, &cfg.memory)?),
+        };
+        Ok(db)
+    }
+
+    /// Put the read-through cache in front of the backend when it is enabled, the tags of
+    /// the products are taken from `dataset`
+    pub fn with_cache(self, cfg: &CacheConfig, dataset: &DatasetConfig) -> eyre::Result<Self> {
+        match cfg.enabled() {
+            true => Ok(Self::Cached(Cached::new(self, cfg, dataset)?)),
+            false => Ok(self),
+        }
+    }
+}
+
+macro_rules! dispatch {
+    ($self:ident, $db:ident => $e:expr) => {
+        match $self {
+            AnyBackend::Postgres($db) => $e,
+            AnyBackend::PostgresBlocking($db) => $e,
+            #[cfg(feature = "redis")]
+            AnyBackend::Redis($db) => $e,
+            #[cfg(feature = "scylla")]
+            AnyBackend::Scylla($db) => $e,
+            AnyBackend::Memory($db) => $e,
+            AnyBackend::Cached($db) => $e,
+        }
+    };
+}
+
+#[async_trait]
+impl EnrichBackend for AnyBackend {
+    async fn get_product(&self, id: i32) -> eyre::Result<Option<Product>> {
+        dispatch!(self, db => EnrichBackend::get_product(db, id).await)
+    }
+
+    async fn mark_hit(&self, p: &Product) -> eyre::Result<()> {
+        dispatch!(self, db => EnrichBackend::mark_hit(db, p).await)
+    }
+
+    async fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        dispatch!(self, db => EnrichBackend::recommend_0(db, p).await)
+    }
+
+    async fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        dispatch!(self, db => EnrichBackend::recommend_1(db, p).await)
+    }
+
+    async fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        dispatch!(self, db => EnrichBackend::get_products_many(db, ids).await)
+    }
+
+    fn write_retries(&self) -> u64 {
+        dispatch!(self, db => EnrichBackend::write_retries(db))
+    }
+}
+
+/// Backend selected at runtime, for blocking pipelines
+#[derive(Clone)]
+pub enum AnyBackendBlocking {
+    PostgresBlocking(postgres_blocking::PgPool),
+    Memory(MemoryBackend),
+    Async(BlockOn<AnyBackend>),
+    Cached(Cached<AnyBackendBlocking>),
+}
+
+impl AnyBackendBlocking {
+    pub fn connect(kind: BackendKind, cfg: &ConnectConfig) -> eyre::Result<Self> {
+        let db = match kind {
+            BackendKind::PostgresBlocking => {
+                Self::PostgresBlocking(postgres_blocking::db_init_pool(cfg)?)
+            }
+            BackendKind::Memory => Self::Memory(MemoryBackend::new(&cfg.dataset, &cfg.memory)?),
+            kind => {
+                let rt = tokio::runtime::Builder::new_multi_thread()
+                    .enable_all()
+                    .build()
+                    .context("failed to build tokio runtime")?;
+                let inner = rt.block_on(AnyBackend::connect(kind, cfg))?;
+                Self::Async(BlockOn::new(inner, Arc::new(rt)))
+            }
+        };
+        Ok(db)
+    }
+
+    /// Put the read-through cache in front of the backend when it is enabled, the tags of
+    /// the products are taken from `dataset`
+    pub fn with_cache(self, cfg: &CacheConfig, dataset: &DatasetConfig) -> eyre::Result<Self> {
+        match cfg.enabled() {
+            true => Ok(Self::Cached(Cached::new(self, cfg, dataset)?)),
+            false => Ok(self),
+        }
+    }
+}
+
+impl EnrichBackendBlocking for AnyBackendBlocking {
+    fn get_product(&self, id: i32) -> eyre::Result<Option<Product>> {
+        match self {
+            Self::PostgresBlocking(db) => EnrichBackendBlocking::get_product(db, id),
+            Self::Memory(db) => EnrichBackendBlocking::get_product(db, id),
+            Self::Async(db) => EnrichBackendBlocking::get_product(db, id),
+            Self::Cached(db) => EnrichBackendBlocking::get_product(db, id),
+        }
+    }
+
+    fn mark_hit(&self, p: &Product) -> eyre::Result<()> {
+        match self {
+            Self::PostgresBlocking(db) => EnrichBackendBlocking::mark_hit(db, p),
+            Self::Memory(db) => EnrichBackendBlocking::mark_hit(db, p),
+            Self::Async(db) => EnrichBackendBlocking::mark_hit(db, p),
+            Self::Cached(db) => EnrichBackendBlocking::mark_hit(db, p),
+        }
+    }
+
+    fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        match self {
+            Self::PostgresBlocking(db) => EnrichBackendBlocking::recommend_0(db, p),
+            Self::Memory(db) => EnrichBackendBlocking::recommend_0(db, p),
+            Self::Async(db) => EnrichBackendBlocking::recommend_0(db, p),
+            Self::Cached(db) => EnrichBackendBlocking::recommend_0(db, p),
+        }
+    }
+
+    fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        match self {
+            Self::PostgresBlocking(db) => EnrichBackendBlocking::recommend_1(db, p),
+            Self::Memory(db) => EnrichBackendBlocking::recommend_1(db, p),
+            Self::Async(db) => EnrichBackendBlocking::recommend_1(db, p),
+            Self::Cached(db) => EnrichBackendBlocking::recommend_1(db, p),
+        }
+    }
+
+    fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        match self {
+            Self::PostgresBlocking(db) => EnrichBackendBlocking::get_products_many(db, ids),
+            Self::Memory(db) => EnrichBackendBlocking::get_products_many(db, ids),
+            Self::Async(db) => EnrichBackendBlocking::get_products_many(db, ids),
+            Self::Cached(db) => EnrichBackendBlocking::get_products_many(db, ids),
+        }
+    }
+
+    fn write_retries(&self) -> u64 {
+        match self {
+            Self::PostgresBlocking(db) => EnrichBackendBlocking::write_retries(db),
+            Self::Memory(db) => EnrichBackendBlocking::write_retries(db),
+            Self::Async(db) => EnrichBackendBlocking::write_retries(db),
+            Self::Cached(db) => EnrichBackendBlocking::write_retries(db),
+        }
+    }
+}
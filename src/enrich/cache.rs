@@ -0,0 +1,575 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use super::backend::{EnrichBackend, EnrichBackendBlocking};
+use super::dataset::{Dataset, DatasetConfig};
+use super::types::Product;
+
+/// Independently locked parts of each table, fewer for tables smaller than this
+const SHARDS: usize = 16;
+
+const TABLES: [&str; 3] = ["product", "recommend_0", "recommend_1"];
+
+/// Counters of every cache table of this process
+static STATS: Lazy<BTreeMap<&'static str, TableStats>> =
+    Lazy::new(|| TABLES.iter().map(|&t| (t, TableStats::default())).collect());
+
+/// Read-through cache in front of the backend, flatten it in the binary options
+#[derive(Debug, Clone, clap::Args, Serialize)]
+pub struct CacheConfig {
+    /// Entries kept at most by each cache table, 0 disables the cache
+    #[clap(long, default_value_t = 0)]
+    pub cache_size: usize,
+
+    /// Expire the cached entries after this long, 0 keeps them until evicted
+    #[clap(long, default_value_t = 0)]
+    pub cache_ttl_ms: u64,
+
+    /// Drop the cached product and the `recommend_0` entry of its category when `mark_hit`
+    /// goes through this process, and treat the stale entries of every table as misses
+    #[clap(long)]
+    pub cache_invalidate: bool,
+}
+
+impl CacheConfig {
+    pub fn enabled(&self) -> bool {
+        self.cache_size > 0
+    }
+}
+
+/// Caches products and recommendations of any backend, bounded in size with LRU eviction.
+///
+/// Each process has its own cache: writes from other hosts are only seen once the entries
+/// expire or are evicted. A hit is stale when a `mark_hit` of this process changed the
+/// product, the category for `recommend_0` or any tag of the product for `recommend_1`,
+/// after the entry was cached, and a miss instead with `--cache-invalidate`. The tags are
+/// those of the dataset, which the store must hold
+#[derive(Clone)]
+pub struct Cached<B>(Arc<Inner<B>>);
+
+struct Inner<B> {
+    backend: B,
+    invalidate: bool,
+    products: Table<i32, Option<Product>>,
+    /// By category
+    recommend_0: Table<i32, Vec<Product>>,
+    /// By product
+    recommend_1: Table<i32, Vec<Product>>,
+    product_versions: Versions,
+    category_versions: Versions,
+    tag_versions: Versions,
+    /// Tags of the products
+    dataset: Dataset,
+}
+
+impl<B> Cached<B> {
+    pub fn new(backend: B, cfg: &CacheConfig, dataset: &DatasetConfig) -> eyre::Result<Self> {
+        let ttl = match cfg.cache_ttl_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        };
+        Ok(Self(Arc::new(Inner {
+            backend,
+            invalidate: cfg.cache_invalidate,
+            products: Table::new("product", cfg.cache_size, ttl, cfg.cache_invalidate),
+            recommend_0: Table::new("recommend_0", cfg.cache_size, ttl, cfg.cache_invalidate),
+            recommend_1: Table::new("recommend_1", cfg.cache_size, ttl, cfg.cache_invalidate),
+            product_versions: Versions::default(),
+            category_versions: Versions::default(),
+            tag_versions: Versions::default(),
+            dataset: Dataset::new(dataset.clone())?,
+        })))
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.0.backend
+    }
+
+    fn lookup_product(&self, id: i32) -> Lookup<Option<Product>> {
+        let version = self.0.product_versions.get(id);
+        self.0.products.get(&id, version)
+    }
+
+    fn lookup_recommend_0(&self, p: &Product) -> Lookup<Vec<Product>> {
+        let version = self.0.category_versions.get(p.category_id);
+        self.0.recommend_0.get(&p.category_id, version)
+    }
+
+    fn lookup_recommend_1(&self, p: &Product) -> Lookup<Vec<Product>> {
+        let version = self.0.tag_versions.sum(&self.0.dataset.product_tags(p.id));
+        self.0.recommend_1.get(&p.id, version)
+    }
+
+    /// Called after `mark_hit` changed the hits of `p`
+    fn touched(&self, p: &Product) {
+        self.0.product_versions.bump(p.id);
+        self.0.category_versions.bump(p.category_id);
+        for t in self.0.dataset.product_tags(p.id) {
+            self.0.tag_versions.bump(t);
+        }
+        if self.0.invalidate {
+            self.0.products.invalidate(&p.id);
+            self.0.recommend_0.invalidate(&p.category_id);
+        }
+    }
+
+    /// Cached products of `ids` and the positions still to be loaded, with their versions
+    fn lookup_many(&self, ids: &[i32]) -> (Vec<Option<Product>>, Vec<(usize, u64)>) {
+        let mut products = Vec::with_capacity(ids.len());
+        let mut missing = Vec::new();
+        for (i, &id) in ids.iter().enumerate() {
+            match self.lookup_product(id) {
+                Lookup::Hit(p) => products.push(p),
+                Lookup::Miss(version) => {
+                    products.push(None);
+                    missing.push((i, version));
+                }
+            }
+        }
+        (products, missing)
+    }
+
+    fn fill_many(
+        &self,
+        ids: &[i32],
+        products: &mut [Option<Product>],
+        missing: &[(usize, u64)],
+        loaded: Vec<Option<Product>>,
+    ) {
+        for (&(i, version), p) in missing.iter().zip(loaded) {
+            self.0.products.insert(ids[i], p.clone(), version);
+            products[i] = p;
+        }
+    }
+}
+
+#[async_trait]
+impl<B: EnrichBackend> EnrichBackend for Cached<B> {
+    async fn get_product(&self, id: i32) -> eyre::Result<Option<Product>> {
+        match self.lookup_product(id) {
+            Lookup::Hit(p) => Ok(p),
+            Lookup::Miss(version) => {
+                let p = self.0.backend.get_product(id).await?;
+                self.0.products.insert(id, p.clone(), version);
+                Ok(p)
+            }
+        }
+    }
+
+    async fn mark_hit(&self, p: &Product) -> eyre::Result<()> {
+        self.0.backend.mark_hit(p).await?;
+        self.touched(p);
+        Ok(())
+    }
+
+    async fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        match self.lookup_recommend_0(p) {
+            Lookup::Hit(r) => Ok(r),
+            Lookup::Miss(version) => {
+                let r = self.0.backend.recommend_0(p).await?;
+                self.0.recommend_0.insert(p.category_id, r.clone(), version);
+                Ok(r)
+            }
+        }
+    }
+
+    async fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        match self.lookup_recommend_1(p) {
+            Lookup::Hit(r) => Ok(r),
+            Lookup::Miss(version) => {
+                let r = self.0.backend.recommend_1(p).await?;
+                self.0.recommend_1.insert(p.id, r.clone(), version);
+                Ok(r)
+            }
+        }
+    }
+
+    async fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        let (mut products, missing) = self.lookup_many(ids);
+        if !missing.is_empty() {
+            let load = missing.iter().map(|&(i, _)| ids[i]).collect::<Vec<_>>();
+            let loaded = self.0.backend.get_products_many(&load).await?;
+            self.fill_many(ids, &mut products, &missing, loaded);
+        }
+        Ok(products)
+    }
+
+    fn write_retries(&self) -> u64 {
+        self.0.backend.write_retries()
+    }
+}
+
+impl<B: EnrichBackendBlocking> EnrichBackendBlocking for Cached<B> {
+    fn get_product(&self, id: i32) -> eyre::Result<Option<Product>> {
+        match self.lookup_product(id) {
+            Lookup::Hit(p) => Ok(p),
+            Lookup::Miss(version) => {
+                let p = self.0.backend.get_product(id)?;
+                self.0.products.insert(id, p.clone(), version);
+                Ok(p)
+            }
+        }
+    }
+
+    fn mark_hit(&self, p: &Product) -> eyre::Result<()> {
+        self.0.backend.mark_hit(p)?;
+        self.touched(p);
+        Ok(())
+    }
+
+    fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        match self.lookup_recommend_0(p) {
+            Lookup::Hit(r) => Ok(r),
+            Lookup::Miss(version) => {
+                let r = self.0.backend.recommend_0(p)?;
+                self.0.recommend_0.insert(p.category_id, r.clone(), version);
+                Ok(r)
+            }
+        }
+    }
+
+    fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        match self.lookup_recommend_1(p) {
+            Lookup::Hit(r) => Ok(r),
+            Lookup::Miss(version) => {
+                let r = self.0.backend.recommend_1(p)?;
+                self.0.recommend_1.insert(p.id, r.clone(), version);
+                Ok(r)
+            }
+        }
+    }
+
+    fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        let (mut products, missing) = self.lookup_many(ids);
+        if !missing.is_empty() {
+            let load = missing.iter().map(|&(i, _)| ids[i]).collect::<Vec<_>>();
+            let loaded = self.0.backend.get_products_many(&load)?;
+            self.fill_many(ids, &mut products, &missing, loaded);
+        }
+        Ok(products)
+    }
+
+    fn write_retries(&self) -> u64 {
+        self.0.backend.write_retries()
+    }
+}
+
+/// Writes seen by this process, by product, category or tag
+#[derive(Default)]
+struct Versions(Mutex<HashMap<i32, u64>>);
+
+impl Versions {
+    fn get(&self, key: i32) -> u64 {
+        self.0.lock().unwrap().get(&key).copied().unwrap_or(0)
+    }
+
+    /// Version of an entry depending on all the `keys`, which grows with any of them
+    fn sum(&self, keys: &[i32]) -> u64 {
+        let versions = self.0.lock().unwrap();
+        keys.iter().filter_map(|k| versions.get(k)).sum()
+    }
+
+    fn bump(&self, key: i32) {
+        *self.0.lock().unwrap().entry(key).or_default() += 1;
+    }
+}
+
+enum Lookup<V> {
+    Hit(V),
+    /// Version to cache the loaded value with, read before loading it so that a concurrent
+    /// write makes the entry stale
+    Miss(u64),
+}
+
+struct Entry<V> {
+    value: V,
+    inserted: Instant,
+    version: u64,
+    /// Position in the LRU order
+    tick: u64,
+}
+
+struct Shard<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    capacity: usize,
+    /// Keys by last use, oldest first
+    lru: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Shard<K, V> {
+    fn touch(&mut self, key: &K) {
+        self.tick += 1;
+        if let Some(e) = self.entries.get_mut(key) {
+            self.lru.remove(&e.tick);
+            self.lru.insert(self.tick, key.clone());
+            e.tick = self.tick;
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> Option<Entry<V>> {
+        let e = self.entries.remove(key)?;
+        self.lru.remove(&e.tick);
+        Some(e)
+    }
+}
+
+struct Table<K, V> {
+    shards: Vec<Mutex<Shard<K, V>>>,
+    hasher: ahash::RandomState,
+    ttl: Option<Duration>,
+    /// Drop the stale entries instead of serving them
+    invalidate: bool,
+    stats: &'static TableStats,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Table<K, V> {
+    /// Table of at most `size` entries, split between the shards: each shard evicts its own
+    /// least recently used entry once full, so the table may evict before holding `size`
+    fn new(name: &'static str, size: usize, ttl: Option<Duration>, invalidate: bool) -> Self {
+        let n = size.clamp(1, SHARDS);
+        let shards = (0..n)
+            .map(|i| {
+                Mutex::new(Shard {
+                    entries: HashMap::new(),
+                    capacity: size / n + (i < size % n) as usize,
+                    lru: BTreeMap::new(),
+                    tick: 0,
+                })
+            })
+            .collect();
+        Self {
+            shards,
+            hasher: ahash::RandomState::new(),
+            ttl,
+            invalidate,
+            stats: &STATS[name],
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<Shard<K, V>> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    fn get(&self, key: &K, version: u64) -> Lookup<V> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let Some(e) = shard.entries.get(key) else {
+            self.stats.misses.fetch_add(1, Ordering::Relaxed);
+            return Lookup::Miss(version);
+        };
+        let age = e.inserted.elapsed();
+        if self.ttl.is_some_and(|ttl| age > ttl) {
+            shard.remove(key);
+            self.stats.expired.fetch_add(1, Ordering::Relaxed);
+            self.stats.misses.fetch_add(1, Ordering::Relaxed);
+            return Lookup::Miss(version);
+        }
+        let stale = e.version != version;
+        if stale && self.invalidate {
+            shard.remove(key);
+            self.stats.invalidations.fetch_add(1, Ordering::Relaxed);
+            self.stats.misses.fetch_add(1, Ordering::Relaxed);
+            return Lookup::Miss(version);
+        }
+        let value = e.value.clone();
+        shard.touch(key);
+        drop(shard);
+
+        self.stats.record_hit(age, stale);
+        Lookup::Hit(value)
+    }
+
+    fn insert(&self, key: K, value: V, version: u64) {
+        let mut shard = self.shard(&key).lock().unwrap();
+        shard.remove(&key);
+        if shard.entries.len() >= shard.capacity {
+            if let Some((_, oldest)) = shard.lru.pop_first() {
+                shard.entries.remove(&oldest);
+                self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        shard.tick += 1;
+        let tick = shard.tick;
+        shard.lru.insert(tick, key.clone());
+        shard.entries.insert(
+            key,
+            Entry {
+                value,
+                inserted: Instant::now(),
+                version,
+                tick,
+            },
+        );
+    }
+
+    fn invalidate(&self, key: &K) {
+        if self.shard(key).lock().unwrap().remove(key).is_some() {
+            self.stats.invalidations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Default)]
+struct TableStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale_hits: AtomicU64,
+    expired: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+    /// Age of the entries served by the hits
+    age_sum_us: AtomicU64,
+    age_max_us: AtomicU64,
+}
+
+impl TableStats {
+    fn record_hit(&self, age: Duration, stale: bool) {
+        let us = age.as_micros() as u64;
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.age_sum_us.fetch_add(us, Ordering::Relaxed);
+        self.age_max_us.fetch_max(us, Ordering::Relaxed);
+        if stale {
+            self.stale_hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Effectiveness and freshness of a cache table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheSummary {
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_ratio: f64,
+    /// Hits serving an entry older than a write of this process, see [Cached]
+    pub stale_hits: u64,
+    pub stale_ratio: f64,
+    pub expired: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+    /// Mean age of the entries served by the hits
+    pub mean_age_ms: f64,
+    pub max_age_ms: f64,
+}
+
+impl From<&TableStats> for CacheSummary {
+    fn from(s: &TableStats) -> Self {
+        let hits = s.hits.load(Ordering::Relaxed);
+        let misses = s.misses.load(Ordering::Relaxed);
+        let stale_hits = s.stale_hits.load(Ordering::Relaxed);
+        let ratio = |n: u64, d: u64| if d == 0 { 0.0 } else { n as f64 / d as f64 };
+        Self {
+            hits,
+            misses,
+            hit_ratio: ratio(hits, hits + misses),
+            stale_hits,
+            stale_ratio: ratio(stale_hits, hits),
+            expired: s.expired.load(Ordering::Relaxed),
+            evictions: s.evictions.load(Ordering::Relaxed),
+            invalidations: s.invalidations.load(Ordering::Relaxed),
+            mean_age_ms: ratio(s.age_sum_us.load(Ordering::Relaxed), hits) / 1000.0,
+            max_age_ms: s.age_max_us.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+/// Summary of the cache tables used by this process so far
+pub fn summary() -> BTreeMap<&'static str, CacheSummary> {
+    STATS
+        .iter()
+        .filter(|(_, s)| s.hits.load(Ordering::Relaxed) + s.misses.load(Ordering::Relaxed) > 0)
+        .map(|(&t, s)| (t, s.into()))
+        .collect()
+}
+
+/// [summary] as a JSON object keyed by table
+pub fn summary_json() -> String {
+    serde_json::to_string(&summary()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit<V>(l: Lookup<V>) -> Option<V> {
+        match l {
+            Lookup::Hit(v) => Some(v),
+            Lookup::Miss(_) => None,
+        }
+    }
+
+    #[test]
+    fn table_expires_after_the_ttl() {
+        let table = Table::new("product", 4, Some(Duration::from_millis(20)), false);
+        table.insert(1, "a", 0);
+        assert_eq!(hit(table.get(&1, 0)), Some("a"));
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(hit(table.get(&1, 0)), None);
+    }
+
+    #[test]
+    fn table_evicts_the_least_recently_used_of_a_shard() {
+        let table = Table::new("product", 2 * SHARDS, None, false);
+        let same_shard = |k| std::ptr::eq(table.shard(&k), table.shard(&0));
+        let keys = (0..)
+            .filter(|&k| same_shard(k))
+            .take(3)
+            .collect::<Vec<i32>>();
+        let (a, b, c) = (keys[0], keys[1], keys[2]);
+
+        table.insert(a, a, 0);
+        table.insert(b, b, 0);
+        assert_eq!(hit(table.get(&a, 0)), Some(a));
+        table.insert(c, c, 0);
+        assert_eq!(hit(table.get(&b, 0)), None);
+        assert_eq!(hit(table.get(&a, 0)), Some(a));
+        assert_eq!(hit(table.get(&c, 0)), Some(c));
+    }
+
+    #[test]
+    fn table_serves_stale_entries_unless_invalidating() {
+        let table = Table::new("recommend_1", 4, None, false);
+        table.insert(1, "a", 0);
+        assert_eq!(hit(table.get(&1, 1)), Some("a"));
+
+        let table = Table::new("recommend_1", 4, None, true);
+        table.insert(1, "a", 0);
+        assert!(matches!(table.get(&1, 1), Lookup::Miss(1)));
+        assert_eq!(hit(table.get(&1, 0)), None);
+        table.insert(1, "b", 1);
+        assert_eq!(hit(table.get(&1, 1)), Some("b"));
+    }
+
+    #[test]
+    fn table_invalidate_drops_the_entry() {
+        let table = Table::new("recommend_0", 4, None, false);
+        table.insert(1, "a", 0);
+        table.insert(2, "b", 0);
+        table.invalidate(&1);
+        table.invalidate(&3);
+        assert_eq!(hit(table.get(&1, 0)), None);
+        assert_eq!(hit(table.get(&2, 0)), Some("b"));
+    }
+
+    #[test]
+    fn versions_grow_with_any_key() {
+        let versions = Versions::default();
+        assert_eq!(versions.get(1), 0);
+        assert_eq!(versions.sum(&[1, 2]), 0);
+
+        versions.bump(1);
+        versions.bump(1);
+        versions.bump(3);
+        assert_eq!(versions.get(1), 2);
+        assert_eq!(versions.get(2), 0);
+        assert_eq!(versions.sum(&[1, 2]), 2);
+        assert_eq!(versions.sum(&[1, 2, 3]), 3);
+        assert_eq!(versions.sum(&[]), 0);
+    }
+}
@@ -0,0 +1,152 @@
+use std::ops::RangeInclusive;
+
+use clap::Parser;
+use eyre::Context;
+use rand::prelude::*;
+use rand_distr::Zipf;
+
+use super::types::Product;
+
+/// Knobs of the synthetic catalogue loaded into every store.
+///
+/// The defaults reproduce the original `00_init.sql` population
+#[derive(Debug, Clone, PartialEq, Parser)]
+pub struct DatasetConfig {
+    /// Number of products
+    #[clap(long, default_value_t = 1_000_000)]
+    pub products: usize,
+
+    /// Number of categories
+    #[clap(long, default_value_t = 100)]
+    pub categories: usize,
+
+    /// Number of tags
+    #[clap(long, default_value_t = 500)]
+    pub tags: usize,
+
+    /// Maximum number of tag draws per product, duplicate draws are dropped
+    #[clap(long, default_value_t = 5)]
+    pub tags_per_product: usize,
+
+    /// Zipf exponent of the category sizes, 0 is uniform
+    #[clap(long, default_value_t = 0.0)]
+    pub category_skew: f64,
+
+    /// Zipf exponent of the tag popularity, 0 is uniform
+    #[clap(long, default_value_t = 0.0)]
+    pub tag_skew: f64,
+
+    /// Zipf exponent of the number of tag draws per product, 0 always draws `tags_per_product`
+    #[clap(long, default_value_t = 0.0)]
+    pub tag_fanout_skew: f64,
+
+    /// Initial hits are uniform in `0..max_hits`
+    #[clap(long, default_value_t = 1000)]
+    pub max_hits: i64,
+
+    /// Seed of the generator, the same seed yields the same dataset on every store
+    #[clap(long, default_value_t = 0xfeeddabeef)]
+    pub seed: u64,
+}
+
+impl Default for DatasetConfig {
+    fn default() -> Self {
+        Self::parse_from(["dataset"])
+    }
+}
+
+/// Deterministic product catalogue.
+///
+/// Every product is derived from the seed and its id alone, so loaders can generate any
+/// slice of the dataset independently and in any order
+#[derive(Debug, Clone)]
+pub struct Dataset {
+    cfg: DatasetConfig,
+    category: Zipf<f64>,
+    tag: Zipf<f64>,
+    fanout: Option<Zipf<f64>>,
+}
+
+const SALT_PRODUCT: u64 = 0x9e3779b97f4a7c15;
+const SALT_TAGS: u64 = 0xc2b2ae3d27d4eb4f;
+
+impl Dataset {
+    pub fn new(cfg: DatasetConfig) -> eyre::Result<Self> {
+        eyre::ensure!(
+            cfg.products > 0,
+            "dataset must contain at least one product"
+        );
+        eyre::ensure!(
+            cfg.products <= i32::MAX as usize,
+            "product ids must fit in an INT column"
+        );
+        eyre::ensure!(cfg.max_hits > 0, "max_hits must be positive");
+
+        let category = Zipf::new(cfg.categories as u64, cfg.category_skew)
+            .context("invalid category distribution")?;
+        let tag = Zipf::new(cfg.tags as u64, cfg.tag_skew).context("invalid tag distribution")?;
+        let fanout = if cfg.tag_fanout_skew > 0.0 && cfg.tags_per_product > 0 {
+            Some(
+                Zipf::new(cfg.tags_per_product as u64, cfg.tag_fanout_skew)
+                    .context("invalid tag fan-out distribution")?,
+            )
+        } else {
+            None
+        };
+
+        Ok(Self {
+            cfg,
+            category,
+            tag,
+            fanout,
+        })
+    }
+
+    pub fn config(&self) -> &DatasetConfig {
+        &self.cfg
+    }
+
+    fn rng(&self, id: i32, salt: u64) -> SmallRng {
+        SmallRng::seed_from_u64(self.cfg.seed ^ (id as u64).wrapping_mul(salt))
+    }
+
+    pub fn categories(&self) -> impl Iterator<Item = (i32, String)> {
+        (1..=self.cfg.categories as i32).map(|i| (i, format!("Category {i}")))
+    }
+
+    pub fn tags(&self) -> impl Iterator<Item = (i32, String)> {
+        (1..=self.cfg.tags as i32).map(|i| (i, format!("Tag {i}")))
+    }
+
+    pub fn product_ids(&self) -> RangeInclusive<i32> {
+        1..=self.cfg.products as i32
+    }
+
+    pub fn product(&self, id: i32) -> Product {
+        let mut rng = self.rng(id, SALT_PRODUCT);
+        Product {
+            id,
+            name: format!("Product {id}"),
+            description: Some(format!("Description for Product {id}")),
+            category_id: self.category.sample(&mut rng) as i32,
+            hits: rng.gen_range(0..self.cfg.max_hits),
+        }
+    }
+
+    /// Distinct tags of a product
+    pub fn product_tags(&self, id: i32) -> Vec<i32> {
+        let mut rng = self.rng(id, SALT_TAGS);
+        let draws = match &self.fanout {
+            Some(fanout) => fanout.sample(&mut rng) as usize,
+            None => self.cfg.tags_per_product,
+        };
+        let mut tags = Vec::with_capacity(draws);
+        for _ in 0..draws {
+            let t = self.tag.sample(&mut rng) as i32;
+            if !tags.contains(&t) {
+                tags.push(t);
+            }
+        }
+        tags
+    }
+}
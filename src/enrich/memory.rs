@@ -0,0 +1,399 @@
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rand::prelude::*;
+use serde::Serialize;
+use tracing::field::Empty;
+
+use super::backend::{record_rows, EnrichBackend, EnrichBackendBlocking};
+use super::dataset::{Dataset, DatasetConfig};
+use super::types::Product;
+
+/// Products ranked by hits, highest first
+type Ranking = BTreeSet<(Reverse<i64>, i32)>;
+
+/// Latency injected by the memory backend
+#[derive(Debug, Clone, Default, clap::Args, Serialize)]
+pub struct MemoryConfig {
+    /// Fixed delay in microseconds added to every operation of the memory backend
+    #[clap(long, env = "MEMORY_LATENCY_US", default_value_t = 0)]
+    pub memory_latency_us: u64,
+
+    /// Upper bound in microseconds of the uniform random delay added on top of
+    /// `--memory-latency-us`
+    #[clap(long, env = "MEMORY_JITTER_US", default_value_t = 0)]
+    pub memory_jitter_us: u64,
+}
+
+/// Position of a 1-based id in the dense tables
+fn slot(id: i32) -> Option<usize> {
+    usize::try_from(id).ok()?.checked_sub(1)
+}
+
+struct Inner {
+    /// Indexed by `id - 1`
+    products: Vec<Product>,
+    /// Tags of each product, indexed by `id - 1`
+    product_tags: Vec<Vec<i32>>,
+    /// Indexed by `category_id - 1`
+    by_category: Vec<Ranking>,
+    /// Indexed by `tag_id - 1`
+    by_tag: Vec<Ranking>,
+}
+
+impl Inner {
+    fn generate(dataset: &Dataset) -> Self {
+        let products: Vec<Product> = dataset.product_ids().map(|i| dataset.product(i)).collect();
+        let product_tags: Vec<Vec<i32>> = dataset
+            .product_ids()
+            .map(|i| dataset.product_tags(i))
+            .collect();
+
+        let cfg = dataset.config();
+        Self::new(products, product_tags, cfg.categories, cfg.tags)
+    }
+
+    /// Store of `products` and their tags, indexed by `id - 1`
+    fn new(
+        products: Vec<Product>,
+        product_tags: Vec<Vec<i32>>,
+        categories: usize,
+        tags: usize,
+    ) -> Self {
+        let mut by_category = vec![Ranking::new(); categories];
+        let mut by_tag = vec![Ranking::new(); tags];
+        for (p, tags) in products.iter().zip(product_tags.iter()) {
+            by_category[p.category_id as usize - 1].insert((Reverse(p.hits), p.id));
+            for &t in tags {
+                by_tag[t as usize - 1].insert((Reverse(p.hits), p.id));
+            }
+        }
+
+        Self {
+            products,
+            product_tags,
+            by_category,
+            by_tag,
+        }
+    }
+
+    fn get(&self, id: i32) -> Option<&Product> {
+        self.products.get(slot(id)?)
+    }
+
+    fn get_many(&self, ids: &[i32]) -> Vec<Option<Product>> {
+        ids.iter().map(|&id| self.get(id).cloned()).collect()
+    }
+
+    fn resolve(&self, ranking: &Ranking) -> Vec<Product> {
+        ranking
+            .iter()
+            .take(5)
+            .map(|&(_, id)| self.products[id as usize - 1].clone())
+            .collect()
+    }
+
+    fn mark_hit(&mut self, id: i32) {
+        let Some(p) = self.get(id) else {
+            return;
+        };
+        let (old, c) = ((Reverse(p.hits), id), p.category_id);
+        let new = (Reverse(p.hits + 1), id);
+
+        let i = id as usize - 1;
+        self.products[i].hits += 1;
+        let category = &mut self.by_category[c as usize - 1];
+        category.remove(&old);
+        category.insert(new);
+        for &t in &self.product_tags[i] {
+            let tag = &mut self.by_tag[t as usize - 1];
+            tag.remove(&old);
+            tag.insert(new);
+        }
+    }
+
+    fn recommend_0(&self, p: &Product) -> Vec<Product> {
+        match slot(p.category_id).and_then(|c| self.by_category.get(c)) {
+            Some(category) => self.resolve(category),
+            None => vec![],
+        }
+    }
+
+    fn recommend_1(&self, p: &Product) -> Vec<Product> {
+        let Some(tags) = slot(p.id).and_then(|i| self.product_tags.get(i)) else {
+            return vec![];
+        };
+        // the top 5 of the union is always contained in the union of the top 5 of each tag
+        let candidates: Ranking = tags
+            .iter()
+            .flat_map(|&t| self.by_tag[t as usize - 1].iter().take(5).copied())
+            .collect();
+        self.resolve(&candidates)
+    }
+}
+
+/// In-process store with the same data model as the SQL schema, for offline runs
+#[derive(Clone)]
+pub struct MemoryBackend {
+    inner: Arc<RwLock<Inner>>,
+    latency: Duration,
+    jitter: Duration,
+}
+
+impl MemoryBackend {
+    /// Store of the generated `dataset`
+    pub fn new(dataset: &DatasetConfig, cfg: &MemoryConfig) -> eyre::Result<Self> {
+        let dataset = Dataset::new(dataset.clone())?;
+        Ok(Self {
+            inner: Arc::new(RwLock::new(Inner::generate(&dataset))),
+            latency: Duration::from_micros(cfg.memory_latency_us),
+            jitter: Duration::from_micros(cfg.memory_jitter_us),
+        })
+    }
+
+    fn delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            self.latency
+        } else {
+            self.latency + thread_rng().gen_range(Duration::ZERO..self.jitter)
+        }
+    }
+
+    async fn wait(&self) {
+        let d = self.delay();
+        if !d.is_zero() {
+            tokio::time::sleep(d).await;
+        }
+    }
+
+    fn wait_blocking(&self) {
+        let d = self.delay();
+        if !d.is_zero() {
+            std::thread::sleep(d);
+        }
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&Inner) -> T) -> T {
+        f(&self.inner.read().unwrap())
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&mut Inner) -> T) -> T {
+        f(&mut self.inner.write().unwrap())
+    }
+}
+
+#[async_trait]
+impl EnrichBackend for MemoryBackend {
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(backend = "memory", id = id, rows = Empty)
+    )]
+    async fn get_product(&self, id: i32) -> eyre::Result<Option<Product>> {
+        self.wait().await;
+        let p = self.read(|db| db.get(id).cloned());
+        record_rows(p.is_some().into());
+        Ok(p)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(backend = "memory", id = p.id))]
+    async fn mark_hit(&self, p: &Product) -> eyre::Result<()> {
+        self.wait().await;
+        self.write(|db| db.mark_hit(p.id));
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(backend = "memory", id = p.id, category = p.category_id, rows = Empty)
+    )]
+    async fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        self.wait().await;
+        let r = self.read(|db| db.recommend_0(p));
+        record_rows(r.len());
+        Ok(r)
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(backend = "memory", id = p.id, rows = Empty)
+    )]
+    async fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        self.wait().await;
+        let r = self.read(|db| db.recommend_1(p));
+        record_rows(r.len());
+        Ok(r)
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(backend = "memory", ids = ids.len(), rows = Empty)
+    )]
+    async fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        self.wait().await;
+        let r = self.read(|db| db.get_many(ids));
+        record_rows(r.iter().flatten().count());
+        Ok(r)
+    }
+}
+
+impl EnrichBackendBlocking for MemoryBackend {
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(backend = "memory", id = id, rows = Empty)
+    )]
+    fn get_product(&self, id: i32) -> eyre::Result<Option<Product>> {
+        self.wait_blocking();
+        let p = self.read(|db| db.get(id).cloned());
+        record_rows(p.is_some().into());
+        Ok(p)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(backend = "memory", id = p.id))]
+    fn mark_hit(&self, p: &Product) -> eyre::Result<()> {
+        self.wait_blocking();
+        self.write(|db| db.mark_hit(p.id));
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(backend = "memory", id = p.id, category = p.category_id, rows = Empty)
+    )]
+    fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        self.wait_blocking();
+        let r = self.read(|db| db.recommend_0(p));
+        record_rows(r.len());
+        Ok(r)
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(backend = "memory", id = p.id, rows = Empty)
+    )]
+    fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        self.wait_blocking();
+        let r = self.read(|db| db.recommend_1(p));
+        record_rows(r.len());
+        Ok(r)
+    }
+
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(backend = "memory", ids = ids.len(), rows = Empty)
+    )]
+    fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        self.wait_blocking();
+        let r = self.read(|db| db.get_many(ids));
+        record_rows(r.iter().flatten().count());
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Products of 2 categories and 2 tags, given as `(category_id, hits, tags)`
+    fn inner() -> Inner {
+        let rows = [
+            (1, 10, vec![1]),
+            (1, 30, vec![1, 2]),
+            (1, 10, vec![2]),
+            (1, 20, vec![]),
+            (1, 5, vec![1]),
+            (1, 1, vec![2]),
+            (2, 99, vec![1]),
+        ];
+        let products = rows
+            .iter()
+            .zip(1..)
+            .map(|(&(category_id, hits, _), id)| Product {
+                id,
+                name: format!("Product {id}"),
+                description: None,
+                category_id,
+                hits,
+            })
+            .collect();
+        let tags = rows.into_iter().map(|(_, _, t)| t).collect();
+        Inner::new(products, tags, 2, 2)
+    }
+
+    fn ids(products: &[Product]) -> Vec<i32> {
+        products.iter().map(|p| p.id).collect()
+    }
+
+    #[test]
+    fn recommend_0_ranks_the_category_by_hits_then_id() {
+        let db = inner();
+        let p = db.get(1).unwrap().clone();
+        assert_eq!(ids(&db.recommend_0(&p)), [2, 4, 1, 3, 5]);
+
+        let p = db.get(7).unwrap().clone();
+        assert_eq!(ids(&db.recommend_0(&p)), [7]);
+
+        let unknown = Product {
+            category_id: 9,
+            ..p
+        };
+        assert!(db.recommend_0(&unknown).is_empty());
+    }
+
+    #[test]
+    fn recommend_1_ranks_the_union_of_the_tags_by_hits_then_id() {
+        let db = inner();
+        let p = db.get(2).unwrap().clone();
+        assert_eq!(ids(&db.recommend_1(&p)), [7, 2, 1, 3, 5]);
+
+        let p = db.get(6).unwrap().clone();
+        assert_eq!(ids(&db.recommend_1(&p)), [2, 3, 6]);
+
+        let untagged = db.get(4).unwrap().clone();
+        assert!(db.recommend_1(&untagged).is_empty());
+    }
+
+    #[test]
+    fn mark_hit_moves_the_product_up_every_ranking() {
+        let mut db = inner();
+        for _ in 0..6 {
+            db.mark_hit(5);
+        }
+        db.mark_hit(99);
+        assert_eq!(db.get(5).unwrap().hits, 11);
+
+        let p = db.get(1).unwrap().clone();
+        assert_eq!(ids(&db.recommend_0(&p)), [2, 4, 5, 1, 3]);
+        let p = db.get(2).unwrap().clone();
+        assert_eq!(ids(&db.recommend_1(&p)), [7, 2, 5, 1, 3]);
+        assert_eq!(db.recommend_0(&p)[2].hits, 11);
+    }
+
+    #[test]
+    fn get_products_many_keeps_the_order_and_the_missing_ids() {
+        let db = MemoryBackend {
+            inner: Arc::new(RwLock::new(inner())),
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+        };
+        let many = EnrichBackendBlocking::get_products_many(&db, &[3, 0, 99, 1, 3]).unwrap();
+        let many = many
+            .iter()
+            .map(|p| p.as_ref().map(|p| p.id))
+            .collect::<Vec<_>>();
+        assert_eq!(many, [Some(3), None, None, Some(1), Some(3)]);
+        assert!(EnrichBackendBlocking::get_products_many(&db, &[])
+            .unwrap()
+            .is_empty());
+    }
+}
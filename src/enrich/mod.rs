@@ -1,3 +1,13 @@
+pub mod backend;
+pub mod cache;
+pub mod dataset;
+pub mod memory;
+pub mod pool;
 pub mod postgres;
 pub mod postgres_blocking;
-pub mod types;
\ No newline at end of file
+#[cfg(feature = "redis")]
+pub mod redis;
+#[cfg(feature = "scylla")]
+pub mod scylladb;
+pub mod types;
+pub mod verify;
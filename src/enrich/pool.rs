@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use noir_compute::prelude::*;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_MIN_IDLE: usize = 4;
+
+/// Size of the connection pool of every backend, flatten it in the binary options.
+///
+/// Without `--pool-max-size` the pool is sized on the noir replicas of this host when
+/// `--pool-per-replica` is given and [PoolConfig::for_host] is called, and on the default
+/// of each backend otherwise
+#[derive(Debug, Clone, clap::Args, Serialize)]
+pub struct PoolConfig {
+    /// Connections of each pool on this host
+    #[clap(long, env = "POOL_MAX_SIZE")]
+    pub pool_max_size: Option<usize>,
+
+    /// Connections of each pool for every local noir replica, without `--pool-max-size`
+    #[clap(long, env = "POOL_PER_REPLICA")]
+    pub pool_per_replica: Option<usize>,
+
+    /// Connections kept open while idle, sqlx and r2d2 only
+    #[clap(long, env = "POOL_MIN_IDLE", default_value_t = DEFAULT_MIN_IDLE)]
+    pub pool_min_idle: usize,
+
+    /// Seconds waited for a free connection before failing the operation, the default of
+    /// each backend if missing
+    #[clap(long, env = "POOL_ACQUIRE_TIMEOUT_S")]
+    pub pool_acquire_timeout_s: Option<u64>,
+
+    /// Noir replicas running on this host, set by [PoolConfig::for_host]
+    #[clap(skip)]
+    pub local_replicas: Option<usize>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            pool_max_size: None,
+            pool_per_replica: None,
+            pool_min_idle: DEFAULT_MIN_IDLE,
+            pool_acquire_timeout_s: None,
+            local_replicas: None,
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Replicas that this host runs in `conf`, the pool is sized on them with
+    /// `--pool-per-replica`
+    pub fn for_host(&mut self, conf: &RuntimeConfig) {
+        self.local_replicas = Some(local_replicas(conf));
+    }
+
+    /// Connections of the pool, `default` applies unless the size or the connections per
+    /// replica are given
+    pub fn max_size(&self, default: usize) -> usize {
+        let per_host = self
+            .pool_per_replica
+            .zip(self.local_replicas)
+            .map(|(c, r)| c * r);
+        let size = self.pool_max_size.or(per_host).unwrap_or(default).max(1);
+        tracing::debug!("pool size: {size}");
+        size
+    }
+
+    /// Idle connections, never more than the pool holds
+    pub fn min_idle(&self, max_size: usize) -> usize {
+        self.pool_min_idle.min(max_size)
+    }
+
+    /// Wait for a free connection, `None` keeps the default of the backend
+    pub fn acquire_timeout(&self) -> Option<Duration> {
+        self.pool_acquire_timeout_s.map(Duration::from_secs)
+    }
+}
+
+/// Cores of this host, noir starts one replica of each operator per core
+fn local_replicas(conf: &RuntimeConfig) -> usize {
+    let cores = match conf {
+        RuntimeConfig::Local(local) => local.num_cores,
+        RuntimeConfig::Remote(remote) => {
+            let host = remote.host_id.unwrap_or(0) as usize;
+            remote.hosts.get(host).map_or(1, |h| h.num_cores)
+        }
+    };
+    cores as usize
+}
+
+const POOLS: [&str; 4] = ["postgres", "postgres_blocking", "redis", "scylla"];
+
+/// Counters of the connection pool of each backend in this process
+static STATS: Lazy<BTreeMap<&'static str, PoolStats>> =
+    Lazy::new(|| POOLS.iter().map(|&p| (p, PoolStats::default())).collect());
+
+/// Counters of the pool `name`, one of the backends
+pub fn stats(name: &'static str) -> &'static PoolStats {
+    &STATS[name]
+}
+
+#[derive(Default)]
+pub struct PoolStats {
+    acquires: AtomicU64,
+    wait_sum_us: AtomicU64,
+    wait_max_us: AtomicU64,
+    timeouts: AtomicU64,
+    created: AtomicU64,
+    /// Sampled after every checkout
+    in_use_sum: AtomicU64,
+    in_use_max: AtomicU64,
+    idle_sum: AtomicU64,
+}
+
+impl PoolStats {
+    /// A connection was opened by the pool
+    pub fn created(&self) {
+        self.created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A checkout gave up waiting for a free connection
+    pub fn timed_out(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A checkout returned after `wait`, when the pool held `size` connections with `idle`
+    /// of them free
+    pub fn checkout(&self, wait: Duration, size: usize, idle: usize) {
+        let us = wait.as_micros() as u64;
+        let in_use = size.saturating_sub(idle) as u64;
+        self.acquires.fetch_add(1, Ordering::Relaxed);
+        self.wait_sum_us.fetch_add(us, Ordering::Relaxed);
+        self.wait_max_us.fetch_max(us, Ordering::Relaxed);
+        self.in_use_sum.fetch_add(in_use, Ordering::Relaxed);
+        self.in_use_max.fetch_max(in_use, Ordering::Relaxed);
+        self.idle_sum.fetch_add(idle as u64, Ordering::Relaxed);
+    }
+}
+
+/// Contention on a connection pool over the run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolSummary {
+    pub acquires: u64,
+    pub mean_wait_ms: f64,
+    pub max_wait_ms: f64,
+    pub timeouts: u64,
+    /// Connections opened, including the replacements of broken ones
+    pub created: u64,
+    /// Connections checked out, sampled after each checkout
+    pub mean_in_use: f64,
+    pub max_in_use: u64,
+    pub mean_idle: f64,
+}
+
+impl From<&PoolStats> for PoolSummary {
+    fn from(s: &PoolStats) -> Self {
+        let acquires = s.acquires.load(Ordering::Relaxed);
+        let mean = |v: &AtomicU64| match acquires {
+            0 => 0.0,
+            n => v.load(Ordering::Relaxed) as f64 / n as f64,
+        };
+        Self {
+            acquires,
+            mean_wait_ms: mean(&s.wait_sum_us) / 1000.0,
+            max_wait_ms: s.wait_max_us.load(Ordering::Relaxed) as f64 / 1000.0,
+            timeouts: s.timeouts.load(Ordering::Relaxed),
+            created: s.created.load(Ordering::Relaxed),
+            mean_in_use: mean(&s.in_use_sum),
+            max_in_use: s.in_use_max.load(Ordering::Relaxed),
+            mean_idle: mean(&s.idle_sum),
+        }
+    }
+}
+
+/// Summary of the pools used by this process so far
+pub fn summary() -> BTreeMap<&'static str, PoolSummary> {
+    STATS
+        .iter()
+        .filter(|(_, s)| s.created.load(Ordering::Relaxed) + s.acquires.load(Ordering::Relaxed) > 0)
+        .map(|(&p, s)| (p, s.into()))
+        .collect()
+}
+
+/// [summary] as a JSON object keyed by pool
+pub fn summary_json() -> String {
+    serde_json::to_string(&summary()).unwrap()
+}
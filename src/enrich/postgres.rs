@@ -1,30 +1,72 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use color_eyre::eyre::Context;
-use sqlx::migrate::MigrateDatabase;
 use sqlx::migrate;
+use sqlx::migrate::MigrateDatabase;
+use sqlx::pool::PoolConnection;
 use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions};
-use sqlx::{ConnectOptions, PgExecutor};
+use sqlx::{ConnectOptions, PgExecutor, Postgres};
+use tracing::field::Empty;
 
+use super::backend::{align_products, record_rows, ConnectConfig, EnrichBackend};
+use super::dataset::Dataset;
+use super::pool;
 use super::types::*;
 
 pub type Pool = PgPool;
 
-pub async fn db_init_pool() -> color_eyre::Result<PgPool> {
+/// Statements prepared and kept by each connection, more than the queries of this module
+const STATEMENT_CACHE: usize = 32;
+
+pub async fn db_init_pool(cfg: &ConnectConfig) -> color_eyre::Result<PgPool> {
     let url = std::env::var("DATABASE_URL").context("Missing DATABASE_URL")?;
 
+    // without the cache sqlx prepares an unnamed statement on every call
+    let statements = match cfg.no_prepare {
+        true => 0,
+        false => STATEMENT_CACHE,
+    };
+    let max_size = cfg.pool.max_size(16);
     let pool = PgPoolOptions::new()
-        .acquire_timeout(Duration::from_secs(16))
+        .acquire_timeout(
+            cfg.pool
+                .acquire_timeout()
+                .unwrap_or(Duration::from_secs(16)),
+        )
         .max_lifetime(None)
         .idle_timeout(None)
-        .min_connections(4)
-        .max_connections(16)
-        .connect_lazy_with(url.parse::<PgConnectOptions>()?.disable_statement_logging());
+        .min_connections(cfg.pool.min_idle(max_size) as u32)
+        .max_connections(max_size as u32)
+        .after_connect(|_, _| {
+            Box::pin(async {
+                pool::stats("postgres").created();
+                Ok(())
+            })
+        })
+        .connect_lazy_with(
+            url.parse::<PgConnectOptions>()?
+                .statement_cache_capacity(statements)
+                .disable_statement_logging(),
+        );
 
     Ok(pool)
 }
 
-pub async fn db_setup() -> color_eyre::Result<()> {
+/// Check out a connection, recording the wait and the pool state in [pool::stats]
+pub async fn acquire(pool: &PgPool) -> sqlx::Result<PoolConnection<Postgres>> {
+    let _span = micrometer::span!("pool_acquire");
+    let stats = pool::stats("postgres");
+    let start = Instant::now();
+    let conn = pool.acquire().await;
+    match &conn {
+        Ok(_) => stats.checkout(start.elapsed(), pool.size() as usize, pool.num_idle()),
+        Err(sqlx::Error::PoolTimedOut) => stats.timed_out(),
+        Err(_) => {}
+    }
+    conn
+}
+
+pub async fn db_setup(dataset: &Dataset, cfg: &ConnectConfig) -> color_eyre::Result<()> {
     let url = std::env::var("DATABASE_URL").context("Missing DATABASE_URL")?;
 
     if !sqlx::Postgres::database_exists(&url).await? {
@@ -34,24 +76,162 @@ pub async fn db_setup() -> color_eyre::Result<()> {
         tracing::info!("database exists");
     }
 
-    let pool = db_init_pool().await?;
-    tracing::info!("populating...");
+    let pool = db_init_pool(cfg).await?;
+    tracing::info!("migrating...");
     migrate!()
         .run(&pool)
         .await
-    .context("Failed to run migration")?;
+        .context("Failed to run migration")?;
+
+    // the marker records which dataset was loaded
+    let marker = format!("{:?}", dataset.config());
+
+    tracing::info!("checking if already populated...");
+    if check_populated(&pool).await?.as_ref() == Some(&marker) {
+        tracing::info!("db already setup with the same dataset, skipping");
+    } else {
+        tracing::info!("populating...");
+        load(&pool, dataset).await?;
+        set_populated(&pool, &marker).await?;
+    }
 
     tracing::info!("init complete");
     Ok(())
 }
 
-pub async fn get_product<'c, E: PgExecutor<'c> + 'c>(db: E, id: i32) -> sqlx::Result<Option<Product>> {
-    sqlx::query_as::<_, Product>("SELECT * FROM product WHERE id = $1")
+/// Replace the content of every table with `dataset`, clearing the marker of the old one
+pub async fn load(pool: &PgPool, dataset: &Dataset) -> color_eyre::Result<()> {
+    sqlx::query("TRUNCATE dataset_marker, product_tag, product, tag, category")
+        .execute(pool)
+        .await?;
+
+    let rows = dataset
+        .categories()
+        .map(|(i, name)| format!("{i}\t{name}\n"));
+    copy_rows(pool, "COPY category (id, name) FROM STDIN", rows).await?;
+    tracing::info!("categories done.");
+
+    let rows = dataset.tags().map(|(i, name)| format!("{i}\t{name}\n"));
+    copy_rows(pool, "COPY tag (id, name) FROM STDIN", rows).await?;
+    tracing::info!("tags done.");
+
+    let rows = dataset.product_ids().map(|i| {
+        let p = dataset.product(i);
+        let description = p.description.as_deref().unwrap_or("\\N");
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            p.id, p.name, description, p.category_id, p.hits
+        )
+    });
+    let n = copy_rows(
+        pool,
+        "COPY product (id, name, description, category_id, hits) FROM STDIN",
+        rows,
+    )
+    .await?;
+    tracing::info!("{n} products done.");
+
+    let rows = dataset.product_ids().flat_map(|i| {
+        dataset
+            .product_tags(i)
+            .into_iter()
+            .map(move |t| format!("{t}\t{i}\n"))
+    });
+    let n = copy_rows(
+        pool,
+        "COPY product_tag (tag_id, product_id) FROM STDIN",
+        rows,
+    )
+    .await?;
+    tracing::info!("{n} product tags done.");
+
+    // COPY with explicit ids leaves the SERIAL sequences behind
+    for table in ["category", "tag", "product"] {
+        sqlx::query(&format!(
+            "SELECT setval(pg_get_serial_sequence('{table}', 'id'), \
+             (SELECT COALESCE(MAX(id), 0) + 1 FROM {table}), false)"
+        ))
+        .execute(pool)
+        .await?;
+    }
+
+    sqlx::query("ANALYZE").execute(pool).await?;
+    Ok(())
+}
+
+async fn set_populated(pool: &PgPool, marker: &str) -> color_eyre::Result<()> {
+    sqlx::query("INSERT INTO dataset_marker (marker) VALUES ($1)")
+        .bind(marker)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+async fn check_populated(pool: &PgPool) -> color_eyre::Result<Option<String>> {
+    let marker = sqlx::query_scalar("SELECT marker FROM dataset_marker")
+        .fetch_optional(pool)
+        .await?;
+    Ok(marker)
+}
+
+async fn copy_rows(
+    pool: &PgPool,
+    statement: &str,
+    rows: impl Iterator<Item = String>,
+) -> color_eyre::Result<u64> {
+    const CHUNK: usize = 1 << 20;
+
+    let mut conn = pool.acquire().await?;
+    let mut copy = conn.copy_in_raw(statement).await?;
+    let mut buf = String::with_capacity(CHUNK);
+    for row in rows {
+        buf.push_str(&row);
+        if buf.len() >= CHUNK {
+            copy.send(buf.as_bytes()).await?;
+            buf.clear();
+        }
+    }
+    if !buf.is_empty() {
+        copy.send(buf.as_bytes()).await?;
+    }
+    Ok(copy.finish().await?)
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "postgres", id = id, rows = Empty)
+)]
+pub async fn get_product<'c, E: PgExecutor<'c> + 'c>(
+    db: E,
+    id: i32,
+) -> sqlx::Result<Option<Product>> {
+    let p = sqlx::query_as::<_, Product>("SELECT * FROM product WHERE id = $1")
         .bind(id)
         .fetch_optional(db)
-        .await
+        .await?;
+    record_rows(p.is_some().into());
+    Ok(p)
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "postgres", ids = ids.len(), rows = Empty)
+)]
+pub async fn get_products_many<'c, E: PgExecutor<'c> + 'c>(
+    db: E,
+    ids: &[i32],
+) -> sqlx::Result<Vec<Option<Product>>> {
+    let found = sqlx::query_as::<_, Product>("SELECT * FROM product WHERE id = ANY($1)")
+        .bind(ids)
+        .fetch_all(db)
+        .await?;
+    record_rows(found.len());
+    Ok(align_products(ids, found))
 }
 
+#[tracing::instrument(level = "debug", skip_all, fields(backend = "postgres", id = p.id))]
 pub async fn mark_hit<'c, E: PgExecutor<'c> + 'c>(db: E, p: &Product) -> sqlx::Result<()> {
     sqlx::query("UPDATE product SET hits = hits + 1 WHERE id = $1")
         .bind(p.id)
@@ -61,22 +241,71 @@ pub async fn mark_hit<'c, E: PgExecutor<'c> + 'c>(db: E, p: &Product) -> sqlx::R
     Ok(())
 }
 
-pub async fn recommend_0<'c, E: PgExecutor<'c> + 'c>(db: E, p: &Product) -> sqlx::Result<Vec<Product>> {
-    sqlx::query_as::<_, Product>(
-        "SELECT * FROM product WHERE category_id = $1 ORDER BY hits DESC LIMIT 5",
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "postgres", id = p.id, category = p.category_id, rows = Empty)
+)]
+pub async fn recommend_0<'c, E: PgExecutor<'c> + 'c>(
+    db: E,
+    p: &Product,
+) -> sqlx::Result<Vec<Product>> {
+    let r = sqlx::query_as::<_, Product>(
+        "SELECT * FROM product WHERE category_id = $1 ORDER BY hits DESC, id LIMIT 5",
     )
     .bind(p.category_id)
     .fetch_all(db)
-    .await
+    .await?;
+    record_rows(r.len());
+    Ok(r)
 }
 
-pub async fn recommend_1<'c, E: PgExecutor<'c> + 'c>(db: E, p: &Product) -> sqlx::Result<Vec<Product>> {
-    sqlx::query_as::<_, Product>(
-        "SELECT p.id, p.name, p.description, p.category_id, p.hits FROM product as p, product_tag as t WHERE
-p.id = t.product_id AND t.tag_id IN (SELECT tag_id FROM tag WHERE product_id = $1)
-ORDER BY p.hits DESC LIMIT 5"
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "postgres", id = p.id, rows = Empty)
+)]
+pub async fn recommend_1<'c, E: PgExecutor<'c> + 'c>(
+    db: E,
+    p: &Product,
+) -> sqlx::Result<Vec<Product>> {
+    let r = sqlx::query_as::<_, Product>(
+        "SELECT * FROM product WHERE id IN (
+    SELECT t.product_id FROM product_tag as t WHERE t.tag_id IN (
+        SELECT tag_id FROM product_tag WHERE product_id = $1))
+ORDER BY hits DESC, id LIMIT 5",
     )
     .bind(p.id)
     .fetch_all(db)
-    .await
+    .await?;
+    record_rows(r.len());
+    Ok(r)
+}
+
+#[async_trait::async_trait]
+impl EnrichBackend for Pool {
+    async fn get_product(&self, id: i32) -> eyre::Result<Option<Product>> {
+        let mut db = acquire(self).await?;
+        Ok(get_product(&mut *db, id).await?)
+    }
+
+    async fn mark_hit(&self, p: &Product) -> eyre::Result<()> {
+        let mut db = acquire(self).await?;
+        Ok(mark_hit(&mut *db, p).await?)
+    }
+
+    async fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        let mut db = acquire(self).await?;
+        Ok(recommend_0(&mut *db, p).await?)
+    }
+
+    async fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        let mut db = acquire(self).await?;
+        Ok(recommend_1(&mut *db, p).await?)
+    }
+
+    async fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        let mut db = acquire(self).await?;
+        Ok(get_products_many(&mut *db, ids).await?)
+    }
 }
@@ -1,25 +1,158 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::time::Instant;
+
 use color_eyre::eyre::Context;
 
-use r2d2_postgres::postgres::NoTls;
+use r2d2_postgres::postgres::types::ToSql;
+use r2d2_postgres::postgres::{NoTls, Row, Statement};
 use r2d2_postgres::PostgresConnectionManager;
 use r2d2_postgres::{postgres, r2d2};
+use tracing::field::Empty;
 
+use super::backend::{align_products, record_rows, ConnectConfig, EnrichBackendBlocking};
+use super::pool;
 use super::types::*;
 
-pub type PgPool = r2d2::Pool<PostgresConnectionManager<NoTls>>;
+pub type PgPool = r2d2::Pool<PgManager>;
 
-pub fn db_init_pool() -> eyre::Result<PgPool> {
+pub fn db_init_pool(cfg: &ConnectConfig) -> eyre::Result<PgPool> {
     let url = std::env::var("DATABASE_URL").context("Missing DATABASE_URL")?;
 
-    let manager = PostgresConnectionManager::new(url.parse().unwrap(), NoTls);
-    let pool = r2d2::Pool::builder()
-        .max_size(16)
-        .min_idle(Some(4))
-        .build_unchecked(manager);
+    let manager = PgManager {
+        inner: PostgresConnectionManager::new(url.parse().unwrap(), NoTls),
+        prepare: !cfg.no_prepare,
+    };
+    let max_size = cfg.pool.max_size(16);
+    let mut builder = r2d2::Pool::builder()
+        .max_size(max_size as u32)
+        .min_idle(Some(cfg.pool.min_idle(max_size) as u32));
+    if let Some(timeout) = cfg.pool.acquire_timeout() {
+        builder = builder.connection_timeout(timeout);
+    }
+    let pool = builder.build_unchecked(manager);
 
     Ok(pool)
 }
 
+/// Check out a connection, recording the wait and the pool state in [pool::stats]
+pub fn checkout(pool: &PgPool) -> Result<r2d2::PooledConnection<PgManager>, r2d2::Error> {
+    let _span = micrometer::span!("pool_acquire");
+    let stats = pool::stats("postgres_blocking");
+    let start = Instant::now();
+    // r2d2 only fails a checkout once the connection timeout is over
+    let conn = pool.get().inspect_err(|_| stats.timed_out())?;
+    let state = pool.state();
+    stats.checkout(
+        start.elapsed(),
+        state.connections as usize,
+        state.idle_connections as usize,
+    );
+    Ok(conn)
+}
+
+/// Opens [Connection]s that keep their prepared statements
+pub struct PgManager {
+    inner: PostgresConnectionManager<NoTls>,
+    prepare: bool,
+}
+
+impl r2d2::ManageConnection for PgManager {
+    type Connection = Connection;
+    type Error = postgres::Error;
+
+    fn connect(&self) -> Result<Connection, postgres::Error> {
+        let client = self.inner.connect()?;
+        pool::stats("postgres_blocking").created();
+        Ok(Connection::new(client, self.prepare))
+    }
+
+    fn is_valid(&self, conn: &mut Connection) -> Result<(), postgres::Error> {
+        self.inner.is_valid(&mut conn.client)
+    }
+
+    fn has_broken(&self, conn: &mut Connection) -> bool {
+        self.inner.has_broken(&mut conn.client)
+    }
+}
+
+/// Client preparing each query once and reusing the statement for the following calls
+pub struct Connection {
+    client: postgres::Client,
+    /// None when every call parses the query again
+    statements: Option<HashMap<&'static str, Statement>>,
+}
+
+impl Connection {
+    pub fn new(client: postgres::Client, prepare: bool) -> Self {
+        Self {
+            client,
+            statements: prepare.then(HashMap::new),
+        }
+    }
+
+    fn statement(&mut self, sql: &'static str) -> Result<Option<Statement>, postgres::Error> {
+        let Some(statements) = &mut self.statements else {
+            return Ok(None);
+        };
+        match statements.get(sql) {
+            Some(stmt) => Ok(Some(stmt.clone())),
+            None => {
+                let stmt = self.client.prepare(sql)?;
+                statements.insert(sql, stmt.clone());
+                Ok(Some(stmt))
+            }
+        }
+    }
+
+    pub fn query(
+        &mut self,
+        sql: &'static str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<Row>, postgres::Error> {
+        match self.statement(sql)? {
+            Some(stmt) => self.client.query(&stmt, params),
+            None => self.client.query(sql, params),
+        }
+    }
+
+    pub fn query_opt(
+        &mut self,
+        sql: &'static str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Option<Row>, postgres::Error> {
+        match self.statement(sql)? {
+            Some(stmt) => self.client.query_opt(&stmt, params),
+            None => self.client.query_opt(sql, params),
+        }
+    }
+
+    pub fn execute(
+        &mut self,
+        sql: &'static str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<u64, postgres::Error> {
+        match self.statement(sql)? {
+            Some(stmt) => self.client.execute(&stmt, params),
+            None => self.client.execute(sql, params),
+        }
+    }
+}
+
+impl Deref for Connection {
+    type Target = postgres::Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.client
+    }
+}
+
 // pub fn db_setup() -> eyre::Result<()> {
 //     let url = std::env::var("DATABASE_URL").context("Missing DATABASE_URL")?;
 
@@ -41,35 +174,96 @@ pub fn db_init_pool() -> eyre::Result<PgPool> {
 //     Ok(())
 // }
 
-pub fn get_product(db: &mut postgres::Client, id: i32) -> Result<Option<Product>, postgres::Error> {
-    db.query_opt("SELECT * FROM product WHERE id = $1", &[&id])
-        .map(|o| o.map(Product::from_pg_row))
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "postgres_blocking", id = id, rows = Empty)
+)]
+pub fn get_product(db: &mut Connection, id: i32) -> Result<Option<Product>, postgres::Error> {
+    let p = db
+        .query_opt("SELECT * FROM product WHERE id = $1", &[&id])?
+        .map(Product::from_pg_row);
+    record_rows(p.is_some().into());
+    Ok(p)
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "postgres_blocking", ids = ids.len(), rows = Empty)
+)]
+pub fn get_products_many(
+    db: &mut Connection,
+    ids: &[i32],
+) -> Result<Vec<Option<Product>>, postgres::Error> {
+    let v = db.query("SELECT * FROM product WHERE id = ANY($1)", &[&ids])?;
+    record_rows(v.len());
+    Ok(align_products(
+        ids,
+        v.into_iter().map(Product::from_pg_row).collect(),
+    ))
 }
 
-pub fn mark_hit(db: &mut postgres::Client, p: &Product) -> Result<(), postgres::Error> {
+#[tracing::instrument(level = "debug", skip_all, fields(backend = "postgres_blocking", id = p.id))]
+pub fn mark_hit(db: &mut Connection, p: &Product) -> Result<(), postgres::Error> {
     db.execute("UPDATE product SET hits = hits + 1 WHERE id = $1", &[&p.id])?;
     Ok(())
 }
 
-pub fn recommend_0(
-    db: &mut postgres::Client,
-    p: &Product,
-) -> Result<Vec<Product>, postgres::Error> {
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "postgres_blocking", id = p.id, category = p.category_id, rows = Empty)
+)]
+pub fn recommend_0(db: &mut Connection, p: &Product) -> Result<Vec<Product>, postgres::Error> {
     let v = db.query(
-        "SELECT * FROM product WHERE category_id = $1 ORDER BY hits DESC LIMIT 5",
+        "SELECT * FROM product WHERE category_id = $1 ORDER BY hits DESC, id LIMIT 5",
         &[&p.category_id],
     )?;
+    record_rows(v.len());
     Ok(v.into_iter().map(Product::from_pg_row).collect())
 }
 
-pub fn recommend_1(
-    db: &mut postgres::Client,
-    p: &Product,
-) -> Result<Vec<Product>, postgres::Error> {
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "postgres_blocking", id = p.id, rows = Empty)
+)]
+pub fn recommend_1(db: &mut Connection, p: &Product) -> Result<Vec<Product>, postgres::Error> {
     let v = db.query(
-        "SELECT p.id, p.name, p.description, p.category_id, p.hits FROM product as p, product_tag as t WHERE
-p.id = t.product_id AND t.tag_id IN (SELECT tag_id FROM tag WHERE product_id = $1)
-ORDER BY p.hits DESC LIMIT 5"
-    , &[&p.id])?;
+        "SELECT * FROM product WHERE id IN (
+    SELECT t.product_id FROM product_tag as t WHERE t.tag_id IN (
+        SELECT tag_id FROM product_tag WHERE product_id = $1))
+ORDER BY hits DESC, id LIMIT 5",
+        &[&p.id],
+    )?;
+    record_rows(v.len());
     Ok(v.into_iter().map(Product::from_pg_row).collect())
 }
+
+impl EnrichBackendBlocking for PgPool {
+    fn get_product(&self, id: i32) -> eyre::Result<Option<Product>> {
+        let mut db = checkout(self)?;
+        Ok(get_product(&mut db, id)?)
+    }
+
+    fn mark_hit(&self, p: &Product) -> eyre::Result<()> {
+        let mut db = checkout(self)?;
+        Ok(mark_hit(&mut db, p)?)
+    }
+
+    fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        let mut db = checkout(self)?;
+        Ok(recommend_0(&mut db, p)?)
+    }
+
+    fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        let mut db = checkout(self)?;
+        Ok(recommend_1(&mut db, p)?)
+    }
+
+    fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        let mut db = checkout(self)?;
+        Ok(get_products_many(&mut db, ids)?)
+    }
+}
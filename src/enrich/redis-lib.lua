@@ -0,0 +1,50 @@
+#!lua name=enrich
+
+-- Increment the hits of a product, keeping the tag rankings in sync.
+-- KEYS[1]: cat:{c}:prod:hits
+-- KEYS[2]: prod:{id}:tags
+-- ARGV[1]: product id
+-- Returns the new hits
+local function mark_hit(keys, args)
+  local hits = redis.call('ZINCRBY', keys[1], 1, args[1])
+  for _, t in ipairs(redis.call('SMEMBERS', keys[2])) do
+    redis.call('ZADD', 'tag:' .. t .. ':prod:hits', hits, args[1])
+  end
+  return hits
+end
+
+-- Top products sharing at least one tag with a product, by hits.
+-- KEYS[1]: prod:{id}:tags
+-- ARGV[1]: number of products to return
+-- Returns a flat array of (product id, hits) pairs
+local function top_by_tags(keys, args)
+  local n = tonumber(args[1])
+  local best = {}
+  for _, t in ipairs(redis.call('SMEMBERS', keys[1])) do
+    local ranked = redis.call('ZRANGE', 'tag:' .. t .. ':prod:hits', 0, n - 1, 'REV', 'WITHSCORES')
+    for i = 1, #ranked, 2 do
+      best[ranked[i]] = tonumber(ranked[i + 1])
+    end
+  end
+
+  local ids = {}
+  for id in pairs(best) do
+    ids[#ids + 1] = id
+  end
+  table.sort(ids, function(a, b)
+    if best[a] ~= best[b] then
+      return best[a] > best[b]
+    end
+    return tonumber(a) < tonumber(b)
+  end)
+
+  local out = {}
+  for i = 1, math.min(n, #ids) do
+    out[#out + 1] = ids[i]
+    out[#out + 1] = best[ids[i]]
+  end
+  return out
+end
+
+redis.register_function('mark_hit', mark_hit)
+redis.register_function('top_by_tags', top_by_tags)
@@ -1,54 +1,81 @@
 use std::future::Future;
+use std::time::Instant;
 
+use deadpool::managed::Hook;
 use deadpool_redis::{
     redis::{self, AsyncCommands},
-    Config, Connection,
+    Config, Connection, Manager, PoolError,
 };
-use rand::{thread_rng, Rng};
 use tokio::task::JoinSet;
+use tracing::field::Empty;
 
-use crate::types::Product;
+use super::backend::{record_rows, ConnectConfig, EnrichBackend};
+use super::dataset::Dataset;
+use super::pool;
+use super::types::Product;
 
 pub use deadpool_redis::Pool;
 
 const REDIS_LIB: &str = include_str!("redis-lib.lua");
 
-async fn populate(pool: &Pool) -> color_eyre::Result<()> {
-    // Generate 100 categories
-    run_many(pool, 1..=100, |mut db: Connection, i| async move {
-        let _: () = db
-            .set(format!("cat:{i}"), format!("Category {}", i))
-            .await
-            .unwrap();
-    })
+async fn populate(pool: &Pool, dataset: &Dataset) -> color_eyre::Result<()> {
+    let mut db = pool.get().await?;
+    let _: () = redis::cmd("FLUSHDB").query_async(&mut db).await?;
+    drop(db);
+
+    let categories = dataset.categories().collect::<Vec<_>>();
+    run_many(
+        pool,
+        1..=categories.len() as i32,
+        |mut db: Connection, i| {
+            let name = categories[i as usize - 1].1.clone();
+            async move {
+                let _: () = db.set(format!("cat:{i}"), name).await.unwrap();
+            }
+        },
+    )
     .await?;
     log::info!("categories done.");
 
-    // // Generate one million products and assign them to a category and random tags
-    run_many(pool, 1..=1_000_000, |mut db: Connection, i| async move {
-        let p = Product {
-            id: i,
-            name: format!("Product {i}"),
-            description: Some(format!("Description of product {i}")),
-            category_id: thread_rng().gen_range(1..=100),
-            hits: 0,
-        };
-        let c = p.category_id;
-        let ser = rmp_serde::to_vec(&p).unwrap();
-
-        let _: () = db.set(format!("prod:{i}"), ser).await.unwrap();
-
-        let _: () = db.zadd(format!("cat:{c}:prod:hits"), i, 0).await.unwrap();
+    let tags = dataset.tags().collect::<Vec<_>>();
+    run_many(pool, 1..=tags.len() as i32, |mut db: Connection, i| {
+        let name = tags[i as usize - 1].1.clone();
+        async move {
+            let _: () = db.set(format!("tag:{i}"), name).await.unwrap();
+        }
+    })
+    .await?;
+    log::info!("tags done.");
+
+    // Products are ranked by hits in their category and in each of their tags
+    run_many(pool, dataset.product_ids(), |mut db: Connection, i| {
+        let p = dataset.product(i);
+        let tags = dataset.product_tags(i);
+        async move {
+            let (c, hits) = (p.category_id, p.hits);
+            let ser = rmp_serde::to_vec(&p).unwrap();
+
+            let mut pipe = redis::pipe();
+            pipe.set(format!("prod:{i}"), ser).ignore();
+            pipe.zadd(format!("cat:{c}:prod:hits"), i, hits).ignore();
+            for &t in &tags {
+                pipe.sadd(format!("prod:{i}:tags"), t).ignore();
+                pipe.zadd(format!("tag:{t}:prod:hits"), i, hits).ignore();
+            }
+            let _: () = pipe.query_async(&mut db).await.unwrap();
+        }
     })
     .await?;
     log::info!("products done.");
+    Ok(())
+}
 
-    log::info!("loading functions...");
-
-    let mut db = pool.get().await.unwrap();
+async fn load_functions(pool: &Pool) -> color_eyre::Result<()> {
+    let mut db = pool.get().await?;
 
     let _: () = redis::cmd("FUNCTION")
         .arg("LOAD")
+        .arg("REPLACE")
         .arg(REDIS_LIB)
         .query_async(&mut db)
         .await?;
@@ -73,62 +100,93 @@ where
             set.join_next().await.unwrap().unwrap();
         }
     }
-    Ok(while let Some(r) = set.join_next().await {
+    while let Some(r) = set.join_next().await {
         r.unwrap();
-    })
+    }
+    Ok(())
 }
 
-pub async fn db_init() -> color_eyre::Result<Pool> {
+pub async fn db_init(cfg: &ConnectConfig) -> color_eyre::Result<Pool> {
     let uri = std::env::var("REDIS_URI").unwrap();
 
-    let cfg = Config::from_url(uri);
-
-    let pool = cfg
+    let pool = Config::from_url(uri)
         .builder()
         .unwrap()
         .runtime(deadpool::Runtime::Tokio1)
-        .max_size(48)
+        .max_size(cfg.pool.max_size(48))
+        .wait_timeout(cfg.pool.acquire_timeout())
+        .post_create(Hook::<Manager>::sync_fn(|_, _| {
+            pool::stats("redis").created();
+            Ok(())
+        }))
         .build()
         .unwrap();
 
     Ok(pool)
 }
 
-pub async fn db_setup() -> color_eyre::Result<()> {
-    let pool = db_init().await?;
+/// Check out a connection, recording the wait and the pool state in [pool::stats]
+async fn checkout(pool: &Pool) -> Result<Connection, PoolError> {
+    let _span = micrometer::span!("pool_acquire");
+    let stats = pool::stats("redis");
+    let start = Instant::now();
+    let conn = pool.get().await;
+    match &conn {
+        Ok(_) => {
+            let status = pool.status();
+            stats.checkout(start.elapsed(), status.size, status.available);
+        }
+        Err(PoolError::Timeout(_)) => stats.timed_out(),
+        Err(_) => {}
+    }
+    conn
+}
+
+pub async fn db_setup(dataset: &Dataset, cfg: &ConnectConfig) -> color_eyre::Result<()> {
+    let pool = db_init(cfg).await?;
+
+    // the marker records which dataset was loaded
+    let marker = format!("{:?}", dataset.config());
 
     log::info!("checking if already populated...");
-    if check_migrated(&pool).await? {
-        log::info!("db already setup, skipping");
-        return Ok(());
+    if check_populated(&pool).await?.as_ref() == Some(&marker) {
+        log::info!("db already setup with the same dataset, skipping");
+    } else {
+        log::info!("populating...");
+        populate(&pool, dataset).await?;
+        set_populated(&pool, &marker).await?;
     }
 
-    log::info!("populating...");
-    populate(&pool).await?;
-    set_migrated(&pool).await?;
+    log::info!("loading functions...");
+    load_functions(&pool).await?;
 
     log::info!("init complete");
     Ok(())
 }
 
-async fn set_migrated(pool: &Pool) -> color_eyre::Result<()> {
+async fn set_populated(pool: &Pool, marker: &str) -> color_eyre::Result<()> {
     let mut db = pool.get().await?;
-    let _: () = db.set("db:ready", true).await?;
+    let _: () = db.set("db:dataset", marker).await?;
     Ok(())
 }
 
-async fn check_migrated(pool: &Pool) -> color_eyre::Result<bool> {
+async fn check_populated(pool: &Pool) -> color_eyre::Result<Option<String>> {
     let mut db = pool.get().await?;
-    let r: Option<bool> = db.get("db:ready").await?;
-    Ok(r.unwrap_or(false))
+    let r: Option<String> = db.get("db:dataset").await?;
+    Ok(r)
 }
 
+#[tracing::instrument(level = "debug", skip_all, fields(backend = "redis", id = id, rows = Empty))]
 pub async fn get_product(db: &Pool, id: i32) -> color_eyre::Result<Option<Product>> {
-    let mut db = db.get().await?;
+    let mut db = checkout(db).await?;
     let q: Option<Vec<u8>> = db.get(format!("prod:{id}")).await?;
 
-    let Some(mut p) = q.map(|v| rmp_serde::from_slice::<Product>(&v).unwrap()) else {
+    let Some(mut p) = q
+        .map(|v| rmp_serde::from_slice::<Product>(&v))
+        .transpose()?
+    else {
         log::warn!("product not found!");
+        record_rows(0);
         return Ok(None);
     };
 
@@ -137,17 +195,15 @@ pub async fn get_product(db: &Pool, id: i32) -> color_eyre::Result<Option<Produc
         .await?;
     p.hits = score as i64;
 
+    record_rows(1);
     Ok(Some(p))
 }
 
 pub async fn mget_product(db: &Pool, cat: i32, ids: &[i32]) -> color_eyre::Result<Vec<Product>> {
     assert!(!ids.is_empty(), "ids is empty!");
-    let mut db = db.get().await?;
+    let mut db = checkout(db).await?;
 
-    let keys = ids
-        .into_iter()
-        .map(|i| format!("prod:{i}"))
-        .collect::<Vec<_>>();
+    let keys = ids.iter().map(|i| format!("prod:{i}")).collect::<Vec<_>>();
 
     let (ser, scores): (Vec<Vec<u8>>, Vec<f32>) = redis::pipe()
         .mget(keys)
@@ -155,33 +211,81 @@ pub async fn mget_product(db: &Pool, cat: i32, ids: &[i32]) -> color_eyre::Resul
         .query_async(&mut db)
         .await?;
 
-    let r: Vec<Product> = ser
+    let r = ser
         .into_iter()
-        .zip(scores.into_iter())
+        .zip(scores)
         .map(|(b, s)| {
-            let mut p = rmp_serde::from_slice::<Product>(&b).unwrap();
+            let mut p = rmp_serde::from_slice::<Product>(&b)?;
             p.hits = s as i64;
-            p
+            Ok(p)
         })
-        .collect();
+        .collect::<color_eyre::Result<Vec<_>>>()?;
+
+    Ok(r)
+}
+
+/// Products of any category, in two round trips: the serialized products, then the hits
+/// from the category rankings in a single pipeline
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "redis", ids = ids.len(), rows = Empty)
+)]
+pub async fn get_products_many(db: &Pool, ids: &[i32]) -> color_eyre::Result<Vec<Option<Product>>> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut db = checkout(db).await?;
+
+    let keys = ids.iter().map(|i| format!("prod:{i}")).collect::<Vec<_>>();
+    let ser: Vec<Option<Vec<u8>>> = redis::cmd("MGET").arg(keys).query_async(&mut db).await?;
+    let mut r = ser
+        .into_iter()
+        .map(|b| b.map(|b| rmp_serde::from_slice::<Product>(&b)).transpose())
+        .collect::<Result<Vec<_>, _>>()?;
+    record_rows(r.iter().flatten().count());
+
+    let mut pipe = redis::pipe();
+    for p in r.iter().flatten() {
+        pipe.zscore(format!("cat:{}:prod:hits", p.category_id), p.id);
+    }
+    if r.iter().any(Option::is_some) {
+        let scores: Vec<f32> = pipe.query_async(&mut db).await?;
+        for (p, s) in r.iter_mut().flatten().zip(scores) {
+            p.hits = s as i64;
+        }
+    }
 
     Ok(r)
 }
 
+#[tracing::instrument(level = "debug", skip_all, fields(backend = "redis", id = p.id))]
 pub async fn mark_hit(db: &Pool, p: &Product) -> color_eyre::Result<()> {
-    let mut db = db.get().await?;
+    let mut db = checkout(db).await?;
 
     let Product {
         category_id: c, id, ..
     } = p;
-    let q: f32 = db.zincr(format!("cat:{c}:prod:hits"), id, 1).await?;
+    let q: f32 = redis::cmd("FCALL")
+        .arg("mark_hit")
+        .arg(2)
+        .arg(format!("cat:{c}:prod:hits"))
+        .arg(format!("prod:{id}:tags"))
+        .arg(id)
+        .query_async(&mut db)
+        .await?;
     log::debug!("incresed {} to {q:.1}", p.id);
 
     Ok(())
 }
 
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "redis", id = p.id, category = p.category_id, rows = Empty)
+)]
 pub async fn recommend_0(pool: &Pool, p: &Product) -> color_eyre::Result<Vec<Product>> {
-    let mut db = pool.get().await?;
+    let mut db = checkout(pool).await?;
     let c = p.category_id;
 
     let r: Vec<i32> = redis::cmd("ZRANGE")
@@ -190,6 +294,7 @@ pub async fn recommend_0(pool: &Pool, p: &Product) -> color_eyre::Result<Vec<Pro
         .arg("REV")
         .query_async(&mut db)
         .await?;
+    record_rows(r.len());
     if !r.is_empty() {
         drop(db);
         mget_product(pool, p.category_id, r.as_ref()).await
@@ -199,14 +304,64 @@ pub async fn recommend_0(pool: &Pool, p: &Product) -> color_eyre::Result<Vec<Pro
     }
 }
 
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "redis", id = p.id, rows = Empty)
+)]
+pub async fn recommend_1(pool: &Pool, p: &Product) -> color_eyre::Result<Vec<Product>> {
+    let mut db = checkout(pool).await?;
+
+    let top: Vec<(i32, i64)> = redis::cmd("FCALL")
+        .arg("top_by_tags")
+        .arg(1)
+        .arg(format!("prod:{}:tags", p.id))
+        .arg(5)
+        .query_async(&mut db)
+        .await?;
+    record_rows(top.len());
+    if top.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let keys = top
+        .iter()
+        .map(|(i, _)| format!("prod:{i}"))
+        .collect::<Vec<_>>();
+    let ser: Vec<Vec<u8>> = redis::cmd("MGET").arg(keys).query_async(&mut db).await?;
+
+    let r = ser
+        .into_iter()
+        .zip(top)
+        .map(|(b, (_, hits))| {
+            let mut p = rmp_serde::from_slice::<Product>(&b)?;
+            p.hits = hits;
+            Ok(p)
+        })
+        .collect::<color_eyre::Result<Vec<_>>>()?;
+
+    Ok(r)
+}
+
+#[async_trait::async_trait]
+impl EnrichBackend for Pool {
+    async fn get_product(&self, id: i32) -> eyre::Result<Option<Product>> {
+        get_product(self, id).await
+    }
 
+    async fn mark_hit(&self, p: &Product) -> eyre::Result<()> {
+        mark_hit(self, p).await
+    }
 
-// pub async fn recommend_1(db: &Pool, p: &Product) -> color_eyre::Result<Vec<Product>> {
-//     let db = db.get().await?;
-//     let q1 = db.prepare("SELECT tag_id FROM ks.product_tag WHERE product_id = ?").await?;
-//     let q2 = db.prepare("SELECT product_id FROM ks.product_tag as t WHERE tag_id IN ? ORDER BY p.hits DESC LIMIT 5")
-//     let r = db.execute(&q1, (p.id,)).await?;
+    async fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        recommend_0(self, p).await
+    }
 
-//     let p = r.rows_typed_or_empty::<Product>().map(Result::unwrap).collect();
-//     Ok(p)
-// }
+    async fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        recommend_1(self, p).await
+    }
+
+    async fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        get_products_many(self, ids).await
+    }
+}
@@ -1,15 +1,26 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use deadpool::managed::{Object, PoolError};
 use futures::StreamExt;
-use rand::prelude::*;
 use scylla::statement::Consistency;
+use scylla::transport::errors::NewSessionError;
 use scylla::transport::query_result::RowsExpectedError;
 use scylla::transport::session::Session;
 use scylla::transport::Compression;
 use scylla::{FromRow, QueryResult, SessionBuilder};
+use tracing::field::Empty;
 
-use crate::types::*;
+use super::backend::{align_products, record_rows, ConnectConfig, EnrichBackend};
+use super::dataset::Dataset;
+use super::types::*;
 
 pub type Pool = ScyllaPool;
 
+/// LWT conflicts hit by `mark_hit` in this process
+static WRITE_RETRIES: AtomicU64 = AtomicU64::new(0);
+
 use self::pool::{Connection, ScyllaManager, ScyllaPool};
 
 const MIGRATIONS: &[&str] = &[
@@ -38,6 +49,24 @@ const MIGRATIONS: &[&str] = &[
     "CREATE MATERIALIZED VIEW ks.product_score AS SELECT * FROM ks.cat_score
     WHERE category_id IS NOT NULL AND product_id IS NOT NULL AND score IS NOT NULL
     primary key (category_id, score, product_id);
+",
+    "DROP TABLE IF EXISTS ks.product_tag;",
+    "CREATE TABLE ks.product_tag (
+  product_id INT,
+  tag_id INT,
+  PRIMARY KEY(product_id, tag_id)
+);",
+    "DROP MATERIALIZED VIEW IF EXISTS ks.tag_product_score;",
+    "DROP TABLE IF EXISTS ks.tag_score;",
+    "CREATE TABLE ks.tag_score (
+  tag_id INT,
+  score FLOAT,
+  product_id INT,
+  PRIMARY KEY(tag_id, product_id)
+);",
+    "CREATE MATERIALIZED VIEW ks.tag_product_score AS SELECT * FROM ks.tag_score
+    WHERE tag_id IS NOT NULL AND product_id IS NOT NULL AND score IS NOT NULL
+    primary key (tag_id, score, product_id);
 ",
 ];
 
@@ -56,8 +85,8 @@ replication = {'class' : 'SimpleStrategy', 'replication_factor' : 1}",
     Ok(())
 }
 
-async fn populate(pool: &ScyllaPool) -> color_eyre::Result<()> {
-    async fn make_category(pool: ScyllaPool, i: i32) {
+async fn populate(pool: &ScyllaPool, dataset: &Dataset) -> color_eyre::Result<()> {
+    async fn make_category(pool: ScyllaPool, i: i32, name: String) {
         let mut conn = pool.get().await.unwrap();
         let mut q = conn
             .prepare("INSERT INTO ks.category (id, name) VALUES (?, ?)")
@@ -65,14 +94,10 @@ async fn populate(pool: &ScyllaPool) -> color_eyre::Result<()> {
             .unwrap();
         q.set_consistency(Consistency::Any);
 
-        conn.execute(&q, (i, format!("Category {}", i)))
-            .await
-            .unwrap();
+        conn.execute(&q, (i, name)).await.unwrap();
     }
 
-    async fn make_product(pool: ScyllaPool, i: i32) {
-        let category_id = thread_rng().gen_range(1..=100);
-
+    async fn make_product(pool: ScyllaPool, p: Product, tags: Vec<i32>) {
         let mut conn = pool.get().await.unwrap();
         let mut q = conn
             .prepare(
@@ -81,49 +106,65 @@ async fn populate(pool: &ScyllaPool) -> color_eyre::Result<()> {
             .await
             .unwrap();
         q.set_consistency(Consistency::Any);
-        conn.execute(
-            &q,
-            (
-                i,
-                format!("Product {}", i),
-                format!("Description for Product {}", i),
-                category_id,
-            ),
-        )
-        .await
-        .unwrap()
-        .result_not_rows()
-        .unwrap();
+        conn.execute(&q, (p.id, p.name, p.description, p.category_id))
+            .await
+            .unwrap()
+            .result_not_rows()
+            .unwrap();
 
+        let score = p.hits as f32;
         let mut q = conn
             .prepare("INSERT INTO ks.cat_score (category_id, score, product_id) VALUES (?, ?, ?)")
             .await
             .unwrap();
         q.set_consistency(Consistency::Any);
 
-        conn.execute(&q, (category_id, 0.0f32, i))
+        conn.execute(&q, (p.category_id, score, p.id))
             .await
             .unwrap()
             .result_not_rows()
             .unwrap();
+
+        let mut q_tag = conn
+            .prepare("INSERT INTO ks.product_tag (product_id, tag_id) VALUES (?, ?)")
+            .await
+            .unwrap();
+        q_tag.set_consistency(Consistency::Any);
+        let mut q_score = conn
+            .prepare("INSERT INTO ks.tag_score (tag_id, score, product_id) VALUES (?, ?, ?)")
+            .await
+            .unwrap();
+        q_score.set_consistency(Consistency::Any);
+
+        for t in tags {
+            conn.execute(&q_tag, (p.id, t))
+                .await
+                .unwrap()
+                .result_not_rows()
+                .unwrap();
+            conn.execute(&q_score, (t, score, p.id))
+                .await
+                .unwrap()
+                .result_not_rows()
+                .unwrap();
+        }
     }
 
-    // Generate 100 categories
     let pool1 = pool.clone();
-    futures::stream::iter((1..=100).map(|i| {
+    futures::stream::iter(dataset.categories().map(|(i, name)| {
         let pool = pool1.clone();
-        make_category(pool, i)
+        make_category(pool, i, name)
     }))
     .buffer_unordered(pool.status().max_size)
     .count()
     .await;
     log::info!("categories done.");
 
-    // Generate one million products and assign them to a category
+    // Products are ranked by hits in their category and in each of their tags
     let pool1 = pool.clone();
-    futures::stream::iter((1..=1_000_000).map(|i| {
+    futures::stream::iter(dataset.product_ids().map(|i| {
         let pool = pool1.clone();
-        make_product(pool, i)
+        make_product(pool, dataset.product(i), dataset.product_tags(i))
     }))
     .buffer_unordered(pool.status().max_size)
     .count()
@@ -133,18 +174,37 @@ async fn populate(pool: &ScyllaPool) -> color_eyre::Result<()> {
     Ok(())
 }
 
-pub async fn db_init() -> color_eyre::Result<ScyllaPool> {
+pub async fn db_init(cfg: &ConnectConfig) -> color_eyre::Result<ScyllaPool> {
     let uri = std::env::var("SCYLLA_URI").unwrap_or_else(|_| "127.0.0.1:9042".to_string());
 
     let pool = ScyllaPool::builder(ScyllaManager { uri })
-        .max_size(64)
+        .runtime(deadpool::Runtime::Tokio1)
+        .max_size(cfg.pool.max_size(64))
+        .wait_timeout(cfg.pool.acquire_timeout())
         .build()
         .unwrap();
 
     Ok(pool)
 }
 
-pub async fn db_setup() -> color_eyre::Result<()> {
+/// Check out a session, recording the wait and the pool state in [super::pool::stats]
+async fn checkout(pool: &ScyllaPool) -> Result<Object<ScyllaManager>, PoolError<NewSessionError>> {
+    let _span = micrometer::span!("pool_acquire");
+    let stats = super::pool::stats("scylla");
+    let start = Instant::now();
+    let conn = pool.get().await;
+    match &conn {
+        Ok(_) => {
+            let status = pool.status();
+            stats.checkout(start.elapsed(), status.size, status.available);
+        }
+        Err(PoolError::Timeout(_)) => stats.timed_out(),
+        Err(_) => {}
+    }
+    conn
+}
+
+pub async fn db_setup(dataset: &Dataset, cfg: &ConnectConfig) -> color_eyre::Result<()> {
     let uri = std::env::var("SCYLLA_URI").unwrap_or_else(|_| "127.0.0.1:9042".to_string());
 
     let db: Session = SessionBuilder::new()
@@ -157,13 +217,10 @@ pub async fn db_setup() -> color_eyre::Result<()> {
         log::info!("init start");
         migrate(&db).await?;
 
-        let pool = ScyllaPool::builder(ScyllaManager { uri })
-            .max_size(48)
-            .build()
-            .unwrap();
+        let pool = db_init(cfg).await?;
 
         log::info!("populating...");
-        populate(&pool).await?;
+        populate(&pool, dataset).await?;
     } else {
         log::info!("init skipped. set SCYLLA_INIT=1 to perform init");
     }
@@ -180,12 +237,18 @@ struct ProductRaw {
     category_id: i32,
 }
 
+#[tracing::instrument(level = "debug", skip_all, fields(backend = "scylla", id = id, rows = Empty))]
 pub async fn get_product(db: &ScyllaPool, id: i32) -> color_eyre::Result<Option<Product>> {
-    let mut db = db.get().await?;
+    let mut db = checkout(db).await?;
     let q = db
         .prepare("SELECT id, name, description, category_id FROM ks.product WHERE id = ?")
         .await?;
-    let Some(r1) = db.execute(&q, (id,)).await?.maybe_first_row_typed::<ProductRaw>()? else {
+    let Some(r1) = db
+        .execute(&q, (id,))
+        .await?
+        .maybe_first_row_typed::<ProductRaw>()?
+    else {
+        record_rows(0);
         return Ok(None);
     };
 
@@ -200,15 +263,68 @@ pub async fn get_product(db: &ScyllaPool, id: i32) -> color_eyre::Result<Option<
         hits: r2 as i64,
     };
 
+    record_rows(1);
     Ok(Some(p))
 }
 
+/// Products in a single `IN` query, the scores are read with concurrent prepared executes
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "scylla", ids = ids.len(), rows = Empty)
+)]
+pub async fn get_products_many(
+    pool: &ScyllaPool,
+    ids: &[i32],
+) -> color_eyre::Result<Vec<Option<Product>>> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut db = checkout(pool).await?;
+    let q = db
+        .prepare("SELECT id, name, description, category_id FROM ks.product WHERE id IN ?")
+        .await?;
+    let mut q_score = db
+        .prepare("SELECT score FROM ks.cat_score WHERE category_id = ? AND product_id = ?")
+        .await?;
+    q_score.set_consistency(Consistency::Quorum);
+
+    let rows = db
+        .execute(&q, (ids.to_vec(),))
+        .await?
+        .rows_typed::<ProductRaw>()?
+        .collect::<Result<Vec<_>, _>>()?;
+    record_rows(rows.len());
+
+    let session: &Session = &db;
+    let scores = futures::future::try_join_all(
+        rows.iter()
+            .map(|r| session.execute(&q_score, (r.category_id, r.id))),
+    )
+    .await?;
+
+    let mut found = Vec::with_capacity(rows.len());
+    for (r1, r2) in rows.into_iter().zip(scores) {
+        let Some((score,)) = r2.maybe_first_row_typed::<(f32,)>()? else {
+            color_eyre::eyre::bail!("missing score for product {}", r1.id);
+        };
+        found.push(Product {
+            id: r1.id,
+            name: r1.name,
+            description: r1.decscription,
+            category_id: r1.category_id,
+            hits: score as i64,
+        });
+    }
+
+    Ok(align_products(ids, found))
+}
+
 async fn get_product_score(
     db: &mut Connection,
     category_id: i32,
     product_id: i32,
 ) -> Result<f32, color_eyre::Report> {
-    assert!((1..=1_000_000).contains(&product_id));
     let mut q = db
         .prepare("SELECT score FROM ks.cat_score WHERE category_id = ? AND product_id = ?")
         .await?;
@@ -242,8 +358,13 @@ fn check_lwt(r: QueryResult) -> Result<bool, RowsExpectedError> {
     Ok(true)
 }
 
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "scylla", id = p.id, retries = Empty)
+)]
 pub async fn mark_hit(db: &ScyllaPool, p: &Product) -> color_eyre::Result<()> {
-    let mut db = db.get().await?;
+    let mut db = checkout(db).await?;
 
     // let q_del = db
     //     .prepare("DELETE FROM ks.cat_score WHERE category_id = ? AND product_id = ? AND score = ? IF EXISTS").await?;
@@ -252,15 +373,14 @@ pub async fn mark_hit(db: &ScyllaPool, p: &Product) -> color_eyre::Result<()> {
     let q_upd = db
         .prepare("UPDATE ks.cat_score SET score = ? WHERE product_id = ? AND category_id = ? IF score = ?").await?;
 
-
     // let mut batch = Batch::default();
     // batch.append_statement(q_del);
     // batch.append_statement(q_ins);
     // batch.set_consistency(Consistency::Quorum);
     // batch.set_serial_consistency(Some(SerialConsistency::Serial));
-    
+
     let mut i = 0;
-    loop {
+    let score = loop {
         let score = get_product_score(&mut db, p.category_id, p.id).await?;
 
         let result = db
@@ -277,8 +397,9 @@ pub async fn mark_hit(db: &ScyllaPool, p: &Product) -> color_eyre::Result<()> {
         log::debug!("batch statement result: {:?}", result);
 
         if check_lwt(result)? {
-            break;
+            break score + 1.0;
         } else {
+            WRITE_RETRIES.fetch_add(1, Ordering::Relaxed);
             log::warn!(
                 "conflict updating score for {:5}({:4}), updating ({i:3})",
                 p.id,
@@ -286,12 +407,39 @@ pub async fn mark_hit(db: &ScyllaPool, p: &Product) -> color_eyre::Result<()> {
             );
         }
         i += 1;
+    };
+    tracing::Span::current().record("retries", i);
+
+    // tag rankings follow the category score, concurrent hits on the same product are last write wins
+    let q_tag = db
+        .prepare("UPDATE ks.tag_score SET score = ? WHERE tag_id = ? AND product_id = ?")
+        .await?;
+    for t in get_product_tags(&mut db, p.id).await? {
+        db.execute(&q_tag, (score, t, p.id)).await?;
     }
     Ok(())
 }
 
+async fn get_product_tags(db: &mut Connection, product_id: i32) -> color_eyre::Result<Vec<i32>> {
+    let q = db
+        .prepare("SELECT tag_id FROM ks.product_tag WHERE product_id = ?")
+        .await?;
+    let r = db
+        .execute(&q, (product_id,))
+        .await?
+        .rows_typed::<(i32,)>()?
+        .map(|r| r.map(|q| q.0))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(r)
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "scylla", id = p.id, category = p.category_id, rows = Empty)
+)]
 pub async fn recommend_0(pool: &ScyllaPool, p: &Product) -> color_eyre::Result<Vec<Product>> {
-    let mut db = pool.get().await?;
+    let mut db = checkout(pool).await?;
     let q = db
         .prepare(
             "SELECT product_id FROM ks.product_score WHERE category_id = ? ORDER BY score DESC LIMIT 5",
@@ -303,24 +451,82 @@ pub async fn recommend_0(pool: &ScyllaPool, p: &Product) -> color_eyre::Result<V
         .rows_typed::<(i32,)>()?
         .map(|r| r.map(|q| q.0))
         .collect::<Result<Vec<_>, _>>()?;
+    record_rows(r.len());
+    drop(db);
 
-    let mut res = Vec::with_capacity(r.len());
-    for id in r {
-        res.push(get_product(pool, id).await?.unwrap());
+    resolve(pool, &r).await
+}
+
+#[tracing::instrument(
+    level = "debug",
+    skip_all,
+    fields(backend = "scylla", id = p.id, rows = Empty)
+)]
+pub async fn recommend_1(pool: &ScyllaPool, p: &Product) -> color_eyre::Result<Vec<Product>> {
+    let mut db = checkout(pool).await?;
+    let q = db
+        .prepare(
+            "SELECT product_id, score FROM ks.tag_product_score WHERE tag_id = ? ORDER BY score DESC LIMIT 5",
+        )
+        .await?;
+
+    // the top 5 of the union is always contained in the union of the top 5 of each tag
+    let mut best = HashMap::new();
+    for t in get_product_tags(&mut db, p.id).await? {
+        for r in db.execute(&q, (t,)).await?.rows_typed::<(i32, f32)>()? {
+            let (id, score) = r?;
+            best.insert(id, score);
+        }
     }
+    drop(db);
 
-    Ok(res)
+    let mut top = best.into_iter().collect::<Vec<_>>();
+    top.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    top.truncate(5);
+
+    let ids = top.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+    record_rows(ids.len());
+    resolve(pool, &ids).await
 }
 
-// pub async fn recommend_1(db: &ScyllaPool, p: &Product) -> color_eyre::Result<Vec<Product>> {
-//     let db = db.get().await?;
-//     let q1 = db.prepare("SELECT tag_id FROM ks.product_tag WHERE product_id = ?").await?;
-//     let q2 = db.prepare("SELECT product_id FROM ks.product_tag as t WHERE tag_id IN ? ORDER BY p.score DESC LIMIT 5")
-//     let r = db.execute(&q1, (p.id,)).await?;
+/// Products of a ranking, which must all exist
+async fn resolve(pool: &ScyllaPool, ids: &[i32]) -> color_eyre::Result<Vec<Product>> {
+    get_products_many(pool, ids)
+        .await?
+        .into_iter()
+        .zip(ids)
+        .map(|(p, id)| {
+            p.ok_or_else(|| color_eyre::eyre::eyre!("recommended product {id} not found"))
+        })
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl EnrichBackend for ScyllaPool {
+    async fn get_product(&self, id: i32) -> eyre::Result<Option<Product>> {
+        get_product(self, id).await
+    }
+
+    async fn mark_hit(&self, p: &Product) -> eyre::Result<()> {
+        mark_hit(self, p).await
+    }
+
+    async fn recommend_0(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        recommend_0(self, p).await
+    }
+
+    async fn recommend_1(&self, p: &Product) -> eyre::Result<Vec<Product>> {
+        recommend_1(self, p).await
+    }
+
+    async fn get_products_many(&self, ids: &[i32]) -> eyre::Result<Vec<Option<Product>>> {
+        get_products_many(self, ids).await
+    }
 
-//     let p = r.rows_typed_or_empty::<Product>().map(Result::unwrap).collect();
-//     Ok(p)
-// }
+    fn write_retries(&self) -> u64 {
+        WRITE_RETRIES.load(Ordering::Relaxed)
+    }
+}
 
 pub mod pool {
     use std::ops::{Deref, DerefMut};
@@ -347,14 +553,12 @@ pub mod pool {
             &mut self,
             stmt: &'static str,
         ) -> Result<PreparedStatement, QueryError> {
-            match self.cache.get_mut(stmt) {
-                Some(stmt) => Ok(stmt.clone()),
-                None => {
-                    let p = self.session.prepare(stmt).await?;
-                    self.cache.insert(stmt, p.clone());
-                    Ok(p)
-                }
+            if let Some(p) = self.cache.get(stmt) {
+                return Ok(p.clone());
             }
+            let p = self.session.prepare(stmt).await?;
+            self.cache.insert(stmt, p.clone());
+            Ok(p)
         }
     }
 
@@ -384,11 +588,16 @@ pub mod pool {
                 .build()
                 .await?;
 
+            crate::enrich::pool::stats("scylla").created();
             let cache = Cache::new(100);
             Ok(Connection { session, cache })
         }
 
-        async fn recycle(&self, _: &mut Self::Type) -> managed::RecycleResult<Self::Error> {
+        async fn recycle(
+            &self,
+            _: &mut Self::Type,
+            _: &managed::Metrics,
+        ) -> managed::RecycleResult<Self::Error> {
             Ok(())
         }
     }
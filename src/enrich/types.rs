@@ -8,7 +8,7 @@ pub struct Category {
     pub parent_category_id: Option<i32>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, FromSqlxRow)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromSqlxRow)]
 pub struct Product {
     pub id: i32,
     pub name: String,
@@ -0,0 +1,199 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+
+use super::backend::{EnrichBackendBlocking, Recommender};
+use super::dataset::{Dataset, DatasetConfig};
+use super::memory::{MemoryBackend, MemoryConfig};
+use super::types::Product;
+
+/// Mismatches logged with their details, the following ones are only counted
+const LOGGED: u64 = 10;
+
+/// Differences with the reference, from the first one checked
+const KINDS: [&str; 5] = ["product", "stale", "ineligible", "length", "ranking"];
+
+/// Reference of this process, set by [VerifyConfig::init]
+static REFERENCE: OnceCell<Reference> = OnceCell::new();
+
+static STATS: Lazy<Stats> = Lazy::new(|| Stats {
+    checked: AtomicU64::new(0),
+    mismatches: KINDS.iter().map(|&k| (k, AtomicU64::new(0))).collect(),
+});
+
+/// Correctness check of the pipeline outputs, flatten it in the binary options
+#[derive(Debug, Clone, clap::Args, Serialize)]
+pub struct VerifyConfig {
+    /// Compare every recommendation to a reference computed from the dataset given to the
+    /// connection. The store must hold the dataset loaded by the setup binary, without the
+    /// hits of earlier runs
+    #[clap(long)]
+    pub verify: bool,
+}
+
+impl VerifyConfig {
+    /// Generate the reference of this process when `--verify` is given, the outputs passed to
+    /// [check] are compared to it from then on
+    pub fn init(&self, dataset: &DatasetConfig, recommender: Recommender) -> eyre::Result<()> {
+        if !self.verify {
+            return Ok(());
+        }
+        log::info!(
+            "generating the reference of {} products...",
+            dataset.products
+        );
+        let rankings = MemoryBackend::new(dataset, &MemoryConfig::default())?;
+        let reference = Reference {
+            dataset: Dataset::new(dataset.clone())?,
+            rankings,
+            recommender,
+        };
+        REFERENCE.set(reference).ok();
+        Ok(())
+    }
+}
+
+/// Compare the recommendations of `p` to the reference, without a reference it does nothing
+pub fn check(p: &Product, rec: &[Product]) {
+    let Some(reference) = REFERENCE.get() else {
+        return;
+    };
+    STATS.checked.fetch_add(1, Ordering::Relaxed);
+    if let Err((kind, detail)) = reference.compare(p, rec) {
+        let n = STATS.mismatches[kind].fetch_add(1, Ordering::Relaxed) + 1;
+        if n <= LOGGED {
+            log::warn!("verify: {kind} mismatch for product {}: {detail}", p.id);
+        }
+    }
+}
+
+/// Products and rankings of the dataset as loaded, before any hit
+struct Reference {
+    dataset: Dataset,
+    rankings: MemoryBackend,
+    recommender: Recommender,
+}
+
+impl Reference {
+    /// First difference between the output and the reference.
+    ///
+    /// Ranks are compared by their hits, then the products ranked above the hits of the
+    /// last one by their ids. The reference ranks equally hit products by id like Postgres,
+    /// Redis and Scylla order and cut them otherwise: their order is not compared, and any
+    /// eligible product with the hits of the last rank is accepted for it
+    fn compare(&self, p: &Product, rec: &[Product]) -> Result<(), (&'static str, String)> {
+        self.compare_product(p).map_err(|d| ("product", d))?;
+
+        let mut seen = HashSet::new();
+        for r in rec {
+            self.compare_product(r).map_err(|d| ("stale", d))?;
+            if !seen.insert(r.id) {
+                return Err(("ineligible", format!("{} is recommended twice", r.id)));
+            }
+            if !self.eligible(p, r) {
+                return Err(("ineligible", format!("{} is not related", r.id)));
+            }
+        }
+
+        let want = self
+            .rankings
+            .recommend(p, self.recommender)
+            .expect("the memory backend does not fail");
+        if rec.len() != want.len() {
+            return Err((
+                "length",
+                format!("{} products instead of {}", rec.len(), want.len()),
+            ));
+        }
+        let hits = |v: &[Product]| v.iter().map(|p| p.hits).collect::<Vec<_>>();
+        if hits(rec) != hits(&want) {
+            return Err((
+                "ranking",
+                format!("hits {:?} instead of {:?}", hits(rec), hits(&want)),
+            ));
+        }
+        let cut = want.last().map(|p| p.hits);
+        let above = |v: &[Product]| {
+            let mut ids = v
+                .iter()
+                .filter(|p| Some(p.hits) != cut)
+                .map(|p| (Reverse(p.hits), p.id))
+                .collect::<Vec<_>>();
+            ids.sort_unstable();
+            ids.into_iter().map(|(_, id)| id).collect::<Vec<_>>()
+        };
+        if above(rec) != above(&want) {
+            return Err((
+                "ranking",
+                format!("ids {:?} instead of {:?}", above(rec), above(&want)),
+            ));
+        }
+        Ok(())
+    }
+
+    fn compare_product(&self, p: &Product) -> Result<(), String> {
+        if !self.dataset.product_ids().contains(&p.id) {
+            return Err(format!("{} is not in the dataset", p.id));
+        }
+        let want = self.dataset.product(p.id);
+        match *p == want {
+            true => Ok(()),
+            false => Err(format!("{p:?} instead of {want:?}")),
+        }
+    }
+
+    /// Whether `r` can be recommended for `p` by the recommender
+    fn eligible(&self, p: &Product, r: &Product) -> bool {
+        match self.recommender {
+            Recommender::Category => r.category_id == p.category_id,
+            Recommender::Tags => {
+                let tags = self.dataset.product_tags(p.id);
+                self.dataset
+                    .product_tags(r.id)
+                    .iter()
+                    .any(|t| tags.contains(t))
+            }
+        }
+    }
+}
+
+struct Stats {
+    checked: AtomicU64,
+    mismatches: BTreeMap<&'static str, AtomicU64>,
+}
+
+/// Outputs of the run compared to the reference
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VerifySummary {
+    pub checked: u64,
+    /// Outputs differing from the reference, by their first difference
+    pub mismatches: BTreeMap<String, u64>,
+}
+
+impl VerifySummary {
+    /// Outputs with any difference
+    pub fn mismatched(&self) -> u64 {
+        self.mismatches.values().sum()
+    }
+}
+
+/// Outputs checked by this process so far, none without `--verify`
+pub fn summary() -> Option<VerifySummary> {
+    REFERENCE.get()?;
+    Some(VerifySummary {
+        checked: STATS.checked.load(Ordering::Relaxed),
+        mismatches: STATS
+            .mismatches
+            .iter()
+            .map(|(&k, n)| (k.to_string(), n.load(Ordering::Relaxed)))
+            .collect(),
+    })
+}
+
+/// [summary] as a JSON object
+pub fn summary_json() -> String {
+    serde_json::to_string(&summary()).unwrap()
+}
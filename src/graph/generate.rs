@@ -0,0 +1,266 @@
+use rand::prelude::*;
+use rand_distr::Geometric;
+use serde::Serialize;
+
+/// Random graph model of `--generate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GraphModel {
+    /// Recursive matrix (Kronecker) graph, with skewed degrees and nested communities
+    Rmat,
+    /// Every ordered pair of distinct nodes is linked with the same probability
+    ErdosRenyi,
+    /// Preferential attachment, every node links `degree` earlier nodes picked by degree
+    BarabasiAlbert,
+    /// Square lattice, every node links its four neighbours
+    Grid,
+}
+
+/// Synthetic graph replacing the input files, see [super::input::GraphArgs]
+#[derive(Debug, Clone, clap::Args, Serialize)]
+pub struct GenerateArgs {
+    /// Generate the graph instead of reading the nodes and edges files
+    #[clap(long, value_enum, conflicts_with_all = ["nodes_path", "edges_path", "nodes"])]
+    pub generate: Option<GraphModel>,
+
+    /// Nodes of the generated graph, R-MAT rounds them up to a power of two and grid to a
+    /// square
+    #[clap(long, default_value_t = 1 << 20)]
+    pub graph_nodes: u64,
+
+    /// Average out-degree of R-MAT and Erdős–Rényi, links of every new Barabási–Albert node
+    #[clap(long, default_value_t = 16)]
+    pub degree: u64,
+
+    /// R-MAT probabilities of the top-left, top-right and bottom-left quadrants, the
+    /// bottom-right one gets the rest
+    #[clap(long, value_delimiter = ',', default_values_t = [0.57, 0.19, 0.19])]
+    pub rmat: Vec<f64>,
+
+    /// Seed of the generated graph, which does not depend on the number of replicas
+    #[clap(long = "graph-seed", default_value_t = 0xfeeddabeef)]
+    pub seed: u64,
+}
+
+/// Validated graph model, ready to generate the nodes and edges of any replica.
+///
+/// Nodes are `0..num_nodes`. R-MAT and Barabási–Albert graphs may have self loops and
+/// parallel edges
+#[derive(Debug, Clone)]
+pub struct Generator {
+    model: GraphModel,
+    nodes: u64,
+    degree: u64,
+    /// Edges of R-MAT and Barabási–Albert, which draw each edge from its index
+    edges: u64,
+    /// Cumulative probabilities of the first three R-MAT quadrants
+    quadrants: [f64; 3],
+    /// Gaps between the Erdős–Rényi links of a node
+    gaps: Option<Geometric>,
+    seed: u64,
+}
+
+impl Generator {
+    pub fn new(args: &GenerateArgs, model: GraphModel) -> eyre::Result<Self> {
+        eyre::ensure!(args.graph_nodes > 0, "the generated graph must have nodes");
+        let nodes = match model {
+            GraphModel::Rmat => args
+                .graph_nodes
+                .checked_next_power_of_two()
+                .ok_or_else(|| eyre::eyre!("too many nodes for R-MAT"))?,
+            GraphModel::Grid => {
+                let side = (args.graph_nodes as f64).sqrt().ceil() as u64;
+                side.checked_mul(side)
+                    .ok_or_else(|| eyre::eyre!("too many nodes for a grid"))?
+            }
+            GraphModel::ErdosRenyi | GraphModel::BarabasiAlbert => args.graph_nodes,
+        };
+
+        // Barabási–Albert also numbers the `2 * edges` ends of the edges
+        let edges = match model {
+            GraphModel::Rmat => nodes.checked_mul(args.degree),
+            GraphModel::BarabasiAlbert => nodes
+                .checked_mul(args.degree)
+                .filter(|&e| e.checked_mul(2).is_some()),
+            GraphModel::ErdosRenyi | GraphModel::Grid => Some(0),
+        }
+        .ok_or_else(|| eyre::eyre!("too many edges for the nodes and degree"))?;
+
+        let mut quadrants = [0.0; 3];
+        if model == GraphModel::Rmat {
+            eyre::ensure!(
+                args.rmat.len() == 3,
+                "R-MAT takes the probabilities of three quadrants"
+            );
+            let mut total = 0.0;
+            for (q, &p) in quadrants.iter_mut().zip(&args.rmat) {
+                eyre::ensure!(p >= 0.0, "R-MAT probabilities must not be negative");
+                total += p;
+                *q = total;
+            }
+            eyre::ensure!(total <= 1.0, "R-MAT probabilities must not sum over 1");
+        }
+
+        let gaps = match model {
+            GraphModel::ErdosRenyi => {
+                eyre::ensure!(
+                    args.degree < nodes,
+                    "an Erdős–Rényi degree must be below the nodes"
+                );
+                let p = args.degree as f64 / (nodes - 1).max(1) as f64;
+                (p > 0.0).then(|| Geometric::new(p)).transpose()?
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            model,
+            nodes,
+            degree: args.degree,
+            edges,
+            quadrants,
+            gaps,
+            seed: args.seed,
+        })
+    }
+
+    pub fn num_nodes(&self) -> u64 {
+        self.nodes
+    }
+
+    /// Nodes emitted by replica `i` of `n`
+    pub fn nodes(&self, i: u64, n: u64) -> impl Iterator<Item = u64> + Send + 'static {
+        (i..self.nodes).step_by(n as usize)
+    }
+
+    /// Edges emitted by replica `i` of `n`. Every node or edge draws from a generator
+    /// seeded by its index, so the graph is the same however it is split
+    pub fn edges(&self, i: u64, n: u64) -> Box<dyn Iterator<Item = (u64, u64)> + Send> {
+        let gen = self.clone();
+        let step = n as usize;
+        match self.model {
+            GraphModel::Rmat => Box::new((i..self.edges).step_by(step).map(move |k| gen.rmat(k))),
+            GraphModel::ErdosRenyi => {
+                Box::new(self.nodes(i, n).flat_map(move |x| gen.erdos_renyi(x)))
+            }
+            GraphModel::BarabasiAlbert => Box::new(
+                (i..self.edges)
+                    .step_by(step)
+                    .map(move |k| (k / gen.degree, gen.attach(k))),
+            ),
+            GraphModel::Grid => Box::new(self.nodes(i, n).flat_map(move |x| gen.grid(x))),
+        }
+    }
+
+    fn rng(&self, k: u64) -> SmallRng {
+        SmallRng::seed_from_u64(self.seed ^ k.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+    }
+
+    /// Edge `k`, picking a quadrant of the adjacency matrix for each bit of its ends
+    fn rmat(&self, k: u64) -> (u64, u64) {
+        let mut rng = self.rng(k);
+        let (mut x, mut y) = (0, 0);
+        for bit in 0..self.nodes.trailing_zeros() {
+            let r: f64 = rng.gen();
+            let (dx, dy) = match self.quadrants.iter().position(|&q| r < q) {
+                Some(0) => (0, 0),
+                Some(1) => (0, 1),
+                Some(2) => (1, 0),
+                _ => (1, 1),
+            };
+            x |= dx << bit;
+            y |= dy << bit;
+        }
+        (x, y)
+    }
+
+    /// Links of node `x`, skipping over the other nodes by geometric gaps
+    fn erdos_renyi(&self, x: u64) -> impl Iterator<Item = (u64, u64)> {
+        let mut rng = self.rng(x);
+        let (gaps, others) = (self.gaps, self.nodes - 1);
+        let mut next = 0u64;
+        std::iter::from_fn(move || {
+            let j = next.saturating_add(rng.sample(gaps?));
+            if j >= others {
+                return None;
+            }
+            next = j + 1;
+            // the others are the nodes but `x`
+            Some((x, if j < x { j } else { j + 1 }))
+        })
+    }
+
+    /// Target of edge `k`, a uniform entry of the list of the ends of the earlier edges.
+    ///
+    /// Entry `2k` of the list is the source of edge `k` and entry `2k + 1` its target, a
+    /// target copies an earlier entry so the nodes are picked by their degree. The copied
+    /// entry is drawn again from its own seed, without generating the list
+    fn attach(&self, mut k: u64) -> u64 {
+        loop {
+            let r = self.rng(k).gen_range(0..=2 * k);
+            if r % 2 == 0 {
+                return r / 2 / self.degree;
+            }
+            k = r / 2;
+        }
+    }
+
+    /// Links of node `x` to its neighbours in the grid
+    fn grid(&self, x: u64) -> impl Iterator<Item = (u64, u64)> {
+        let side = (self.nodes as f64).sqrt() as u64;
+        let (row, col) = (x / side, x % side);
+        let neighbours = [
+            (row > 0).then(|| x - side),
+            (row + 1 < side).then(|| x + side),
+            (col > 0).then(|| x - 1),
+            (col + 1 < side).then(|| x + 1),
+        ];
+        neighbours.into_iter().flatten().map(move |y| (x, y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(model: GraphModel) -> Generator {
+        let args = GenerateArgs {
+            generate: Some(model),
+            graph_nodes: 100,
+            degree: 4,
+            rmat: vec![0.57, 0.19, 0.19],
+            seed: 42,
+        };
+        Generator::new(&args, model).unwrap()
+    }
+
+    #[test]
+    fn edges_do_not_depend_on_the_replicas() {
+        for model in [
+            GraphModel::Rmat,
+            GraphModel::ErdosRenyi,
+            GraphModel::BarabasiAlbert,
+            GraphModel::Grid,
+        ] {
+            let gen = generator(model);
+            let mut one = gen.edges(0, 1).collect::<Vec<_>>();
+            let mut three = (0..3).flat_map(|i| gen.edges(i, 3)).collect::<Vec<_>>();
+            one.sort_unstable();
+            three.sort_unstable();
+            assert!(!one.is_empty(), "{model:?}");
+            assert_eq!(one, three, "{model:?}");
+        }
+    }
+
+    #[test]
+    fn too_many_edges() {
+        let args = GenerateArgs {
+            generate: Some(GraphModel::BarabasiAlbert),
+            graph_nodes: u64::MAX / 2,
+            degree: 4,
+            rmat: vec![],
+            seed: 0,
+        };
+        assert!(Generator::new(&args, GraphModel::BarabasiAlbert).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use eyre::Context;
+use noir_compute::{operator::Operator, prelude::*, Stream};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::generate::{GenerateArgs, Generator};
+
+/// Input graph of the graph benchmarks, flatten it in the binary options
+#[derive(Debug, Clone, clap::Args, Serialize)]
+pub struct GraphArgs {
+    /// Headerless CSV of the nodes, one id per row
+    #[clap(short, long, required_unless_present = "generate")]
+    pub nodes_path: Option<PathBuf>,
+
+    /// Headerless CSV of the edges, one `source,target` pair per row
+    #[clap(short, long, required_unless_present = "generate")]
+    pub edges_path: Option<PathBuf>,
+
+    /// Number of nodes in the files, counted from the nodes file if missing. The dense
+    /// connected components need the ids below it
+    #[clap(short('N'), long)]
+    pub nodes: Option<usize>,
+
+    #[clap(flatten)]
+    pub generate: GenerateArgs,
+}
+
+impl GraphArgs {
+    pub fn graph(&self) -> eyre::Result<Graph> {
+        if let Some(model) = self.generate.generate {
+            return Ok(Graph::Generated(Generator::new(&self.generate, model)?));
+        }
+        match (&self.nodes_path, &self.edges_path) {
+            (Some(nodes_path), Some(edges_path)) => Ok(Graph::Files {
+                nodes_path: nodes_path.clone(),
+                edges_path: edges_path.clone(),
+                nodes: self.nodes,
+            }),
+            _ => eyre::bail!("the graph requires the nodes and edges files"),
+        }
+    }
+}
+
+/// Graph read from CSV files or generated, split between the replicas of its sources
+#[derive(Debug, Clone)]
+pub enum Graph {
+    Files {
+        nodes_path: PathBuf,
+        edges_path: PathBuf,
+        nodes: Option<usize>,
+    },
+    Generated(Generator),
+}
+
+impl Graph {
+    /// Number of nodes, reading the nodes file if it was not given
+    pub fn num_nodes(&self) -> eyre::Result<usize> {
+        match self {
+            Graph::Files {
+                nodes: Some(nodes), ..
+            } => Ok(*nodes),
+            Graph::Files { .. } => Ok(self.read_nodes()?.len()),
+            Graph::Generated(gen) => Ok(gen.num_nodes() as usize),
+        }
+    }
+
+    /// Parallel source of the node ids
+    pub fn node_source(&self, env: &mut StreamContext) -> Stream<impl Operator<Out = u64>> {
+        let graph = self.clone();
+        env.stream_par_iter(move |i, n| graph.nodes(i, n))
+    }
+
+    /// Parallel source of the `(source, target)` edges
+    pub fn edge_source(&self, env: &mut StreamContext) -> Stream<impl Operator<Out = (u64, u64)>> {
+        let graph = self.clone();
+        env.stream_par_iter(move |i, n| graph.edges(i, n))
+    }
+
+    /// Nodes emitted by replica `i` of `n`
+    pub fn nodes(&self, i: u64, n: u64) -> Box<dyn Iterator<Item = u64> + Send> {
+        match self {
+            Graph::Files { nodes_path, .. } => rows(nodes_path, i, n),
+            Graph::Generated(gen) => Box::new(gen.nodes(i, n)),
+        }
+    }
+
+    /// Edges emitted by replica `i` of `n`
+    pub fn edges(&self, i: u64, n: u64) -> Box<dyn Iterator<Item = (u64, u64)> + Send> {
+        match self {
+            Graph::Files { edges_path, .. } => rows(edges_path, i, n),
+            Graph::Generated(gen) => gen.edges(i, n),
+        }
+    }
+
+    /// Every node, read on this host
+    pub fn read_nodes(&self) -> eyre::Result<Vec<u64>> {
+        match self {
+            Graph::Files { nodes_path, .. } => read_csv(nodes_path),
+            Graph::Generated(gen) => Ok(gen.nodes(0, 1).collect()),
+        }
+    }
+
+    /// Every edge, read on this host
+    pub fn read_edges(&self) -> eyre::Result<Vec<(u64, u64)>> {
+        match self {
+            Graph::Files { edges_path, .. } => read_csv(edges_path),
+            Graph::Generated(gen) => Ok(gen.edges(0, 1).collect()),
+        }
+    }
+}
+
+/// Rows of a CSV file without headers, like the noir `CsvSource` of the jobs reads them
+pub fn read_csv<T: DeserializeOwned>(path: impl AsRef<Path>) -> eyre::Result<Vec<T>> {
+    let path = path.as_ref();
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_path(path)
+        .with_context(|| format!("failed to open {}", path.display()))?
+        .into_deserialize()
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("failed to parse {}", path.display()))
+}
+
+/// Rows of replica `i` of `n` for a source, which panics on errors like the noir `CsvSource`
+fn rows<T: DeserializeOwned + 'static>(
+    path: &Path,
+    i: u64,
+    n: u64,
+) -> Box<dyn Iterator<Item = T> + Send> {
+    let part = read_csv_part(path, i, n)
+        .unwrap_or_else(|e| panic!("failed to read {}: {e:#}", path.display()));
+    let path = path.to_path_buf();
+    Box::new(
+        part.map(move |row| {
+            row.unwrap_or_else(|e| panic!("failed to read {}: {e:#}", path.display()))
+        }),
+    )
+}
+
+/// Rows of the `i`-th of `n` byte ranges of a CSV file without headers. A row belongs to the
+/// range holding its first byte, so the replicas split the file like the noir `CsvSource`
+fn read_csv_part<T: DeserializeOwned>(
+    path: &Path,
+    i: u64,
+    n: u64,
+) -> eyre::Result<impl Iterator<Item = eyre::Result<T>> + Send> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let len = file.metadata()?.len();
+    let (start, end) = (len * i / n, len * (i + 1) / n);
+
+    // the row holding `start` belongs to the previous range, unless it begins there
+    let mut file = BufReader::new(file);
+    let mut offset = start.saturating_sub(1);
+    file.seek(SeekFrom::Start(offset))?;
+    if start > 0 {
+        offset += file.read_until(b'\n', &mut Vec::new())? as u64;
+    }
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(file);
+    let mut record = csv::ByteRecord::new();
+    Ok(std::iter::from_fn(move || {
+        match reader.read_byte_record(&mut record) {
+            Ok(true) if offset + record.position()?.byte() < end => {
+                Some(record.deserialize(None).map_err(Into::into))
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_hold_every_row_once() {
+        let path = std::env::temp_dir().join(format!("input-parts-{}.csv", std::process::id()));
+        let rows = (0..200u64).map(|i| (i, i * i % 997)).collect::<Vec<_>>();
+        let mut w = csv::Writer::from_path(&path).unwrap();
+        for row in &rows {
+            w.serialize(row).unwrap();
+        }
+        w.flush().unwrap();
+
+        let part = |i, n| {
+            read_csv_part::<(u64, u64)>(&path, i, n)
+                .unwrap()
+                .collect::<eyre::Result<Vec<_>>>()
+                .unwrap()
+        };
+        let one = part(0, 1);
+        let three = (0..3).flat_map(|i| part(i, 3)).collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(one, rows);
+        assert_eq!(three, rows);
+    }
+}
@@ -0,0 +1,3 @@
+pub mod generate;
+pub mod input;
+pub mod pagerank;
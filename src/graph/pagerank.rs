@@ -0,0 +1,210 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use ahash::{AHashMap as HashMap, AHashSet as HashSet};
+use serde::{Deserialize, Serialize};
+
+use super::input::read_csv;
+
+/// Relative difference under which a rank matches the reference
+pub const EPS: f64 = 1e-8;
+
+/// Parameters of the PageRank, flatten them in the binary options
+#[derive(Debug, Clone, clap::Args, Serialize)]
+pub struct RankArgs {
+    /// Probability of following a link instead of teleporting
+    #[clap(long, default_value_t = 0.85)]
+    pub damping: f64,
+
+    /// Stop once the L1 distance between the ranks of two iterations is below this
+    #[clap(long, default_value_t = 1e-9)]
+    pub tolerance: f64,
+
+    /// CSV of `page,weight` rows biasing the teleports and the dangling mass towards these
+    /// pages, the weights are normalized. Uniform over the pages without it
+    #[clap(long)]
+    pub personalization: Option<PathBuf>,
+}
+
+impl RankArgs {
+    /// Model for a graph of `num_pages` pages, reading the personalization vector
+    pub fn model(&self, num_pages: usize) -> eyre::Result<PageRank> {
+        eyre::ensure!(
+            (0.0..1.0).contains(&self.damping),
+            "damping must be in 0..1"
+        );
+        eyre::ensure!(num_pages > 0, "the graph has no pages");
+        let personalization = match &self.personalization {
+            Some(path) => {
+                let weights = read_csv::<(u64, f64)>(path)?;
+                eyre::ensure!(
+                    weights.iter().all(|(_, w)| *w >= 0.0),
+                    "personalization weights must not be negative"
+                );
+                let total: f64 = weights.iter().map(|(_, w)| w).sum();
+                eyre::ensure!(total > 0.0, "personalization weights sum to zero");
+                let mut normalized: HashMap<u64, f64> = HashMap::default();
+                for (x, w) in weights {
+                    *normalized.entry(x).or_default() += w / total;
+                }
+                Some(Arc::new(normalized))
+            }
+            None => None,
+        };
+        Ok(PageRank {
+            damping: self.damping,
+            tolerance: self.tolerance,
+            num_pages,
+            personalization,
+        })
+    }
+}
+
+/// PageRank with the rank of the pages without out-links, the dangling mass, spread like the
+/// teleports, so that the ranks always sum to 1
+#[derive(Debug, Clone)]
+pub struct PageRank {
+    damping: f64,
+    tolerance: f64,
+    num_pages: usize,
+    /// Teleport probability of each page, uniform if none
+    personalization: Option<Arc<HashMap<u64, f64>>>,
+}
+
+impl PageRank {
+    /// Rank of every page before the first iteration
+    pub fn initial(&self) -> f64 {
+        1.0 / self.num_pages as f64
+    }
+
+    /// Probability of teleporting to `x`
+    pub fn teleport(&self, x: u64) -> f64 {
+        match &self.personalization {
+            Some(p) => p.get(&x).copied().unwrap_or(0.0),
+            None => 1.0 / self.num_pages as f64,
+        }
+    }
+
+    /// Rank of `x` given the `shares` sent by its in-links and the `dangling` mass of the
+    /// previous iteration
+    pub fn rank(&self, x: u64, shares: f64, dangling: f64) -> f64 {
+        let d = self.damping;
+        self.teleport(x) * (1.0 - d + d * dangling) + d * shares
+    }
+
+    /// Whether the iterations can stop after moving the ranks by `delta`, in L1 distance
+    pub fn converged(&self, delta: f64) -> bool {
+        delta < self.tolerance
+    }
+}
+
+/// Ranks and convergence of a PageRank
+#[derive(Debug, Clone)]
+pub struct Ranking {
+    pub ranks: HashMap<u64, f64>,
+    /// Rank updates until convergence, or until the limit
+    pub iterations: usize,
+    /// L1 distance moved by the last update
+    pub delta: f64,
+}
+
+/// Single-threaded PageRank of the `nodes`, which must include both ends of every edge.
+///
+/// Ranks are updated until the model converges, for at most `max_iterations` times
+pub fn reference(
+    model: &PageRank,
+    nodes: &[u64],
+    edges: &[(u64, u64)],
+    max_iterations: usize,
+) -> Ranking {
+    let mut adjacency: HashMap<u64, Vec<u64>> = HashMap::default();
+    for &(x, y) in edges {
+        adjacency.entry(x).or_default().push(y);
+    }
+
+    let mut ranks: HashMap<u64, f64> = nodes.iter().map(|&x| (x, model.initial())).collect();
+    let mut ranking = Ranking {
+        ranks: HashMap::default(),
+        iterations: 0,
+        delta: f64::INFINITY,
+    };
+    while ranking.iterations < max_iterations {
+        let mut dangling = 0.0;
+        let mut shares: HashMap<u64, f64> = HashMap::with_capacity(ranks.len());
+        for (&x, &rank) in &ranks {
+            match adjacency.get(&x) {
+                Some(adj) => {
+                    let share = rank / adj.len() as f64;
+                    for &y in adj {
+                        *shares.entry(y).or_default() += share;
+                    }
+                }
+                None => dangling += rank,
+            }
+        }
+
+        let mut delta = 0.0;
+        for (&x, rank) in ranks.iter_mut() {
+            let new = model.rank(x, shares.get(&x).copied().unwrap_or(0.0), dangling);
+            delta += (new - *rank).abs();
+            *rank = new;
+        }
+        ranking.iterations += 1;
+        ranking.delta = delta;
+        if model.converged(delta) {
+            break;
+        }
+    }
+    ranking.ranks = ranks;
+    ranking
+}
+
+/// Ranks of a job compared to the [reference]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Validation {
+    /// Pages ranked by the reference
+    pub pages: usize,
+    /// Rank updates of the reference
+    pub iterations: usize,
+    /// Pages of the reference without a rank
+    pub missing: usize,
+    /// Ranked pages that the reference does not rank, or ranked more than once
+    pub extra: usize,
+    /// Ranks differing from the reference by more than [EPS], relatively
+    pub mismatched: usize,
+    pub max_relative_error: f64,
+}
+
+impl Validation {
+    pub fn passed(&self) -> bool {
+        self.missing + self.extra + self.mismatched == 0
+    }
+}
+
+/// Compare the `(page, rank)` output of a job to the reference
+pub fn validate(ranks: &[(u64, f64)], reference: &Ranking) -> Validation {
+    let reference_ranks = &reference.ranks;
+    let mut v = Validation {
+        pages: reference_ranks.len(),
+        iterations: reference.iterations,
+        ..Default::default()
+    };
+    let mut seen = HashSet::with_capacity(ranks.len());
+    for &(x, rank) in ranks {
+        let Some(&want) = reference_ranks.get(&x).filter(|_| seen.insert(x)) else {
+            v.extra += 1;
+            continue;
+        };
+        // pages that are never teleported to nor linked have no rank
+        let error = match want > 0.0 {
+            true => (rank - want).abs() / want,
+            false => rank.abs(),
+        };
+        v.max_relative_error = v.max_relative_error.max(error);
+        if error > EPS || error.is_nan() {
+            v.mismatched += 1;
+        }
+    }
+    v.missing = reference_ranks.len() - seen.len();
+    v
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hdrhistogram::Histogram;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+/// Highest recorded latency, one hour in microseconds
+const MAX_LATENCY_US: u64 = 3_600_000_000;
+
+/// Histograms merged from every recorder of this process, by stage
+static STAGES: Lazy<Mutex<BTreeMap<&'static str, Histogram<u64>>>> = Lazy::new(Default::default);
+
+/// Wall clock in microseconds since the epoch, comparable across hosts with synchronized clocks
+pub fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64
+}
+
+/// Event tagged with the time it entered the pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timed<T> {
+    /// Microseconds since the epoch, see [now_us]
+    pub ingest: u64,
+    pub value: T,
+}
+
+impl<T> Timed<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Timed<U> {
+        Timed {
+            ingest: self.ingest,
+            value: f(self.value),
+        }
+    }
+
+    pub async fn then<U, F: Future<Output = U>>(self, f: impl FnOnce(T) -> F) -> Timed<U> {
+        Timed {
+            ingest: self.ingest,
+            value: f(self.value).await,
+        }
+    }
+}
+
+impl<T> Timed<Option<T>> {
+    /// Moves the option outside, to flatten a stream of timed lookups
+    pub fn transpose(self) -> Option<Timed<T>> {
+        let ingest = self.ingest;
+        self.value.map(|value| Timed { ingest, value })
+    }
+}
+
+impl<T, E> Timed<Result<T, E>> {
+    /// Moves the result outside, to send the errors to a sink
+    pub fn transpose(self) -> Result<Timed<T>, E> {
+        let ingest = self.ingest;
+        self.value.map(|value| Timed { ingest, value })
+    }
+}
+
+fn histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_LATENCY_US, 3).unwrap()
+}
+
+/// Records the end-to-end latency of the events reaching a stage.
+///
+/// Each replica owns a clone and merges it into the process-wide histogram of the stage
+/// when the operator is dropped at the end of the execution
+pub struct StageRecorder {
+    stage: &'static str,
+    hist: Histogram<u64>,
+}
+
+impl StageRecorder {
+    pub fn new(stage: &'static str) -> Self {
+        Self {
+            stage,
+            hist: histogram(),
+        }
+    }
+
+    pub fn record<T>(&mut self, e: &Timed<T>) {
+        self.hist
+            .saturating_record(now_us().saturating_sub(e.ingest));
+    }
+}
+
+/// Operator body for `rich_map`, recording the events passing through `stage`
+pub fn recorder<T>(
+    stage: &'static str,
+) -> impl FnMut(Timed<T>) -> Timed<T> + Clone + Send + 'static {
+    let mut recorder = StageRecorder::new(stage);
+    move |e| {
+        recorder.record(&e);
+        e
+    }
+}
+
+impl Clone for StageRecorder {
+    fn clone(&self) -> Self {
+        Self::new(self.stage)
+    }
+}
+
+impl Drop for StageRecorder {
+    fn drop(&mut self) {
+        if self.hist.is_empty() {
+            return;
+        }
+        let mut stages = STAGES.lock().unwrap();
+        stages
+            .entry(self.stage)
+            .or_insert_with(histogram)
+            .add(&self.hist)
+            .expect("stage histograms share the same bounds");
+    }
+}
+
+/// Latency percentiles of a stage, in microseconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageSummary {
+    pub count: u64,
+    pub p50: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+}
+
+impl From<&Histogram<u64>> for StageSummary {
+    fn from(h: &Histogram<u64>) -> Self {
+        Self {
+            count: h.len(),
+            p50: h.value_at_quantile(0.5),
+            p99: h.value_at_quantile(0.99),
+            p999: h.value_at_quantile(0.999),
+            max: h.max(),
+        }
+    }
+}
+
+/// Summary of the stages recorded by this process so far
+pub fn summary() -> BTreeMap<&'static str, StageSummary> {
+    let stages = STAGES.lock().unwrap();
+    stages.iter().map(|(&s, h)| (s, h.into())).collect()
+}
+
+/// [summary] as a JSON object keyed by stage
+pub fn summary_json() -> String {
+    serde_json::to_string(&summary()).unwrap()
+}
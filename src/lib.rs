@@ -1 +1,9 @@
-pub mod enrich;
\ No newline at end of file
+pub mod bench;
+pub mod enrich;
+pub mod graph;
+pub mod latency;
+pub mod output;
+pub mod report;
+pub mod retry;
+pub mod trace;
+pub mod workload;
@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use eyre::Context;
+use serde::Serialize;
+
+/// Parts opened by this process, numbering the files of its replicas
+static PARTS: AtomicU64 = AtomicU64::new(0);
+
+/// Where a batch job writes its results, flatten it in the binary options
+#[derive(Debug, Clone, clap::Args)]
+pub struct OutputArgs {
+    /// Directory receiving the results as CSV, one `part-h<host>-<n>.csv` for each replica
+    /// with rows. Without it the results are only collected
+    #[clap(long)]
+    pub output: Option<PathBuf>,
+}
+
+impl OutputArgs {
+    /// Sink for `for_each` writing the rows of each replica to its own part, under the
+    /// `header` columns. The parts written by an earlier run of this host are removed
+    pub fn sink<T: Serialize>(
+        &self,
+        host_id: Option<u64>,
+        header: &'static [&'static str],
+    ) -> eyre::Result<Option<impl FnMut(T) + Clone + Send + 'static>> {
+        let Some(dir) = &self.output else {
+            return Ok(None);
+        };
+        let host = host_id.unwrap_or(0);
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        remove_parts(dir, host)?;
+
+        let mut part = Part {
+            dir: dir.clone(),
+            host,
+            header,
+            out: None,
+        };
+        Ok(Some(move |row: T| part.write(row)))
+    }
+}
+
+fn remove_parts(dir: &Path, host: u64) -> eyre::Result<()> {
+    let prefix = format!("part-h{host}-");
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with(&prefix) && name.ends_with(".csv") {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("failed to remove {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// CSV file of a replica, opened on its first row and flushed when the replica drops it
+struct Part {
+    dir: PathBuf,
+    host: u64,
+    header: &'static [&'static str],
+    out: Option<csv::Writer<File>>,
+}
+
+/// Every replica gets a part of its own
+impl Clone for Part {
+    fn clone(&self) -> Self {
+        Self {
+            dir: self.dir.clone(),
+            host: self.host,
+            header: self.header,
+            out: None,
+        }
+    }
+}
+
+impl Part {
+    fn write<T: Serialize>(&mut self, row: T) {
+        if let Err(e) = self.try_write(row) {
+            panic!(
+                "failed to write the output to {}: {e:#}",
+                self.dir.display()
+            );
+        }
+    }
+
+    fn try_write<T: Serialize>(&mut self, row: T) -> eyre::Result<()> {
+        let out = match &mut self.out {
+            Some(out) => out,
+            None => {
+                let n = PARTS.fetch_add(1, Ordering::Relaxed);
+                let path = self.dir.join(format!("part-h{}-{n:03}.csv", self.host));
+                let mut out = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_path(&path)
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                out.write_record(self.header)?;
+                self.out.insert(out)
+            }
+        };
+        out.serialize(row)?;
+        Ok(())
+    }
+}
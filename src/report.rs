@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use eyre::Context;
+use noir_compute::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::enrich::cache::{self, CacheSummary};
+use crate::enrich::pool::{self, PoolSummary};
+use crate::enrich::verify::{self, VerifySummary};
+use crate::latency::{self, StageSummary};
+use crate::retry::{self, StageErrors};
+
+/// Where the result record of the run is appended, flatten it in the binary options
+#[derive(Debug, Clone, clap::Args)]
+pub struct ReportArgs {
+    /// JSON lines file receiving one record per run on every host, each host writes to its
+    /// own filesystem
+    #[clap(long, default_value = "results/records.jsonl")]
+    pub record: PathBuf,
+
+    /// Do not write the result record
+    #[clap(long)]
+    pub no_record: bool,
+
+    /// Parameter of the `bench` cell running the binary, as `name=value`
+    #[clap(long = "record-param", value_parser = parse_param)]
+    pub params: Vec<(String, String)>,
+}
+
+fn parse_param(s: &str) -> Result<(String, String), String> {
+    let (k, v) = s.split_once('=').ok_or("expected name=value")?;
+    Ok((k.to_string(), v.to_string()))
+}
+
+/// Deployment the run executed on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterInfo {
+    /// `local` or `remote`
+    pub runtime: String,
+    /// Config file passed with `-r`, if any
+    pub config: Option<String>,
+    pub hosts: usize,
+    /// Cores summed over every host
+    pub cores: u64,
+    /// Host writing the record, none for local runs
+    pub host_id: Option<u64>,
+}
+
+impl ClusterInfo {
+    pub fn new(conf: &RuntimeConfig) -> Self {
+        let (runtime, hosts, cores) = match conf {
+            RuntimeConfig::Local(local) => ("local", 1, local.num_cores),
+            RuntimeConfig::Remote(remote) => (
+                "remote",
+                remote.hosts.len(),
+                remote.hosts.iter().map(|h| h.num_cores).sum(),
+            ),
+        };
+        Self {
+            runtime: runtime.to_string(),
+            config: config_path(),
+            hosts,
+            cores,
+            host_id: match conf {
+                RuntimeConfig::Local(_) => None,
+                RuntimeConfig::Remote(remote) => remote.host_id,
+            },
+        }
+    }
+
+    /// The first host, whose record times the run in `bench report`. Every host runs the same
+    /// pipeline and records its own summaries
+    pub fn is_leader(&self) -> bool {
+        self.host_id.unwrap_or(0) == 0
+    }
+}
+
+/// Config file from the noir arguments, `-r <path>` or `--remote <path>`
+fn config_path() -> Option<String> {
+    let mut args = std::env::args().take_while(|a| a != "--");
+    while let Some(a) = args.next() {
+        if a == "-r" || a == "--remote" {
+            return args.next();
+        }
+        if let Some(path) = a.strip_prefix("--remote=") {
+            return Some(path.to_string());
+        }
+    }
+    None
+}
+
+/// Result of one execution of a benchmark binary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub run_id: String,
+    /// Seconds since the epoch at the end of the run
+    pub timestamp: u64,
+    pub binary: String,
+    /// Parsed options of the binary
+    pub variant: serde_json::Value,
+    /// Parameters of the `bench` cell, which name the variant in `bench report` like they
+    /// name the other results. Empty outside of `bench run`
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+    pub cluster: ClusterInfo,
+    /// Number of generated events, none for batch jobs
+    pub events: Option<u64>,
+    /// Execution time of the pipeline, without connection and setup
+    pub exec_time_s: f64,
+    /// Events per second over `exec_time_s`
+    pub throughput: Option<f64>,
+    /// Per-stage latency, see [latency::summary]
+    pub latency: BTreeMap<String, StageSummary>,
+    /// Retries and dead letters by stage, see [retry::summary]
+    #[serde(default)]
+    pub errors: BTreeMap<String, StageErrors>,
+    /// Hit ratio and staleness by cache table, see [cache::summary]
+    #[serde(default)]
+    pub cache: BTreeMap<String, CacheSummary>,
+    /// Checkout wait and occupancy by connection pool, see [pool::summary]
+    #[serde(default)]
+    pub pools: BTreeMap<String, PoolSummary>,
+    /// Outputs compared to the dataset, none without `--verify`, see [verify::summary]
+    #[serde(default)]
+    pub verify: Option<VerifySummary>,
+    /// Iterations an iterative batch job ran until it converged, where its state is collected
+    #[serde(default)]
+    pub iterations: Option<usize>,
+    /// Bytes of the state an iterative batch job broadcasts to every replica each iteration
+    #[serde(default)]
+    pub state_bytes: Option<usize>,
+    /// Bytes of the per-key state and messages an iterative batch job shuffles over all its
+    /// iterations, where its state is collected
+    #[serde(default)]
+    pub shuffled_bytes: Option<u64>,
+    /// `mark_hit` attempts retried because of a write conflict, none for read-only runs, see
+    /// [crate::enrich::backend::EnrichBackend::write_retries]
+    #[serde(default)]
+    pub write_retries: Option<u64>,
+    /// Span statistics of micrometer, one object per span with the columns of its CSV export
+    #[serde(default)]
+    pub micrometer: Vec<BTreeMap<String, serde_json::Value>>,
+}
+
+impl RunRecord {
+    pub fn new(
+        binary: &str,
+        variant: &impl Serialize,
+        cluster: ClusterInfo,
+        events: Option<u64>,
+        exec_time: Duration,
+    ) -> eyre::Result<Self> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let exec_time_s = exec_time.as_secs_f64();
+        Ok(Self {
+            run_id: format!(
+                "{binary}-{}-h{}",
+                timestamp.as_millis(),
+                cluster.host_id.unwrap_or(0)
+            ),
+            timestamp: timestamp.as_secs(),
+            binary: binary.to_string(),
+            variant: serde_json::to_value(variant)?,
+            params: BTreeMap::new(),
+            cluster,
+            events,
+            exec_time_s,
+            throughput: events.map(|n| n as f64 / exec_time_s),
+            latency: latency::summary()
+                .into_iter()
+                .map(|(s, v)| (s.to_string(), v))
+                .collect(),
+            errors: retry::summary()
+                .into_iter()
+                .map(|(s, v)| (s.to_string(), v))
+                .collect(),
+            cache: cache::summary()
+                .into_iter()
+                .map(|(t, v)| (t.to_string(), v))
+                .collect(),
+            pools: pool::summary()
+                .into_iter()
+                .map(|(p, v)| (p.to_string(), v))
+                .collect(),
+            verify: verify::summary(),
+            iterations: None,
+            state_bytes: None,
+            shuffled_bytes: None,
+            write_retries: None,
+            micrometer: Vec::new(),
+        })
+    }
+
+    /// Append the record with the micrometer spans of this host
+    pub fn write(mut self, args: &ReportArgs) -> eyre::Result<()> {
+        if args.no_record {
+            return Ok(());
+        }
+        if let Some(dir) = args.record.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+
+        self.params = args.params.iter().cloned().collect();
+        self.micrometer = micrometer_stats(&self.run_id)?;
+
+        append_line(&args.record, &serde_json::to_string(&self)?)?;
+        log::info!("result record appended to {}", args.record.display());
+        Ok(())
+    }
+}
+
+/// Rows of the micrometer CSV export, exported to a temporary file, with the numbers parsed
+fn micrometer_stats(run_id: &str) -> eyre::Result<Vec<BTreeMap<String, serde_json::Value>>> {
+    let path = std::env::temp_dir().join(format!("{run_id}.micrometer.csv"));
+    micrometer::append_csv_uniform(&path, run_id)?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let rows = csv::Reader::from_path(&path)
+        .and_then(|r| r.into_deserialize::<BTreeMap<String, String>>().collect());
+    std::fs::remove_file(&path).ok();
+    let rows: Vec<_> =
+        rows.with_context(|| format!("failed to read the micrometer spans {}", path.display()))?;
+
+    let value = |v: String| match v.parse::<f64>().ok().and_then(serde_json::Number::from_f64) {
+        Some(n) => serde_json::Value::Number(n),
+        None => serde_json::Value::String(v),
+    };
+    Ok(rows
+        .into_iter()
+        .map(|row| row.into_iter().map(|(k, v)| (k, value(v))).collect())
+        .collect())
+}
+
+fn append_line(path: &Path, line: &str) -> eyre::Result<()> {
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    writeln!(f, "{line}")?;
+    Ok(())
+}
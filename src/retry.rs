@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use backoff::{ExponentialBackoff, ExponentialBackoffBuilder};
+use eyre::Context;
+use once_cell::sync::{Lazy, OnceCell};
+use r2d2_postgres::{postgres, r2d2};
+use serde::{Deserialize, Serialize};
+
+/// Retry and failure counters, by stage
+static STAGES: Lazy<Mutex<BTreeMap<&'static str, StageErrors>>> = Lazy::new(Default::default);
+
+/// Dead letters of this process, one JSON object per line
+static DEAD_LETTER_FILE: OnceCell<Mutex<File>> = OnceCell::new();
+
+/// Retry policy of the enrichment operations, flatten it in the binary options
+#[derive(Debug, Clone, clap::Args, Serialize)]
+pub struct RetryConfig {
+    /// Delay before the first retry of a failed operation
+    #[clap(long, default_value_t = 10)]
+    pub retry_initial_ms: u64,
+
+    /// Upper bound of the exponentially growing delay between retries
+    #[clap(long, default_value_t = 1000)]
+    pub retry_max_interval_ms: u64,
+
+    /// Give up on an event after retrying for this long, 0 disables retries
+    #[clap(long, default_value_t = 10_000)]
+    pub retry_max_elapsed_ms: u64,
+
+    /// Append the events failing permanently to this JSON lines file, on every host
+    #[clap(long)]
+    #[serde(skip)]
+    pub dead_letter: Option<PathBuf>,
+}
+
+impl RetryConfig {
+    /// Open the dead letter file, once per process
+    pub fn policy(&self) -> eyre::Result<RetryPolicy> {
+        if let Some(path) = &self.dead_letter {
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("failed to create {}", dir.display()))?;
+            }
+            let f = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            DEAD_LETTER_FILE.set(Mutex::new(f)).ok();
+        }
+        Ok(RetryPolicy {
+            initial: Duration::from_millis(self.retry_initial_ms),
+            max_interval: Duration::from_millis(self.retry_max_interval_ms),
+            max_elapsed: Duration::from_millis(self.retry_max_elapsed_ms),
+        })
+    }
+}
+
+/// Exponential backoff applied to each operation of an event.
+///
+/// Errors are retried until the policy gives up, unless [is_permanent] says they would
+/// fail again. The event is then returned as a [DeadLetter]
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    initial: Duration,
+    max_interval: Duration,
+    max_elapsed: Duration,
+}
+
+impl RetryPolicy {
+    fn backoff(&self) -> ExponentialBackoff {
+        ExponentialBackoffBuilder::new()
+            .with_initial_interval(self.initial)
+            .with_max_interval(self.max_interval)
+            .with_max_elapsed_time(Some(self.max_elapsed))
+            .build()
+    }
+
+    pub fn run<E: Clone, T>(
+        &self,
+        stage: &'static str,
+        event: &E,
+        mut op: impl FnMut() -> eyre::Result<T>,
+    ) -> Result<T, DeadLetter<E>> {
+        let op = || op().map_err(classify);
+        backoff::retry_notify(self.backoff(), op, notify(stage))
+            .map_err(|e| DeadLetter::new(stage, event.clone(), unwrap_backoff(e)))
+    }
+
+    pub async fn run_async<E: Clone, T, F: Future<Output = eyre::Result<T>>>(
+        &self,
+        stage: &'static str,
+        event: &E,
+        mut op: impl FnMut() -> F,
+    ) -> Result<T, DeadLetter<E>> {
+        let op = || {
+            let f = op();
+            async move { f.await.map_err(classify) }
+        };
+        backoff::future::retry_notify(self.backoff(), op, notify(stage))
+            .await
+            .map_err(|e| DeadLetter::new(stage, event.clone(), e))
+    }
+}
+
+fn classify(e: eyre::Report) -> backoff::Error<eyre::Report> {
+    match is_permanent(&e) {
+        true => backoff::Error::permanent(e),
+        false => backoff::Error::transient(e),
+    }
+}
+
+fn unwrap_backoff(e: backoff::Error<eyre::Report>) -> eyre::Report {
+    match e {
+        backoff::Error::Permanent(e) => e,
+        backoff::Error::Transient { err, .. } => err,
+    }
+}
+
+fn notify(stage: &'static str) -> impl FnMut(eyre::Report, Duration) {
+    move |e, d| {
+        log::debug!("{stage}: retrying in {d:?} after {e:#}");
+        STAGES.lock().unwrap().entry(stage).or_default().retries += 1;
+    }
+}
+
+/// Errors that would fail again: database errors outside the connection, transaction
+/// rollback and resource classes, Redis replies like a missing function, Scylla errors
+/// other than timeouts and overloads, and decoding errors. Everything else, like timeouts
+/// of the pool or dropped connections, is transient
+pub fn is_permanent(e: &eyre::Report) -> bool {
+    for cause in e.chain() {
+        #[cfg(feature = "redis")]
+        if let Some(e) = cause.downcast_ref::<deadpool_redis::redis::RedisError>() {
+            return !transient_redis(e);
+        }
+        #[cfg(feature = "redis")]
+        if cause.is::<rmp_serde::decode::Error>() {
+            return true;
+        }
+        #[cfg(feature = "scylla")]
+        if let Some(e) = cause.downcast_ref::<scylla::transport::errors::QueryError>() {
+            return !transient_scylla(e);
+        }
+        #[cfg(feature = "scylla")]
+        if cause.is::<scylla::cql_to_rust::FromRowError>()
+            || cause.is::<scylla::transport::query_result::RowsExpectedError>()
+        {
+            return true;
+        }
+        if let Some(e) = cause.downcast_ref::<postgres::Error>() {
+            return e.code().is_some_and(|c| !transient_sql_state(c.code()));
+        }
+        if let Some(e) = cause.downcast_ref::<sqlx::Error>() {
+            return match e {
+                sqlx::Error::Database(db) => db.code().is_some_and(|c| !transient_sql_state(&c)),
+                sqlx::Error::Io(_)
+                | sqlx::Error::Tls(_)
+                | sqlx::Error::PoolTimedOut
+                | sqlx::Error::WorkerCrashed => false,
+                _ => true,
+            };
+        }
+        if cause.is::<r2d2::Error>() || cause.is::<std::io::Error>() {
+            return false;
+        }
+    }
+    false
+}
+
+/// Connection exception, transaction rollback, insufficient resources and operator
+/// intervention classes of the Postgres SQLSTATE codes
+fn transient_sql_state(code: &str) -> bool {
+    matches!(code.get(..2), Some("08" | "40" | "53" | "57"))
+}
+
+/// Lost or refused connections, timeouts, and servers still loading or failing over
+#[cfg(feature = "redis")]
+fn transient_redis(e: &deadpool_redis::redis::RedisError) -> bool {
+    use deadpool_redis::redis::ErrorKind;
+    e.is_io_error()
+        || e.is_timeout()
+        || e.is_connection_dropped()
+        || e.is_connection_refusal()
+        || matches!(
+            e.kind(),
+            ErrorKind::BusyLoadingError
+                | ErrorKind::TryAgain
+                | ErrorKind::ClusterDown
+                | ErrorKind::MasterDown
+        )
+}
+
+/// Connection and timeout errors, and the database errors of unavailable, overloaded or
+/// slow nodes
+#[cfg(feature = "scylla")]
+fn transient_scylla(e: &scylla::transport::errors::QueryError) -> bool {
+    use scylla::transport::errors::{DbError, QueryError};
+    match e {
+        QueryError::DbError(db, _) => matches!(
+            db,
+            DbError::Unavailable { .. }
+                | DbError::Overloaded
+                | DbError::IsBootstrapping
+                | DbError::ReadTimeout { .. }
+                | DbError::WriteTimeout { .. }
+                | DbError::RateLimitReached { .. }
+                | DbError::Unprepared { .. }
+        ),
+        QueryError::IoError(_)
+        | QueryError::TimeoutError
+        | QueryError::RequestTimeout(_)
+        | QueryError::TooManyOrphanedStreamIds(_)
+        | QueryError::UnableToAllocStreamId => true,
+        _ => false,
+    }
+}
+
+/// Event that failed permanently in a stage
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter<E> {
+    pub stage: &'static str,
+    pub event: E,
+    pub error: String,
+}
+
+impl<E> DeadLetter<E> {
+    pub fn new(stage: &'static str, event: E, error: eyre::Report) -> Self {
+        Self {
+            stage,
+            event,
+            error: format!("{error:#}"),
+        }
+    }
+}
+
+/// Operator body for `filter_map`, passing the successful results and counting the dead
+/// letters, which are also written to the dead letter file if configured
+pub fn sink<T, E: Serialize>(
+) -> impl Fn(Result<T, DeadLetter<E>>) -> Option<T> + Clone + Send + 'static {
+    |r| match r {
+        Ok(v) => Some(v),
+        Err(d) => {
+            log::warn!("{}: dead letter: {}", d.stage, d.error);
+            {
+                let mut stages = STAGES.lock().unwrap();
+                let s = stages.entry(d.stage).or_default();
+                s.failed += 1;
+                s.last_error = Some(d.error.clone());
+            }
+            if let Some(f) = DEAD_LETTER_FILE.get() {
+                let line = serde_json::to_string(&d).expect("dead letters are serializable");
+                if let Err(e) = writeln!(f.lock().unwrap(), "{line}") {
+                    log::error!("failed to write dead letter: {e}");
+                }
+            }
+            None
+        }
+    }
+}
+
+/// Errors of a stage
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StageErrors {
+    /// Failed attempts that were retried
+    pub retries: u64,
+    /// Events sent to the dead letter sink
+    pub failed: u64,
+    pub last_error: Option<String>,
+}
+
+/// Errors recorded by this process so far, by stage
+pub fn summary() -> BTreeMap<&'static str, StageErrors> {
+    STAGES.lock().unwrap().clone()
+}
+
+/// [summary] as a JSON object keyed by stage
+pub fn summary_json() -> String {
+    serde_json::to_string(&summary()).unwrap()
+}
@@ -0,0 +1,205 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use eyre::Context;
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::layer::{Context as LayerContext, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+use crate::latency::now_us;
+
+/// Span exporter of the host, flatten it in the binary options
+#[derive(Debug, Clone, clap::Args)]
+pub struct TraceArgs {
+    /// Write the spans of this host to `<stem>-h<host>.json` in the Chrome trace event
+    /// format, for Perfetto or chrome://tracing. Enables the debug spans of the backends
+    #[clap(long)]
+    pub trace: Option<PathBuf>,
+}
+
+/// Install the log subscriber, filtered by `RUST_LOG` and at INFO by default, and the trace
+/// exporter when `--trace` is given, the trace is complete once the returned guard is dropped
+pub fn init(args: &TraceArgs, host_id: Option<u64>) -> eyre::Result<TraceGuard> {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    let log = tracing_subscriber::fmt::layer().with_filter(filter);
+    let Some(path) = &args.trace else {
+        tracing_subscriber::registry().with(log).init();
+        return Ok(TraceGuard(None));
+    };
+
+    let path = host_path(path, host_id.unwrap_or(0));
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let file = File::create(&path).with_context(|| format!("creating {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    out.write_all(b"[")?;
+    let out = Arc::new(Mutex::new(TraceFile { out, events: 0 }));
+
+    let chrome = ChromeLayer {
+        out: out.clone(),
+        pid: host_id.unwrap_or(0),
+    };
+    tracing_subscriber::registry()
+        .with(log)
+        .with(chrome.with_filter(LevelFilter::DEBUG))
+        .init();
+    tracing::info!("writing spans to {}", path.display());
+    Ok(TraceGuard(Some(out)))
+}
+
+/// `trace.json` becomes `trace-h<host>.json`, so that hosts sharing a directory do not
+/// overwrite each other
+fn host_path(path: &Path, host: u64) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem}-h{host}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-h{host}"),
+    };
+    path.with_file_name(name)
+}
+
+/// Closes the trace file on drop
+pub struct TraceGuard(Option<Arc<Mutex<TraceFile>>>);
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        if let Some(file) = &self.0 {
+            let out = &mut file.lock().unwrap().out;
+            if let Err(e) = out.write_all(b"\n]\n").and_then(|_| out.flush()) {
+                eprintln!("failed to close the trace: {e}");
+            }
+        }
+    }
+}
+
+/// JSON array of trace events
+struct TraceFile {
+    out: BufWriter<File>,
+    events: u64,
+}
+
+impl TraceFile {
+    fn write(&mut self, event: &Value) -> std::io::Result<()> {
+        let sep: &[u8] = if self.events == 0 { b"\n" } else { b",\n" };
+        self.out.write_all(sep)?;
+        serde_json::to_writer(&mut self.out, event)?;
+        self.events += 1;
+        Ok(())
+    }
+}
+
+/// Writes each closed span as a complete ("X") event, from its creation to its close so
+/// that the time an async span spends suspended is included
+struct ChromeLayer {
+    out: Arc<Mutex<TraceFile>>,
+    pid: u64,
+}
+
+/// Kept in the extensions of each span until it closes
+struct Timing {
+    start_us: u64,
+    start: Instant,
+    tid: u64,
+    args: Map<String, Value>,
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for ChromeLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut args = Map::new();
+        attrs.record(&mut JsonVisitor(&mut args));
+        span.extensions_mut().insert(Timing {
+            start_us: now_us(),
+            start: Instant::now(),
+            tid: thread_id(),
+            args,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(timing) = extensions.get_mut::<Timing>() {
+            values.record(&mut JsonVisitor(&mut timing.args));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(timing) = span.extensions_mut().remove::<Timing>() else {
+            return;
+        };
+        let event = serde_json::json!({
+            "name": span.name(),
+            "cat": span.metadata().target(),
+            "ph": "X",
+            "ts": timing.start_us,
+            "dur": timing.start.elapsed().as_micros() as u64,
+            "pid": self.pid,
+            "tid": timing.tid,
+            "args": timing.args,
+        });
+        // a failing trace must not fail the pipeline
+        let _ = self.out.lock().unwrap().write(&event);
+    }
+}
+
+/// Small sequential id of the current thread, for the `tid` of the events
+fn thread_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static ID: Cell<u64> = const { Cell::new(0) };
+    }
+    ID.with(|id| {
+        if id.get() == 0 {
+            id.set(NEXT.fetch_add(1, Ordering::Relaxed));
+        }
+        id.get()
+    })
+}
+
+/// Collects the fields of a span as JSON values
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
@@ -0,0 +1,329 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use eyre::Context;
+use noir_compute::{operator::Operator, prelude::*, Stream};
+use rand::distributions::Uniform;
+use rand::prelude::*;
+use rand_distr::{Exp, Zipf};
+use serde::Serialize;
+
+use crate::latency::{now_us, Timed};
+
+/// Distribution of the keys emitted by the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyDistribution {
+    /// Exponential with mean `lambda_inv`, folded into the key space
+    Exponential,
+    /// Zipf with exponent `zipf_s`, key 1 is the most frequent
+    Zipf,
+    /// Uniform over the key space
+    Uniform,
+    /// `hot_ratio` of the events hit the first `hot_fraction` of the keys
+    Hotspot,
+    /// Every replica scans the key space in order, interleaved with the others
+    Sequential,
+    /// Keys read from `replay`, one per line, split round robin between replicas
+    Replay,
+}
+
+/// Key of an event, flagged when the event also writes
+pub type FlaggedKey = (i32, bool);
+
+/// Workload shared by the benchmarks, flatten it in the binary options
+#[derive(Debug, Clone, clap::Args, Serialize)]
+pub struct WorkloadConfig {
+    /// Number of generated events
+    #[clap(short('n'), long, default_value_t = 1_000_000)]
+    pub event_number: u64,
+
+    /// Key distribution
+    #[clap(long, value_enum, default_value_t = KeyDistribution::Exponential)]
+    pub distribution: KeyDistribution,
+
+    /// Keys are drawn from `1..=keys`, match it to the dataset size
+    #[clap(short('k'), long, default_value_t = 1_000_000)]
+    pub keys: u64,
+
+    /// 1 / lambda parameter for exponential id distribution
+    #[clap(short('l'), long, default_value_t = 20_000)]
+    pub lambda_inv: usize,
+
+    /// Exponent of the zipf distribution
+    #[clap(long, default_value_t = 1.0)]
+    pub zipf_s: f64,
+
+    /// Fraction of the key space that is hot
+    #[clap(long, default_value_t = 0.01)]
+    pub hot_fraction: f64,
+
+    /// Fraction of the events hitting the hot keys
+    #[clap(long, default_value_t = 0.9)]
+    pub hot_ratio: f64,
+
+    /// Key trace for the replay distribution
+    #[clap(long)]
+    pub replay: Option<PathBuf>,
+
+    /// Total events per second for open-loop generation, as fast as possible if missing
+    #[clap(long)]
+    pub rate: Option<f64>,
+
+    /// Seed of the generators, xored with the replica index
+    #[clap(
+        long = "workload-seed",
+        id = "workload_seed",
+        value_name = "WORKLOAD_SEED",
+        default_value_t = 0xfeeddabeef
+    )]
+    pub seed: u64,
+
+    /// Source batch size
+    #[clap(long, default_value_t = 8192)]
+    pub batch_size: usize,
+
+    /// Flush timeout of partial batches in milliseconds, 0 uses fixed batches
+    #[clap(long, default_value_t = 1000)]
+    pub batch_timeout_ms: u64,
+}
+
+impl WorkloadConfig {
+    pub fn batch_mode(&self) -> BatchMode {
+        match self.batch_timeout_ms {
+            0 => BatchMode::fixed(self.batch_size),
+            ms => BatchMode::adaptive(self.batch_size, Duration::from_millis(ms)),
+        }
+    }
+
+    /// Parallel source emitting `event_number` keys split between the replicas
+    pub fn source(
+        &self,
+        env: &mut StreamContext,
+    ) -> eyre::Result<Stream<impl Operator<Out = i32>>> {
+        let workload = Workload::new(self)?;
+        let source = env
+            .stream_par_iter(move |i, n| workload.keys(i, n))
+            .batch_mode(self.batch_mode());
+        Ok(source)
+    }
+
+    /// Like [WorkloadConfig::source], with every key tagged by its ingest time.
+    ///
+    /// With a `rate` the ingest time is when the event was due, so a source falling behind
+    /// its schedule shows up as latency instead of lowering the offered load
+    pub fn timed_source(
+        &self,
+        env: &mut StreamContext,
+    ) -> eyre::Result<Stream<impl Operator<Out = Timed<i32>>>> {
+        let workload = Workload::new(self)?;
+        let source = env
+            .stream_par_iter(move |i, n| workload.timed_keys(i, n))
+            .batch_mode(self.batch_mode());
+        Ok(source)
+    }
+
+    /// Like [WorkloadConfig::timed_source], with every key flagged for a write with
+    /// probability `write_ratio`, see [Workload::timed_writes]
+    pub fn timed_write_source(
+        &self,
+        env: &mut StreamContext,
+        write_ratio: f64,
+    ) -> eyre::Result<Stream<impl Operator<Out = Timed<FlaggedKey>>>> {
+        let workload = Workload::new(self)?;
+        let source = env
+            .stream_par_iter(move |i, n| workload.timed_writes(i, n, write_ratio))
+            .batch_mode(self.batch_mode());
+        Ok(source)
+    }
+
+    /// Like [WorkloadConfig::timed_write_source], grouping up to `size` consecutive keys of
+    /// a replica, for the lookups answering a whole batch in one round trip
+    pub fn timed_batch_source(
+        &self,
+        env: &mut StreamContext,
+        size: usize,
+        write_ratio: f64,
+    ) -> eyre::Result<Stream<impl Operator<Out = Vec<Timed<FlaggedKey>>>>> {
+        eyre::ensure!(size > 0, "batches must not be empty");
+        let workload = Workload::new(self)?;
+        let source = env
+            .stream_par_iter(move |i, n| {
+                let mut keys = workload.timed_writes(i, n, write_ratio);
+                std::iter::from_fn(move || {
+                    let batch = keys.by_ref().take(size).collect::<Vec<_>>();
+                    (!batch.is_empty()).then_some(batch)
+                })
+            })
+            .batch_mode(self.batch_mode());
+        Ok(source)
+    }
+}
+
+/// Key generator state shared by every replica
+#[derive(Debug, Clone)]
+enum KeyGen {
+    Exponential(Exp<f32>),
+    Zipf(Zipf<f64>),
+    Uniform(Uniform<i32>),
+    Hotspot {
+        hot: Uniform<i32>,
+        cold: Uniform<i32>,
+        ratio: f64,
+    },
+    Sequential,
+    Replay(Arc<Vec<i32>>),
+}
+
+/// Validated workload, ready to generate the keys of any replica
+#[derive(Debug, Clone)]
+pub struct Workload {
+    gen: KeyGen,
+    keys: i32,
+    events: u64,
+    rate: Option<f64>,
+    seed: u64,
+}
+
+impl Workload {
+    pub fn new(cfg: &WorkloadConfig) -> eyre::Result<Self> {
+        eyre::ensure!(cfg.keys > 0, "key space must not be empty");
+        let keys = i32::try_from(cfg.keys).context("keys must fit in an i32")?;
+        if let Some(rate) = cfg.rate {
+            eyre::ensure!(rate > 0.0, "rate must be positive");
+        }
+
+        let gen = match cfg.distribution {
+            KeyDistribution::Exponential => {
+                KeyGen::Exponential(Exp::new(1. / cfg.lambda_inv as f32)?)
+            }
+            KeyDistribution::Zipf => KeyGen::Zipf(Zipf::new(cfg.keys, cfg.zipf_s)?),
+            KeyDistribution::Uniform => KeyGen::Uniform(Uniform::new_inclusive(1, keys)),
+            KeyDistribution::Hotspot => {
+                eyre::ensure!(
+                    (0.0..=1.0).contains(&cfg.hot_ratio),
+                    "hot ratio must be in 0..=1"
+                );
+                let hot = ((cfg.keys as f64 * cfg.hot_fraction) as i32).clamp(1, keys);
+                KeyGen::Hotspot {
+                    hot: Uniform::new_inclusive(1, hot),
+                    // with no cold keys every event goes to the hot set
+                    cold: Uniform::new_inclusive(hot.min(keys - 1) + 1, keys),
+                    ratio: if hot == keys { 1.0 } else { cfg.hot_ratio },
+                }
+            }
+            KeyDistribution::Sequential => KeyGen::Sequential,
+            KeyDistribution::Replay => {
+                let path = cfg
+                    .replay
+                    .as_ref()
+                    .ok_or_else(|| eyre::eyre!("the replay distribution requires --replay"))?;
+                KeyGen::Replay(Arc::new(read_trace(path)?))
+            }
+        };
+
+        Ok(Self {
+            gen,
+            keys,
+            events: cfg.event_number,
+            rate: cfg.rate,
+            seed: cfg.seed,
+        })
+    }
+
+    /// Keys emitted by replica `i` of `n`
+    pub fn keys(&self, i: u64, n: u64) -> impl Iterator<Item = i32> + Send + 'static {
+        self.timed_keys(i, n).map(|e| e.value)
+    }
+
+    /// Keys of [Workload::timed_keys], each flagged for a write with probability
+    /// `write_ratio`. The flags are drawn from the seed and the replica index like the keys,
+    /// by a generator of their own so that the keys do not depend on the ratio
+    pub fn timed_writes(
+        &self,
+        i: u64,
+        n: u64,
+        write_ratio: f64,
+    ) -> impl Iterator<Item = Timed<FlaggedKey>> + Send + 'static {
+        let mut rng = SmallRng::seed_from_u64(!(i ^ self.seed));
+        self.timed_keys(i, n)
+            .map(move |e| e.map(|k| (k, rng.gen_bool(write_ratio))))
+    }
+
+    /// Keys emitted by replica `i` of `n` with their ingest time
+    pub fn timed_keys(&self, i: u64, n: u64) -> impl Iterator<Item = Timed<i32>> + Send + 'static {
+        let mut rng = SmallRng::seed_from_u64(i ^ self.seed);
+        let mut pacer = self.rate.map(|r| Pacer::new(r / n as f64));
+        let (gen, keys) = (self.gen.clone(), self.keys);
+        let mut next = i;
+
+        (0..self.events / n).map_while(move |_| {
+            let k = match &gen {
+                // the sample wraps around the key space, rounding may give `keys` itself
+                KeyGen::Exponential(d) => {
+                    (d.sample(&mut rng).rem_euclid(keys as f32) as i32).min(keys - 1) + 1
+                }
+                KeyGen::Zipf(d) => d.sample(&mut rng) as i32,
+                KeyGen::Uniform(d) => d.sample(&mut rng),
+                KeyGen::Hotspot { hot, cold, ratio } => match rng.gen_bool(*ratio) {
+                    true => hot.sample(&mut rng),
+                    false => cold.sample(&mut rng),
+                },
+                KeyGen::Sequential => (next % keys as u64) as i32 + 1,
+                KeyGen::Replay(trace) => *trace.get(next as usize)?,
+            };
+            next += n;
+            let ingest = match pacer.as_mut() {
+                Some(pacer) => pacer.wait(),
+                None => now_us(),
+            };
+            Some(Timed { ingest, value: k })
+        })
+    }
+}
+
+fn read_trace(path: &Path) -> eyre::Result<Vec<i32>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read trace {}", path.display()))?;
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            l.parse()
+                .with_context(|| format!("invalid key in trace: {l:?}"))
+        })
+        .collect()
+}
+
+/// Open-loop schedule: the k-th event is due at `k / rate` seconds from the first one,
+/// regardless of how long the previous events took downstream
+struct Pacer {
+    /// Monotonic and wall clock time of the first event
+    start: Option<(Instant, u64)>,
+    rate: f64,
+    emitted: u64,
+}
+
+impl Pacer {
+    fn new(rate: f64) -> Self {
+        Self {
+            start: None,
+            rate,
+            emitted: 0,
+        }
+    }
+
+    /// Sleeps until the next event is due and returns its due time, see [now_us]
+    fn wait(&mut self) -> u64 {
+        let (start, wall) = *self.start.get_or_insert_with(|| (Instant::now(), now_us()));
+        let offset = Duration::from_secs_f64(self.emitted as f64 / self.rate);
+        self.emitted += 1;
+        let now = Instant::now();
+        if start + offset > now {
+            std::thread::sleep(start + offset - now);
+        }
+        wall + offset.as_micros() as u64
+    }
+}